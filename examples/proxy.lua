@@ -31,6 +31,10 @@ end
 -- Setup Domain via API (manages authorized_users table in server.db)
 reverse_proxy.domain("corp.internal").add_user("boss@gmail.com")
 
+-- Removing a user does not log them out by itself; revoke their sessions too
+-- reverse_proxy.domain("corp.internal").remove_user("former@gmail.com")
+-- reverse_proxy.revoke_sessions("former@gmail.com")
+
 -- Configure Proxies
 
 -- Public proxy (no auth)
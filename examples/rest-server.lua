@@ -76,7 +76,12 @@ local function add_user_to_db(params)
 end
 
 -- Register a POST endpoint at /api/users
-srv:register("/api/users", "POST", add_user_to_db)
+-- Handlers also receive the full request: method, path, query, headers,
+-- cookies, raw body, decoded json/form body and remote_addr.
+srv:register("/api/users", "POST", function(params, req)
+    print("--- [Lua] POST from " .. (req.remote_addr or "unknown") .. " ---")
+    return add_user_to_db(req.json or req.form or params)
+end)
 
 -- HTTP client for the cron job
 local http_client = http.new({ insecure = true })
@@ -162,6 +167,17 @@ srv:register("/api/hello", "GET", function(params)
     }
 end)
 
+-- Endpoint returning a custom status code, headers and a non-JSON body
+srv:register("/api/users.csv", "GET", function()
+    local lines = { "id,name,role" }
+    for _, user in ipairs(db:objects("users", {})) do
+        table.insert(lines, string.format("%d,%s,%s", user.id, user.name, user.role))
+    end
+    return rest.response({ status = 200, content_type = "text/csv" })
+        :header("Content-Disposition", "attachment; filename=\"users.csv\"")
+        :body(table.concat(lines, "\n") .. "\n")
+end)
+
 -- Serve static files
 srv:serve_static("/", "public")
 
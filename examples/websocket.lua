@@ -0,0 +1,34 @@
+-- WebSocket example: an echo endpoint and a live feed pushed to browsers
+local srv = rest.new()
+srv:listen("0.0.0.0:8080")
+
+-- Each connection runs its own handler coroutine
+srv:websocket("/ws/echo", function(conn, req)
+    print("Client " .. conn.id .. " connected from " .. (req.remote_addr or "?"))
+    while true do
+        local msg, kind = conn:recv()
+        if not msg then
+            break
+        end
+        if msg == "bye" then
+            conn:close(1000, "bye")
+            break
+        end
+        conn:send({ echo = msg, kind = kind })
+    end
+    print("Client " .. conn.id .. " disconnected")
+end)
+
+-- The connection is closed when the handler returns, so it waits for the
+-- client to leave while broadcasts are pushed to it
+srv:websocket("/ws/feed", function(conn)
+    conn:send({ hello = "welcome to the feed" })
+    while conn:recv() do
+    end
+end)
+
+local scheduler = cron.new()
+scheduler:register("*/5 * * * * *", function()
+    local count = srv:broadcast("/ws/feed", { time = now() })
+    print("Broadcast to " .. count .. " clients")
+end)
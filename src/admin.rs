@@ -0,0 +1,280 @@
+use crate::types::{EngineRequest, EvalRequest};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+/// Engine of the version that is serving, updated by the main loop.
+pub type CurrentEngine = Arc<Mutex<Option<mpsc::WeakSender<EngineRequest>>>>;
+
+/// Where the console listens: a Unix socket path or a loopback address.
+enum Target {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl Target {
+    fn parse(target: &str) -> Target {
+        match target.parse() {
+            Ok(addr) => Target::Tcp(addr),
+            Err(_) => Target::Unix(PathBuf::from(target)),
+        }
+    }
+}
+
+/// The file holding the token TCP clients must send first. Loopback ports
+/// are open to every local user and to web pages in a local browser, so
+/// only those who can read the owner's files get in.
+fn token_path(addr: SocketAddr) -> Option<PathBuf> {
+    Some(dirs::home_dir()?.join(format!(".lumen-admin-{}", addr.port())))
+}
+
+fn write_token(path: &Path) -> std::io::Result<String> {
+    let token = crate::util::random_token();
+    // Created afresh, since an existing file may be readable by others
+    let _ = std::fs::remove_file(path);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", token)?;
+    Ok(token)
+}
+
+/// The admin console listener. It outlives reloads, since every line is
+/// evaluated by whichever version is serving at the time.
+pub struct AdminGuard {
+    handle: tokio::task::JoinHandle<()>,
+    /// Socket or token file, removed when the console closes.
+    file: PathBuf,
+}
+
+impl Drop for AdminGuard {
+    fn drop(&mut self) {
+        self.handle.abort();
+        let _ = std::fs::remove_file(&self.file);
+    }
+}
+
+/// Opens the console on `target`. Unix sockets are only accessible to the
+/// owner and TCP is limited to loopback addresses and clients that know the
+/// token, since whoever connects can run any code.
+pub async fn start(target: &str, engine: CurrentEngine) -> Option<AdminGuard> {
+    match Target::parse(target) {
+        Target::Tcp(addr) => {
+            if !addr.ip().is_loopback() {
+                eprintln!(
+                    "Admin console must listen on a loopback address, not {}",
+                    addr
+                );
+                return None;
+            }
+            let listener = match TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to bind admin console to {}: {}", addr, e);
+                    return None;
+                }
+            };
+            let Some(path) = token_path(addr) else {
+                eprintln!("Admin console needs a home directory for its token");
+                return None;
+            };
+            let token: Arc<str> = match write_token(&path) {
+                Ok(token) => token.into(),
+                Err(e) => {
+                    eprintln!("Failed to write admin console token {:?}: {}", path, e);
+                    return None;
+                }
+            };
+            println!("Admin console listening on {}, token in {:?}", addr, path);
+            let handle = tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(session(stream, engine.clone(), Some(token.clone())));
+                }
+            });
+            Some(AdminGuard { handle, file: path })
+        }
+        Target::Unix(path) => {
+            // Left behind by a process that didn't shut down cleanly
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                let _ = std::fs::remove_file(&path);
+            }
+            let listener = match bind_private(&path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to bind admin console to {:?}: {}", path, e);
+                    return None;
+                }
+            };
+            println!("Admin console listening on {:?}", path);
+            let handle = tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(session(stream, engine.clone(), None));
+                }
+            });
+            Some(AdminGuard { handle, file: path })
+        }
+    }
+}
+
+/// Binds the socket inside a private directory and moves it into place once
+/// restricted to the owner, so it is never reachable with looser permissions.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("socket");
+    let result = UnixListener::bind(&tmp).and_then(|listener| {
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+/// Reads Lua line by line and writes back the results and a prompt, so any
+/// line-based client such as `nc -U` works as well as `lumen attach`. With a
+/// `token`, the connection is closed unless it is the first line.
+async fn session<S>(stream: S, engine: CurrentEngine, token: Option<Arc<str>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    if let Some(token) = token {
+        match lines.next_line().await {
+            Ok(Some(line)) if same_token(line.trim_end(), &token) => {}
+            _ => return,
+        }
+    }
+    let banner = format!(
+        "Lumen {} admin console, pid {}. Results are returned, print() goes to the log.\n> ",
+        env!("LUMEN_VERSION"),
+        std::process::id()
+    );
+    if writer.write_all(banner.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let mut buffer = String::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        // A browser posting to the console, whose body must not be run
+        if looks_like_http(&line) {
+            return;
+        }
+        buffer.push_str(&line);
+        buffer.push('\n');
+        let output = match eval(&engine, buffer.clone()).await {
+            None => {
+                if writer.write_all(b">> ").await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Some(Ok(result)) if result.is_empty() => String::new(),
+            Some(Ok(result)) | Some(Err(result)) => format!("{}\n", result),
+        };
+        buffer.clear();
+        if writer
+            .write_all(format!("{}> ", output).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn looks_like_http(line: &str) -> bool {
+    let line = line.trim_end();
+    line.rsplit_once(' ')
+        .is_some_and(|(_, version)| version.starts_with("HTTP/"))
+}
+
+/// Compares in constant time, so the token can't be guessed byte by byte.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+async fn eval(engine: &CurrentEngine, code: String) -> Option<Result<String, String>> {
+    let tx = engine.lock().unwrap().as_ref().and_then(|tx| tx.upgrade());
+    let Some(tx) = tx else {
+        return Some(Err("No version of the script is running.".to_string()));
+    };
+    let (response_tx, response_rx) = oneshot::channel();
+    let req = EngineRequest::Eval(EvalRequest { code, response_tx });
+    if tx.send(req).await.is_err() {
+        return Some(Err("The script is shutting down.".to_string()));
+    }
+    response_rx
+        .await
+        .unwrap_or_else(|_| Some(Err("The script stopped before answering.".to_string())))
+}
+
+/// `lumen attach`: connects the terminal to the console at `target` until
+/// either side closes. Returns the exit code.
+pub async fn attach(target: &str) -> i32 {
+    let res = match Target::parse(target) {
+        Target::Tcp(addr) => match connect_tcp(addr).await {
+            Ok(stream) => pipe(stream).await,
+            Err(e) => Err(e),
+        },
+        Target::Unix(path) => match UnixStream::connect(&path).await {
+            Ok(stream) => pipe(stream).await,
+            Err(e) => Err(e),
+        },
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Failed to attach to {}: {}", target, e);
+            1
+        }
+    }
+}
+
+/// Connects and sends the token the console expects first.
+async fn connect_tcp(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let path = token_path(addr).ok_or_else(|| std::io::Error::other("no home directory"))?;
+    let token = std::fs::read_to_string(path)?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(token.as_bytes()).await?;
+    Ok(stream)
+}
+
+async fn pipe<S>(stream: S) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let input = async {
+        tokio::io::copy(&mut tokio::io::stdin(), &mut writer).await?;
+        writer.shutdown().await
+    };
+    let mut stdout = tokio::io::stdout();
+    let output = tokio::io::copy(&mut reader, &mut stdout);
+    tokio::pin!(output);
+    // The console closing ends the session even while stdin is still open
+    tokio::select! {
+        res = input => {
+            res?;
+            (&mut output).await?;
+        }
+        res = &mut output => {
+            res?;
+        }
+    }
+    Ok(())
+}
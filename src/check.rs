@@ -0,0 +1,90 @@
+use crate::exit;
+use crate::types::AppState;
+use crate::{sandbox, web_server};
+use mlua::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// `lumen check`: runs the top level of the script, which registers its
+/// routes and jobs, and validates them without starting any service.
+/// Returns the exit code.
+pub async fn run(lua: &Lua, app_state: &Arc<Mutex<AppState>>, path_str: &str) -> i32 {
+    let content = match std::fs::read_to_string(path_str) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path_str, e);
+            return 1;
+        }
+    };
+
+    // Parse everything before running anything
+    let func = match lua
+        .load(&content)
+        .set_name(format!("@{}", path_str))
+        .into_function()
+    {
+        Ok(func) => func,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = sandbox::limited(func.call_async::<()>(())).await {
+        match exit::exit_code(&e) {
+            Some(0) => {}
+            Some(code) => {
+                eprintln!("{}: {}", path_str, e);
+                return code;
+            }
+            None => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    }
+
+    let script_dir = Path::new(path_str).parent().unwrap_or(Path::new("."));
+    let state = app_state.lock().unwrap();
+    for route in &state.routes {
+        println!("{:<9} {}", route.method, route.path);
+    }
+    for route in &state.websocket_routes {
+        println!("{:<9} {}", "WEBSOCKET", route.path);
+    }
+    for (url_path, fs_path) in &state.static_routes {
+        println!("{:<9} {} -> {}", "STATIC", url_path, fs_path);
+    }
+    for proxy in &state.reverse_proxies {
+        println!(
+            "{:<9} {}{} -> {}",
+            "PROXY", proxy.host, proxy.path_prefix, proxy.remote_base
+        );
+    }
+    for job in &state.cron_jobs {
+        println!("{:<9} {}", "CRON", job.schedule.describe());
+    }
+    for def in &state.job_defs {
+        println!(
+            "{:<9} {} (concurrency {})",
+            "JOB", def.name, def.concurrency
+        );
+    }
+    if let Some(debugger) = &state.debugger {
+        println!("{:<9} {}", "DEBUGGER", debugger.path);
+    }
+    if state.telegram_handler.is_some() {
+        println!("{:<9} handler registered", "TELEGRAM");
+    }
+
+    let problems = web_server::check(&state, script_dir);
+    if problems.is_empty() {
+        println!("{}: OK", path_str);
+        0
+    } else {
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        eprintln!("{}: {} problem(s) found", path_str, problems.len());
+        1
+    }
+}
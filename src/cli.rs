@@ -0,0 +1,150 @@
+use crate::test;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: lumen [options] <command> [args]
+       lumen [options] <script.lua>
+
+Commands:
+  run <script>     Run a script and its services, reloading on changes
+  check <script>   Load a script and validate it without starting services
+  eval <code>      Evaluate Lua code and print the result
+  repl             Start an interactive Lua prompt
+  test [path]      Run the *_test.lua files below path (default: .)
+  attach [socket]  Open the admin console of a running script
+                   (default: LUMEN_ADMIN)
+  version          Print the version
+
+Options:
+  -C, --cwd <dir>          Change to <dir> before doing anything else
+  -e, --env-file <file>    Load environment variables from <file>
+  -l, --log-level <level>  off, error, warn, info, debug or trace
+                           (default: RUST_LOG or info)
+  -s, --sandbox <file>     Restrict the script to the JSON manifest <file>
+  -f, --format <format>    Test report: text, tap or junit (default: text)
+  -o, --output <file>      Write the test report to <file>
+  -h, --help               Print this help
+  -V, --version            Print the version
+";
+
+pub enum Command {
+    Run(String),
+    Check(String),
+    Eval(String),
+    Repl,
+    Test(test::Options),
+    Attach(Option<String>),
+    Version,
+    Help,
+}
+
+pub struct Cli {
+    pub command: Command,
+    pub cwd: Option<PathBuf>,
+    pub env_file: Option<PathBuf>,
+    pub log_level: Option<log::LevelFilter>,
+    pub sandbox: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Parses the arguments after the program name. Options may appear
+    /// before or after the command.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+        let mut cwd = None;
+        let mut env_file = None;
+        let mut log_level = None;
+        let mut sandbox = None;
+        let mut format = None;
+        let mut output = None;
+        let mut help = false;
+        let mut version = false;
+        let mut positional = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+                break;
+            }
+            if !arg.starts_with('-') || arg == "-" {
+                positional.push(arg);
+                continue;
+            }
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            match name.as_str() {
+                "-C" | "--cwd" => cwd = Some(PathBuf::from(value()?)),
+                "-e" | "--env-file" => env_file = Some(PathBuf::from(value()?)),
+                "-l" | "--log-level" => {
+                    let level = value()?;
+                    log_level = Some(
+                        level
+                            .parse()
+                            .map_err(|_| format!("Invalid log level: {}", level))?,
+                    );
+                }
+                "-s" | "--sandbox" => sandbox = Some(PathBuf::from(value()?)),
+                "-f" | "--format" => format = Some(value()?.parse()?),
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "-h" | "--help" => help = true,
+                "-V" | "--version" => version = true,
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            _ if help => Command::Help,
+            _ if version => Command::Version,
+            None => return Err("Missing command".to_string()),
+            Some("help") => Command::Help,
+            Some("version") => Command::Version,
+            Some("repl") => Command::Repl,
+            Some("attach") => Command::Attach(positional.next()),
+            Some("test") => Command::Test(test::Options {
+                path: positional.next(),
+                format: format.unwrap_or(test::Format::Text),
+                output: output.take(),
+            }),
+            Some("run") => Command::Run(script_arg(positional.next(), "run")?),
+            Some("check") => Command::Check(script_arg(positional.next(), "check")?),
+            Some("eval") => {
+                Command::Eval(positional.next().ok_or("eval needs the code to evaluate")?)
+            }
+            // `lumen app.lua` predates the subcommands
+            Some(script) if script.ends_with(".lua") => Command::Run(script.to_string()),
+            Some(other) => return Err(format!("Unknown command: {}", other)),
+        };
+        if !help
+            && !version
+            && let Some(extra) = positional.next()
+        {
+            return Err(format!("Unexpected argument: {}", extra));
+        }
+        if !matches!(command, Command::Test(_)) && (format.is_some() || output.is_some()) {
+            return Err("--format and --output only apply to test".to_string());
+        }
+
+        Ok(Cli {
+            command,
+            cwd,
+            env_file,
+            log_level,
+            sandbox,
+        })
+    }
+}
+
+fn script_arg(arg: Option<String>, command: &str) -> Result<String, String> {
+    arg.ok_or_else(|| format!("{} needs a script path", command))
+}
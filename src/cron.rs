@@ -1,9 +1,409 @@
-use crate::types::{AppState, CronJobInfo, EngineRequest};
-use chrono::{DateTime, Local};
+use crate::session::db_path;
+use crate::types::{AppState, CronJobInfo, CronRun, EngineRequest};
+use chrono::{DateTime, Local, SubsecRound};
+use chrono_tz::Tz;
 use croner::Cron;
+use croner::parser::{CronParser, Seconds};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
 use mlua::prelude::*;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc::Sender;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender, WeakSender};
+use tokio::sync::oneshot;
+
+/// Keys of jobs added while the script is running start here, so they never
+/// clash with the index of a job registered while it loads.
+const FIRST_DYNAMIC_KEY: u64 = 1 << 32;
+
+static NEXT_KEY: AtomicU64 = AtomicU64::new(FIRST_DYNAMIC_KEY);
+
+/// The index of a job registered while the script loaded, which is the same
+/// in every Lua state of a version.
+pub fn static_index(key: u64) -> Option<usize> {
+    (key < FIRST_DYNAMIC_KEY).then_some(key as usize)
+}
+
+/// What happens when a job is due while its previous run is still going.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Overlap {
+    /// The new run is dropped.
+    #[default]
+    Skip,
+    /// One run waits for the previous one to finish; more are dropped.
+    Queue,
+    /// Runs are started regardless.
+    Parallel,
+}
+
+impl Overlap {
+    pub fn name(self) -> &'static str {
+        match self {
+            Overlap::Skip => "skip",
+            Overlap::Queue => "queue",
+            Overlap::Parallel => "parallel",
+        }
+    }
+}
+
+/// Which runs that were due while the scheduler wasn't running are made up
+/// for when it starts.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum CatchUp {
+    #[default]
+    Never,
+    /// One run for any number of missed ones.
+    Once,
+    /// Every missed run, one after the other.
+    All,
+}
+
+impl std::str::FromStr for CatchUp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(CatchUp::Never),
+            "once" => Ok(CatchUp::Once),
+            "all" => Ok(CatchUp::All),
+            _ => Err(format!(
+                "Invalid catch_up '{}', expected never, once or all",
+                s
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for Overlap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Overlap::Skip),
+            "queue" => Ok(Overlap::Queue),
+            "parallel" => Ok(Overlap::Parallel),
+            _ => Err(format!(
+                "Invalid overlap policy '{}', expected skip, queue or parallel",
+                s
+            )),
+        }
+    }
+}
+
+/// When a job runs.
+#[derive(Clone)]
+pub enum Schedule {
+    /// A cron expression, evaluated in `tz` or the local time zone.
+    Cron {
+        expression: String,
+        cron: Box<Cron>,
+        tz: Option<Tz>,
+    },
+    /// Every interval, counted from the given time.
+    Every(DateTime<Local>, Duration),
+    /// Once, at the given time.
+    At(DateTime<Local>),
+}
+
+impl Schedule {
+    /// Parses a cron expression of five fields, or six with seconds first.
+    pub fn cron(expression: &str, tz: Option<Tz>) -> Result<Schedule, String> {
+        let cron = CronParser::builder()
+            .seconds(Seconds::Optional)
+            .build()
+            .parse(expression)
+            .map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))?;
+        Ok(Schedule::Cron {
+            expression: expression.to_string(),
+            cron: Box::new(cron),
+            tz,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Schedule::Cron {
+                expression,
+                tz: Some(tz),
+                ..
+            } => format!("{} ({})", expression, tz.name()),
+            Schedule::Cron { expression, .. } => expression.clone(),
+            Schedule::Every(_, interval) => format!("every {} s", interval.as_secs_f64()),
+            Schedule::At(time) => format!("once at {}", time.to_rfc3339()),
+        }
+    }
+
+    /// The expression, for jobs whose state is kept in `server.db`.
+    pub fn expression(&self) -> Option<&str> {
+        match self {
+            Schedule::Cron { expression, .. } => Some(expression),
+            _ => None,
+        }
+    }
+
+    /// The first occurrence after `from`. A one-off time stays due until it
+    /// has run, even once it is past.
+    pub fn next_after(&self, from: DateTime<Local>) -> Option<DateTime<Local>> {
+        // Whole seconds, as croner keeps the fraction of the time it starts
+        // from
+        let from_second = from.trunc_subsecs(0);
+        match self {
+            Schedule::Cron {
+                cron, tz: Some(tz), ..
+            } => cron
+                .find_next_occurrence(&from_second.with_timezone(tz), false)
+                .ok()
+                .map(|next| next.with_timezone(&Local)),
+            Schedule::Cron { cron, .. } => cron.find_next_occurrence(&from_second, false).ok(),
+            Schedule::Every(start, interval) => {
+                let interval = (interval.as_millis() as i64).max(1);
+                let elapsed = (from - *start).num_milliseconds().max(0);
+                let count = elapsed / interval + 1;
+                Some(*start + chrono::Duration::milliseconds(count * interval))
+            }
+            Schedule::At(time) => Some(*time),
+        }
+    }
+}
+
+/// When a job with `schedule` fires next.
+pub fn next_run(schedule: &Schedule) -> Option<DateTime<Local>> {
+    schedule.next_after(Local::now())
+}
+
+/// Options shared by `register`, `every` and `after`.
+#[derive(Default)]
+struct Options {
+    name: Option<String>,
+    overlap: Overlap,
+    timeout: Option<Duration>,
+    catch_up: CatchUp,
+    jitter: Option<Duration>,
+    /// Time zone of a cron expression.
+    tz: Option<Tz>,
+}
+
+impl Options {
+    fn from_lua(opts: Option<LuaTable>) -> LuaResult<Self> {
+        let mut options = Options::default();
+        let Some(opts) = opts else {
+            return Ok(options);
+        };
+        options.name = opts.get("name")?;
+        if let Some(value) = opts.get::<Option<String>>("catch_up")? {
+            options.catch_up = value.parse().map_err(LuaError::RuntimeError)?;
+        }
+        if let Some(name) = opts.get::<Option<String>>("overlap")? {
+            options.overlap = name.parse().map_err(LuaError::RuntimeError)?;
+        }
+        if let Some(secs) = opts.get::<Option<f64>>("timeout")? {
+            options.timeout = Some(seconds(secs, "cron timeout")?);
+        }
+        if let Some(secs) = opts.get::<Option<f64>>("jitter")? {
+            options.jitter = Some(seconds(secs, "cron jitter")?);
+        }
+        if let Some(name) = opts.get::<Option<String>>("tz")? {
+            let tz = name
+                .parse()
+                .map_err(|_| LuaError::RuntimeError(format!("Unknown time zone '{}'", name)))?;
+            options.tz = Some(tz);
+        }
+        Ok(options)
+    }
+}
+
+fn seconds(secs: f64, what: &str) -> LuaResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| {
+            LuaError::RuntimeError(format!("{} must be a positive number of seconds", what))
+        })
+}
+
+/// A job added while the script is running.
+pub struct NewJob {
+    key: u64,
+    name: Option<String>,
+    schedule: Schedule,
+    options: Options,
+    /// Engine of the Lua state that added the job, which runs it.
+    target: Option<WeakSender<EngineRequest>>,
+}
+
+/// Requests to the scheduler of the running version, from any of its Lua
+/// states.
+pub enum Command {
+    Add(Box<NewJob>),
+    Pause(u64),
+    Resume(u64),
+    Cancel(u64),
+    NextRun(u64, oneshot::Sender<Option<DateTime<Local>>>),
+    List(oneshot::Sender<Vec<Listed>>),
+}
+
+/// A job as listed by `cron.status()`.
+pub struct Listed {
+    name: String,
+    schedule: Schedule,
+    next_run: Option<DateTime<Local>>,
+    paused: bool,
+}
+
+/// Callbacks of the jobs that a Lua state added at runtime, by key.
+#[derive(Default)]
+struct DynamicJobs(HashMap<u64, LuaRegistryKey>);
+
+/// The callback of a job added at runtime by `lua`. With `once` it is
+/// forgotten, as the job won't run again.
+pub fn dynamic_callback(lua: &Lua, key: u64, once: bool) -> Option<LuaFunction> {
+    let mut jobs = lua.app_data_mut::<DynamicJobs>()?;
+    let func = lua.registry_value(jobs.0.get(&key)?).ok();
+    if once {
+        jobs.0.remove(&key);
+    }
+    func
+}
+
+/// Handle of a job, returned by `register`, `every` and `after`.
+pub struct JobHandle {
+    key: u64,
+    state: Arc<Mutex<AppState>>,
+}
+
+impl JobHandle {
+    /// Hands `command` to the scheduler, or applies `update` to the job if
+    /// the script is still loading.
+    fn control(&self, command: Command, update: impl FnOnce(&mut CronJobInfo)) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tx) = &state.cron_tx {
+            tx.send(command).ok();
+        } else if let Some(job) = static_index(self.key).and_then(|i| state.cron_jobs.get_mut(i)) {
+            update(job);
+        }
+    }
+}
+
+impl LuaUserData for JobHandle {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("cancel", |lua, handle, ()| {
+            handle.control(Command::Cancel(handle.key), |job| job.cancelled = true);
+            if let Some(mut jobs) = lua.app_data_mut::<DynamicJobs>() {
+                jobs.0.remove(&handle.key);
+            }
+            Ok(())
+        });
+        methods.add_method("pause", |_, handle, ()| {
+            handle.control(Command::Pause(handle.key), |job| job.paused = true);
+            Ok(())
+        });
+        methods.add_method("resume", |_, handle, ()| {
+            handle.control(Command::Resume(handle.key), |job| job.paused = false);
+            Ok(())
+        });
+        methods.add_async_method("next_run", |_, handle, ()| async move {
+            let (tx, loading) = {
+                let state = handle.state.lock().unwrap();
+                let loading = static_index(handle.key)
+                    .and_then(|i| state.cron_jobs.get(i))
+                    .filter(|job| !job.paused && !job.cancelled)
+                    .map(|job| job.schedule.clone());
+                (state.cron_tx.clone(), loading)
+            };
+            let next = match tx {
+                Some(tx) => {
+                    let (reply, rx) = oneshot::channel();
+                    tx.send(Command::NextRun(handle.key, reply)).ok();
+                    rx.await.ok().flatten()
+                }
+                None => loading.as_ref().and_then(next_run),
+            };
+            Ok(next.map(epoch))
+        });
+    }
+}
+
+/// Adds a job. While the script loads it becomes part of the version and
+/// runs wherever its handlers do; later it is handed to the running
+/// scheduler and runs in this Lua state until the next reload.
+fn add(
+    lua: &Lua,
+    app_state: &Arc<Mutex<AppState>>,
+    schedule: Schedule,
+    func: LuaFunction,
+    options: Options,
+) -> LuaResult<JobHandle> {
+    let mut state = app_state.lock().unwrap();
+    if let Some(tx) = state.cron_tx.clone() {
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        let callback = lua.create_registry_value(func)?;
+        if lua.app_data_ref::<DynamicJobs>().is_none() {
+            lua.set_app_data(DynamicJobs::default());
+        }
+        if let Some(mut jobs) = lua.app_data_mut::<DynamicJobs>() {
+            jobs.0.insert(key, callback);
+        }
+        let job = NewJob {
+            key,
+            name: options.name.clone(),
+            schedule,
+            options,
+            target: state.engine_tx.clone(),
+        };
+        tx.send(Command::Add(Box::new(job))).ok();
+        return Ok(JobHandle {
+            key,
+            state: app_state.clone(),
+        });
+    }
+
+    let taken = |name: &str| state.cron_jobs.iter().any(|job| job.name == name);
+    // The name keys the job's history, so it must stay the same across
+    // restarts
+    let name = match options.name {
+        Some(name) if taken(&name) => {
+            return Err(LuaError::RuntimeError(format!(
+                "A cron job named '{}' is already registered",
+                name
+            )));
+        }
+        Some(name) => name,
+        None => unique_name(&schedule.describe(), taken),
+    };
+    let callback_id = state.cron_jobs.len();
+    let callback_key = lua.create_registry_value(func)?;
+    state.cron_jobs.push(CronJobInfo {
+        name,
+        schedule,
+        callback_id,
+        callback_key,
+        overlap: options.overlap,
+        timeout: options.timeout,
+        catch_up: options.catch_up,
+        jitter: options.jitter,
+        paused: false,
+        cancelled: false,
+    });
+    Ok(JobHandle {
+        key: callback_id as u64,
+        state: app_state.clone(),
+    })
+}
+
+/// `base`, or `base #2`, `base #3`... if it is taken.
+fn unique_name(base: &str, taken: impl Fn(&str) -> bool) -> String {
+    (1..)
+        .map(|n| match n {
+            1 => base.to_string(),
+            n => format!("{} #{}", base, n),
+        })
+        .find(|name| !taken(name))
+        .unwrap()
+}
 
 pub struct CronScheduler {
     state: Arc<Mutex<AppState>>,
@@ -13,18 +413,16 @@ impl LuaUserData for CronScheduler {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method(
             "register",
-            |lua, scheduler, (expression, func): (String, LuaFunction)| {
-                let mut state = scheduler.state.lock().unwrap();
-                let callback_id = state.cron_jobs.len();
-                let callback_key = lua.create_registry_value(func)?;
-                state.cron_jobs.push(CronJobInfo {
-                    expression,
-                    callback_id,
-                    callback_key,
-                });
-                Ok(())
+            |lua, scheduler, (expression, func, opts): (String, LuaFunction, Option<LuaTable>)| {
+                let options = Options::from_lua(opts)?;
+                let schedule =
+                    Schedule::cron(&expression, options.tz).map_err(LuaError::RuntimeError)?;
+                add(lua, &scheduler.state, schedule, func, options)
             },
         );
+        methods.add_async_method("status", |lua, scheduler, ()| {
+            status(lua, scheduler.state.clone())
+        });
     }
 }
 
@@ -39,86 +437,799 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
             })
         })?,
     )?;
+
+    // Listing and running jobs by hand, e.g. from the admin console
+    let jobs_state = app_state.clone();
+    cron.set(
+        "jobs",
+        lua.create_function(move |lua, ()| {
+            let state = jobs_state.lock().unwrap();
+            let jobs = lua.create_table()?;
+            for job in &state.cron_jobs {
+                let entry = lua.create_table()?;
+                entry.set("id", job.callback_id + 1)?;
+                entry.set("name", job.name.clone())?;
+                entry.set("expression", job.schedule.describe())?;
+                entry.set("overlap", job.overlap.name())?;
+                entry.set("timeout", job.timeout.map(|t| t.as_secs_f64()))?;
+                jobs.push(entry)?;
+            }
+            Ok(jobs)
+        })?,
+    )?;
+    // `cron:status()` works as well
+    let status_state = app_state.clone();
+    cron.set(
+        "status",
+        lua.create_async_function(move |lua, ()| status(lua, status_state.clone()))?,
+    )?;
+    let run_state = app_state.clone();
+    cron.set(
+        "run",
+        lua.create_async_function(move |lua, id: usize| {
+            let func = {
+                let state = run_state.lock().unwrap();
+                id.checked_sub(1)
+                    .and_then(|i| state.cron_jobs.get(i))
+                    .ok_or_else(|| LuaError::RuntimeError(format!("No cron job with id {}", id)))
+                    .and_then(|job| lua.registry_value::<LuaFunction>(&job.callback_key))
+            };
+            async move { func?.call_async::<()>(()).await }
+        })?,
+    )?;
     lua.globals().set("cron", cron)?;
+
+    let every_state = app_state.clone();
+    lua.globals().set(
+        "every",
+        lua.create_function(
+            move |lua, (secs, func, opts): (f64, LuaFunction, Option<LuaTable>)| {
+                let interval = seconds(secs, "every")?;
+                let options = Options::from_lua(opts)?;
+                let schedule = Schedule::Every(Local::now(), interval);
+                add(lua, &every_state, schedule, func, options)
+            },
+        )?,
+    )?;
+    lua.globals().set(
+        "after",
+        lua.create_function(
+            move |lua, (secs, func, opts): (f64, LuaFunction, Option<LuaTable>)| {
+                let delay = Duration::try_from_secs_f64(secs).map_err(|_| {
+                    LuaError::RuntimeError("after needs a number of seconds >= 0".into())
+                })?;
+                let options = Options::from_lua(opts)?;
+                let schedule = Schedule::At(Local::now() + delay);
+                add(lua, &app_state, schedule, func, options)
+            },
+        )?,
+    )?;
     Ok(())
 }
 
-pub async fn start(
-    app_state: Arc<Mutex<AppState>>,
-    tx: Sender<EngineRequest>,
-) -> Option<tokio::task::JoinHandle<()>> {
-    let has_jobs = {
-        let state = app_state.lock().unwrap();
-        !state.cron_jobs.is_empty()
-    };
+/// Runs sent to an engine that aren't over yet, by job name. They outlive
+/// the scheduler, which is replaced on reload while the retired engine
+/// finishes its runs.
+static RUNNING: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Default::default);
+
+/// Names of jobs whose run is over, for the scheduler running at the time.
+static FINISHED: LazyLock<(
+    UnboundedSender<String>,
+    tokio::sync::Mutex<UnboundedReceiver<String>>,
+)> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (tx, tokio::sync::Mutex::new(rx))
+});
+
+fn running(name: &str) -> usize {
+    RUNNING.lock().unwrap().get(name).copied().unwrap_or(0)
+}
+
+/// Most missed runs that `catch_up = "all"` makes up for.
+const MAX_CATCH_UP: usize = 100;
+
+/// Runs kept per job in `server.db`.
+const HISTORY: usize = 20;
+
+fn open_db() -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path())?;
+    conn.busy_timeout(crate::kv::BUSY_TIMEOUT)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS cron_jobs (
+            name TEXT PRIMARY KEY,
+            expression TEXT NOT NULL,
+            last_due REAL NOT NULL,
+            next_run REAL
+        );
+        CREATE TABLE IF NOT EXISTS cron_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            due REAL NOT NULL,
+            started REAL,
+            finished REAL NOT NULL,
+            outcome TEXT NOT NULL,
+            error TEXT
+        );
+        CREATE INDEX IF NOT EXISTS cron_runs_name ON cron_runs (name, id);",
+    )?;
+    Ok(conn)
+}
+
+async fn with_db<T: Send + 'static>(
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, String> {
+    tokio::task::spawn_blocking(move || {
+        let conn = open_db().map_err(|e| e.to_string())?;
+        f(&conn).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
 
-    if has_jobs {
-        let jobs: Vec<_> = {
-            let state = app_state.lock().unwrap();
-            state
-                .cron_jobs
-                .iter()
-                .map(|job_info| (job_info.callback_id, job_info.expression.clone()))
-                .collect()
+fn epoch(time: DateTime<Local>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.0
+}
+
+fn from_epoch(secs: f64) -> Option<DateTime<Local>> {
+    DateTime::from_timestamp_millis((secs * 1000.0) as i64).map(|t| t.with_timezone(&Local))
+}
+
+/// How a run ended, as kept in the history.
+enum Outcome {
+    Ok,
+    Error(String),
+    /// Stopped by a reload or exit before it was over.
+    Cancelled,
+    /// Due while the previous run was still going.
+    Skipped,
+    /// Due while the scheduler wasn't running, and not caught up.
+    Missed,
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Error(_) => "error",
+            Outcome::Cancelled => "cancelled",
+            Outcome::Skipped => "skipped",
+            Outcome::Missed => "missed",
+        }
+    }
+}
+
+/// An entry of the history of a job.
+struct Record {
+    due: DateTime<Local>,
+    started: Option<DateTime<Local>>,
+    finished: DateTime<Local>,
+    outcome: Outcome,
+}
+
+impl Record {
+    /// A run that didn't happen.
+    fn not_run(due: DateTime<Local>, outcome: Outcome) -> Self {
+        Record {
+            due,
+            started: None,
+            finished: Local::now(),
+            outcome,
+        }
+    }
+}
+
+/// Adds `record` to the history of `name`, dropping the oldest entries.
+async fn record(name: String, record: Record) {
+    let res = with_db(move |conn| {
+        let error = match &record.outcome {
+            Outcome::Error(e) => Some(e.as_str()),
+            _ => None,
         };
+        conn.execute(
+            "INSERT INTO cron_runs (name, due, started, finished, outcome, error)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                name,
+                epoch(record.due),
+                record.started.map(epoch),
+                epoch(record.finished),
+                record.outcome.name(),
+                error
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM cron_runs WHERE name = ?1 AND id NOT IN
+             (SELECT id FROM cron_runs WHERE name = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![name, HISTORY],
+        )?;
+        Ok(())
+    })
+    .await;
+    if let Err(e) = res {
+        eprintln!("Failed to record cron run: {}", e);
+    }
+}
 
-        let handle = tokio::spawn(async move {
-            let mut cron_jobs: Vec<(usize, Cron)> = Vec::new();
-            for (id, expr) in jobs {
-                // Try parsing using the Parse trait which Cron implements
-                match expr.parse::<Cron>() {
-                    Ok(cron) => cron_jobs.push((id, cron)),
-                    Err(e) => eprintln!("Invalid cron expression '{}': {}", expr, e),
-                }
+/// Stores that the occurrences of `job` up to `due` have been handled.
+async fn save_due(job: &Job, due: DateTime<Local>) {
+    let Some(expression) = job.schedule.expression() else {
+        return;
+    };
+    let name = job.name.clone();
+    let expression = expression.to_string();
+    let next = job.schedule.next_after(due).map(epoch);
+    let res = with_db(move |conn| {
+        conn.execute(
+            "INSERT INTO cron_jobs (name, expression, last_due, next_run) VALUES (?, ?, ?, ?)
+             ON CONFLICT(name) DO UPDATE SET expression = excluded.expression,
+                last_due = excluded.last_due, next_run = excluded.next_run",
+            params![name, expression, epoch(due), next],
+        )
+        .map(|_| ())
+    })
+    .await;
+    if let Err(e) = res {
+        eprintln!("Failed to store cron state: {}", e);
+    }
+}
+
+/// The expression and last handled occurrence of every stored job.
+fn load_all_due(conn: &Connection) -> rusqlite::Result<HashMap<String, (String, f64)>> {
+    let mut stmt = conn.prepare("SELECT name, expression, last_due FROM cron_jobs")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+    rows.collect()
+}
+
+/// The expression and last handled occurrence of the job `name`.
+fn load_due(conn: &Connection, name: &str) -> rusqlite::Result<Option<(String, f64)>> {
+    conn.query_row(
+        "SELECT expression, last_due FROM cron_jobs WHERE name = ?",
+        params![name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+/// Occurrences of `schedule` after `since` and up to `now`, at most `max` + 1.
+fn occurrences(
+    schedule: &Schedule,
+    since: DateTime<Local>,
+    now: DateTime<Local>,
+    max: usize,
+) -> Vec<DateTime<Local>> {
+    let mut found = Vec::new();
+    let mut cursor = since;
+    while found.len() <= max {
+        match schedule.next_after(cursor) {
+            Some(next) if next <= now => {
+                found.push(next);
+                cursor = next;
             }
+            _ => break,
+        }
+    }
+    found
+}
 
-            if cron_jobs.is_empty() {
-                return;
+/// `cron.status()`: the jobs with their next run and, for cron expressions,
+/// their history, newest run first.
+async fn status(lua: Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<LuaTable> {
+    let (tx, loading) = {
+        let state = app_state.lock().unwrap();
+        let loading: Vec<Listed> = state
+            .cron_jobs
+            .iter()
+            .filter(|job| !job.cancelled)
+            .map(|job| Listed {
+                name: job.name.clone(),
+                schedule: job.schedule.clone(),
+                next_run: (!job.paused).then(|| next_run(&job.schedule)).flatten(),
+                paused: job.paused,
+            })
+            .collect();
+        (state.cron_tx.clone(), loading)
+    };
+    let jobs = match tx {
+        Some(tx) => {
+            let (reply, rx) = oneshot::channel();
+            tx.send(Command::List(reply)).ok();
+            rx.await.unwrap_or(loading)
+        }
+        None => loading,
+    };
+
+    let names: Vec<Option<String>> = jobs
+        .iter()
+        .map(|job| job.schedule.expression().map(|_| job.name.clone()))
+        .collect();
+    let histories = with_db(move |conn| {
+        let mut runs = conn.prepare(
+            "SELECT due, started, finished, outcome, error FROM cron_runs
+             WHERE name = ? ORDER BY id DESC",
+        )?;
+        names
+            .iter()
+            .map(|name| match name {
+                Some(name) => runs
+                    .query_map(params![name], |row| {
+                        Ok((
+                            row.get::<_, f64>(0)?,
+                            row.get::<_, Option<f64>>(1)?,
+                            row.get::<_, f64>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, Option<String>>(4)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>(),
+                None => Ok(Vec::new()),
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(LuaError::RuntimeError)?;
+
+    let result = lua.create_table()?;
+    for (job, history) in jobs.into_iter().zip(histories) {
+        let entry = lua.create_table()?;
+        entry.set("name", job.name)?;
+        entry.set("expression", job.schedule.describe())?;
+        entry.set("next_run", job.next_run.map(epoch))?;
+        entry.set("paused", job.paused)?;
+        let runs = lua.create_table()?;
+        for (due, started, finished, outcome, error) in history {
+            let run = lua.create_table()?;
+            run.set("due", due)?;
+            run.set("started", started)?;
+            run.set("finished", finished)?;
+            run.set("outcome", outcome)?;
+            run.set("error", error)?;
+            runs.push(run)?;
+        }
+        entry.set("last_run", runs.get::<LuaValue>(1)?)?;
+        entry.set("history", runs)?;
+        result.push(entry)?;
+    }
+    Ok(result)
+}
+
+/// A job as seen by the scheduler.
+struct Job {
+    key: u64,
+    name: String,
+    schedule: Schedule,
+    overlap: Overlap,
+    timeout: Option<Duration>,
+    catch_up: CatchUp,
+    jitter: Option<Duration>,
+    paused: bool,
+    /// Engine of the Lua state that added the job at runtime. Jobs
+    /// registered while loading go to the main engine.
+    target: Option<WeakSender<EngineRequest>>,
+    /// Runs held back by the jitter.
+    held: usize,
+    /// When the run that waits for the previous one was due, with
+    /// `Overlap::Queue`.
+    queued: Option<DateTime<Local>>,
+    /// Missed runs still to make up for, one after the other.
+    backlog: VecDeque<DateTime<Local>>,
+}
+
+impl Job {
+    fn new(
+        key: u64,
+        name: String,
+        schedule: Schedule,
+        options: &Options,
+        target: Option<WeakSender<EngineRequest>>,
+    ) -> Job {
+        Job {
+            key,
+            name,
+            schedule,
+            overlap: options.overlap,
+            timeout: options.timeout,
+            catch_up: options.catch_up,
+            jitter: options.jitter,
+            paused: false,
+            target,
+            held: 0,
+            queued: None,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    fn next_after(&self, from: DateTime<Local>) -> Option<DateTime<Local>> {
+        if self.paused {
+            return None;
+        }
+        self.schedule.next_after(from)
+    }
+
+    /// Whether no run of the job is held back or going, in any version.
+    fn idle(&self) -> bool {
+        self.held == 0 && running(&self.name) == 0
+    }
+
+    /// Name of the job in `server.db`, for cron expressions.
+    fn history_name(&self) -> Option<String> {
+        self.schedule.expression().map(|_| self.name.clone())
+    }
+}
+
+/// A run sent to the engine.
+struct Run {
+    name: String,
+    /// Whether to record it.
+    history: bool,
+    due: DateTime<Local>,
+    started: DateTime<Local>,
+    done: oneshot::Receiver<Result<(), String>>,
+}
+
+/// Waits for `run` to be over, records it and tells the scheduler. Runs on
+/// its own task, so a reload doesn't lose the outcome.
+async fn finished(run: Run) {
+    let outcome = match run.done.await {
+        Ok(Ok(())) => Outcome::Ok,
+        Ok(Err(e)) => Outcome::Error(e),
+        Err(_) => Outcome::Cancelled,
+    };
+    if run.history {
+        let entry = Record {
+            due: run.due,
+            started: Some(run.started),
+            finished: Local::now(),
+            outcome,
+        };
+        record(run.name.clone(), entry).await;
+    }
+    {
+        let mut running = RUNNING.lock().unwrap();
+        if let Some(count) = running.get_mut(&run.name) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&run.name);
             }
+        }
+    }
+    FINISHED.0.send(run.name).ok();
+}
 
-            println!("Cron scheduler started with {} jobs.", cron_jobs.len());
+/// Runs held back by their job's jitter, as the key and due time.
+type Delayed =
+    FuturesUnordered<std::pin::Pin<Box<dyn Future<Output = (u64, DateTime<Local>)> + Send>>>;
 
-            loop {
-                let now = Local::now();
-                let mut next_run: Option<(DateTime<Local>, Vec<usize>)> = None;
+struct Scheduler {
+    jobs: BTreeMap<u64, Job>,
+    /// The main engine.
+    tx: Sender<EngineRequest>,
+    delayed: Delayed,
+    /// Occurrences up to here have been handled.
+    after: DateTime<Local>,
+}
 
-                for (id, cron) in &cron_jobs {
-                    if let Ok(next) = cron.find_next_occurrence(&now, false) {
-                        match next_run {
-                            None => next_run = Some((next, vec![*id])),
-                            Some((curr_next, ref mut ids)) => {
-                                if next < curr_next {
-                                    next_run = Some((next, vec![*id]));
-                                } else if next == curr_next {
-                                    ids.push(*id);
-                                }
-                            }
+impl Scheduler {
+    /// Adds `job`, making up for the runs it missed since `stored`, its last
+    /// handled occurrence.
+    async fn insert(&mut self, mut job: Job, stored: Option<(String, f64)>) {
+        // A changed expression starts afresh
+        let since = stored
+            .filter(|(expression, _)| Some(expression.as_str()) == job.schedule.expression())
+            .and_then(|(_, due)| from_epoch(due));
+        if let Some(since) = since {
+            let mut missed = occurrences(&job.schedule, since, self.after, MAX_CATCH_UP);
+            if let Some(&last) = missed.last() {
+                match job.catch_up {
+                    CatchUp::Never => {
+                        println!(
+                            "Cron job {} missed {} run(s) since {}",
+                            job.name,
+                            missed.len(),
+                            since.to_rfc3339()
+                        );
+                        record(job.name.clone(), Record::not_run(last, Outcome::Missed)).await;
+                    }
+                    CatchUp::Once => job.backlog.push_back(last),
+                    CatchUp::All => {
+                        if missed.len() > MAX_CATCH_UP {
+                            println!(
+                                "Cron job {} missed more than {} runs, catching up on the first {}",
+                                job.name, MAX_CATCH_UP, MAX_CATCH_UP
+                            );
+                            missed.truncate(MAX_CATCH_UP);
                         }
+                        job.backlog.extend(missed);
                     }
                 }
+            }
+        }
+        save_due(&job, self.after).await;
+        let key = job.key;
+        let catch_up = job.backlog.pop_front();
+        if catch_up.is_some() {
+            println!("Catching up on cron job {}", job.name);
+        }
+        self.jobs.insert(key, job);
+        if let Some(due) = catch_up {
+            self.trigger(key, due).await;
+        }
+    }
 
-                if let Some((next, ids)) = next_run {
-                    let sleep_duration = next
-                        .signed_duration_since(now)
-                        .to_std()
-                        .unwrap_or(std::time::Duration::from_secs(0));
-                    tokio::time::sleep(sleep_duration).await;
+    /// Starts a run of the job with `key`, at a random point of its jitter
+    /// window if it has one. One-off timers run on time.
+    async fn trigger(&mut self, key: u64, due: DateTime<Local>) {
+        let Some(job) = self.jobs.get_mut(&key) else {
+            return;
+        };
+        match job
+            .jitter
+            .filter(|_| !matches!(job.schedule, Schedule::At(_)))
+        {
+            Some(jitter) => {
+                job.held += 1;
+                let delay = jitter.mul_f64(rand::random::<f64>());
+                self.delayed.push(Box::pin(async move {
+                    tokio::time::sleep(delay).await;
+                    (key, due)
+                }));
+            }
+            None => self.send(key, due).await,
+        }
+    }
 
-                    for id in ids {
-                        if let Err(e) = tx.send(EngineRequest::Cron(id)).await {
-                            eprintln!("Failed to send cron trigger: {}", e);
-                        }
+    /// Sends a run of the job with `key` to its engine.
+    async fn send(&mut self, key: u64, due: DateTime<Local>) {
+        let Some(job) = self.jobs.get(&key) else {
+            return;
+        };
+        let once = matches!(job.schedule, Schedule::At(_));
+        let (done, rx) = oneshot::channel();
+        let run = CronRun {
+            key,
+            name: job.name.clone(),
+            once,
+            timeout: job.timeout,
+            done,
+        };
+        let engine = match &job.target {
+            Some(target) => target.upgrade(),
+            None => Some(self.tx.clone()),
+        };
+        *RUNNING.lock().unwrap().entry(job.name.clone()).or_default() += 1;
+        tokio::spawn(finished(Run {
+            name: job.name.clone(),
+            history: job.history_name().is_some(),
+            due,
+            started: Local::now(),
+            done: rx,
+        }));
+        match engine {
+            Some(engine) => {
+                if let Err(e) = engine.send(EngineRequest::Cron(run)).await {
+                    eprintln!("Failed to send cron trigger: {}", e);
+                }
+                if once {
+                    self.jobs.remove(&key);
+                }
+            }
+            // The Lua state that added it was replaced
+            None => {
+                self.jobs.remove(&key);
+            }
+        }
+    }
+
+    /// Starts the jobs due at `next`, the first occurrence after `from`.
+    async fn tick(&mut self, from: DateTime<Local>, next: DateTime<Local>) {
+        let due: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|job| job.next_after(from) == Some(next))
+            .map(|job| job.key)
+            .collect();
+        for key in due {
+            let Some(job) = self.jobs.get_mut(&key) else {
+                continue;
+            };
+            save_due(job, next).await;
+            if job.idle() || job.overlap == Overlap::Parallel {
+                self.trigger(key, next).await;
+            } else if job.overlap == Overlap::Queue {
+                job.queued = Some(next);
+            } else {
+                println!(
+                    "Skipping cron job {}: the previous run is still going",
+                    job.name
+                );
+                if let Some(name) = job.history_name() {
+                    record(name, Record::not_run(next, Outcome::Skipped)).await;
+                }
+            }
+        }
+        self.after = self.after.max(next);
+    }
+
+    /// Starts the run waiting for the one of the job `name` that is over.
+    async fn finish(&mut self, name: &str) {
+        let Some(job) = self.jobs.values_mut().find(|job| job.name == name) else {
+            return;
+        };
+        if job.idle() {
+            let due = job.backlog.pop_front().or_else(|| job.queued.take());
+            if let Some(due) = due {
+                let key = job.key;
+                self.trigger(key, due).await;
+            }
+        }
+    }
+
+    /// Sends a run that was held back by the jitter.
+    async fn release(&mut self, key: u64, due: DateTime<Local>) {
+        // Cancelled while held back
+        let Some(job) = self.jobs.get_mut(&key) else {
+            return;
+        };
+        job.held -= 1;
+        self.send(key, due).await;
+    }
+
+    async fn command(&mut self, command: Command) {
+        let from = Local::now().max(self.after);
+        match command {
+            Command::Add(new) => {
+                let NewJob {
+                    key,
+                    name,
+                    schedule,
+                    options,
+                    target,
+                } = *new;
+                let name = match name {
+                    Some(name) => {
+                        // Adding a job under a taken name replaces it
+                        self.jobs.retain(|_, job| job.name != name);
+                        name
                     }
-                    // Avoid tight loop
-                    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
-                } else {
-                    break;
+                    None => unique_name(&schedule.describe(), |name| {
+                        self.jobs.values().any(|job| job.name == name)
+                    }),
+                };
+                let job = Job::new(key, name, schedule, &options, target);
+                let stored = match job.history_name() {
+                    Some(name) => with_db(move |conn| load_due(conn, &name))
+                        .await
+                        .unwrap_or_else(|e| {
+                            eprintln!("Failed to load cron state: {}", e);
+                            None
+                        }),
+                    None => None,
+                };
+                self.insert(job, stored).await;
+            }
+            Command::Pause(key) => {
+                if let Some(job) = self.jobs.get_mut(&key) {
+                    job.paused = true;
                 }
             }
+            Command::Resume(key) => {
+                if let Some(job) = self.jobs.get_mut(&key)
+                    && std::mem::take(&mut job.paused)
+                {
+                    // Runs due while paused are not missed
+                    save_due(job, from).await;
+                }
+            }
+            Command::Cancel(key) => {
+                self.jobs.remove(&key);
+            }
+            Command::NextRun(key, reply) => {
+                let next = self.jobs.get(&key).and_then(|job| job.next_after(from));
+                reply.send(next).ok();
+            }
+            Command::List(reply) => {
+                let jobs = self
+                    .jobs
+                    .values()
+                    .map(|job| Listed {
+                        name: job.name.clone(),
+                        schedule: job.schedule.clone(),
+                        next_run: job.next_after(from),
+                        paused: job.paused,
+                    })
+                    .collect();
+                reply.send(jobs).ok();
+            }
+        }
+    }
+}
+
+/// Schedules the jobs of `app_state`, and those added through `commands`
+/// while it runs, until aborted. Each job runs at most as often as its
+/// overlap policy allows: a run counts until the engine has finished it, on
+/// the main thread or a worker, even one retired by a reload.
+///
+/// The last handled occurrence of each cron expression is kept in
+/// `server.db`, so runs that were due while the process was down or
+/// reloading are found on the next start, and made up for if the job asks
+/// to catch up.
+pub fn start(
+    app_state: Arc<Mutex<AppState>>,
+    tx: Sender<EngineRequest>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) -> tokio::task::JoinHandle<()> {
+    let jobs: Vec<Job> = {
+        let state = app_state.lock().unwrap();
+        state
+            .cron_jobs
+            .iter()
+            .filter(|info| !info.cancelled)
+            .map(|info| {
+                let options = Options {
+                    overlap: info.overlap,
+                    timeout: info.timeout,
+                    catch_up: info.catch_up,
+                    jitter: info.jitter,
+                    ..Options::default()
+                };
+                let key = info.callback_id as u64;
+                let schedule = info.schedule.clone();
+                let mut job = Job::new(key, info.name.clone(), schedule, &options, None);
+                job.paused = info.paused;
+                job
+            })
+            .collect()
+    };
+
+    tokio::spawn(async move {
+        if !jobs.is_empty() {
+            println!("Cron scheduler started with {} jobs.", jobs.len());
+        }
+        // Released when the previous scheduler is aborted
+        let mut finished = FINISHED.1.lock().await;
+        let mut stored = with_db(load_all_due).await.unwrap_or_else(|e| {
+            eprintln!("Failed to load cron state: {}", e);
+            HashMap::new()
         });
+        let mut scheduler = Scheduler {
+            jobs: BTreeMap::new(),
+            tx,
+            delayed: FuturesUnordered::new(),
+            after: Local::now().trunc_subsecs(0),
+        };
+        for job in jobs {
+            let due = stored.remove(&job.name);
+            scheduler.insert(job, due).await;
+        }
 
-        Some(handle)
-    } else {
-        None
-    }
+        loop {
+            let now = Local::now();
+            let from = now.max(scheduler.after);
+            let next = scheduler
+                .jobs
+                .values()
+                .filter_map(|job| job.next_after(from))
+                .min();
+            let wait = next
+                .map(|next| next.signed_duration_since(now).to_std().unwrap_or_default())
+                .unwrap_or_default();
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait), if next.is_some() => {
+                    if let Some(next) = next {
+                        scheduler.tick(from, next).await;
+                    }
+                }
+                Some(name) = finished.recv() => {
+                    scheduler.finish(&name).await;
+                }
+                Some((key, due)) = scheduler.delayed.next() => {
+                    scheduler.release(key, due).await;
+                }
+                Some(command) = commands.recv() => {
+                    scheduler.command(command).await;
+                }
+                else => break,
+            }
+        }
+    })
 }
@@ -0,0 +1,531 @@
+use crate::session::{SESSION_COOKIE, Session};
+use crate::types::AppState;
+use crate::{cron, repl, sandbox, web_server};
+use axum::{
+    Json, Router,
+    extract::{Path as AxPath, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{DateTime, Local, SecondsFormat};
+use mlua::Debug;
+use mlua::prelude::*;
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// How many errors, and hits per breakpoint, are kept.
+const MAX_ERRORS: usize = 50;
+const MAX_HITS: usize = 10;
+/// Longest local value shown in a hit.
+const MAX_VALUE_LEN: usize = 200;
+
+/// Settings of `srv:debugger`.
+#[derive(Clone)]
+pub struct DebuggerConfig {
+    pub path: String,
+    /// Emails of the users allowed in.
+    pub users: Vec<String>,
+}
+
+impl DebuggerConfig {
+    pub fn from_lua(path: String, opts: &LuaTable) -> LuaResult<Self> {
+        let users: Vec<String> = opts
+            .get::<Option<Vec<String>>>("users")?
+            .unwrap_or_default();
+        if users.is_empty() {
+            return Err(LuaError::RuntimeError(
+                "debugger needs at least one user in `users`".into(),
+            ));
+        }
+        if path.contains('{') {
+            return Err(LuaError::RuntimeError(format!(
+                "debugger path can't have captures: {}",
+                path
+            )));
+        }
+        Ok(DebuggerConfig { path, users })
+    }
+
+    /// Whether the debugger serves `path`, which a route can then not use.
+    pub fn owns(&self, path: &str) -> bool {
+        let base = self.path.trim_end_matches('/');
+        path == self.path || path.starts_with(&format!("{}/api/", base))
+    }
+}
+
+// Everything below is shared by the main thread and the workers, so one page
+// shows the whole process.
+
+struct Task {
+    label: String,
+    engine: String,
+    started: Instant,
+}
+
+struct ErrorEntry {
+    time: DateTime<Local>,
+    engine: String,
+    context: String,
+    message: String,
+}
+
+struct Hit {
+    time: DateTime<Local>,
+    engine: String,
+    function: String,
+    locals: Vec<(String, String)>,
+    traceback: String,
+}
+
+struct Breakpoint {
+    id: u64,
+    file: String,
+    line: usize,
+    hits: u64,
+    recent: VecDeque<Hit>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static TASKS: Mutex<BTreeMap<u64, Task>> = Mutex::new(BTreeMap::new());
+static ERRORS: Mutex<VecDeque<ErrorEntry>> = Mutex::new(VecDeque::new());
+static BREAKPOINTS: Mutex<Vec<Breakpoint>> = Mutex::new(Vec::new());
+/// Bumped on every change to `BREAKPOINTS`, so engines know to update their hook.
+static BREAKPOINTS_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn engine_name() -> String {
+    std::thread::current().name().unwrap_or("main").to_string()
+}
+
+/// Removes the task from the pending list when the future is done or dropped.
+struct TaskGuard(u64);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        TASKS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Lists `fut` as pending work of the current engine until it completes.
+pub fn tracked<F: Future>(label: String, fut: F) -> impl Future<Output = F::Output> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TASKS.lock().unwrap().insert(
+        id,
+        Task {
+            label,
+            engine: engine_name(),
+            started: Instant::now(),
+        },
+    );
+    let guard = TaskGuard(id);
+    async move {
+        let _guard = guard;
+        fut.await
+    }
+}
+
+/// Logs an error and keeps it for the debugger.
+pub fn report(context: String, err: &dyn Display) {
+    eprintln!("{}: {}", context, err);
+    let mut errors = ERRORS.lock().unwrap();
+    if errors.len() == MAX_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(ErrorEntry {
+        time: Local::now(),
+        engine: engine_name(),
+        context,
+        message: err.to_string(),
+    });
+}
+
+/// A breakpoint as seen by the line hook of one Lua state.
+struct Point {
+    id: u64,
+    file: String,
+    line: usize,
+}
+
+/// Per-state parts of the debugger, kept in the app data.
+struct Probe {
+    getlocal: LuaFunction,
+    traceback: LuaFunction,
+    /// Arms the calling thread, then calls the function it is given.
+    trampoline: LuaFunction,
+    version: u64,
+    points: Option<Rc<Vec<Point>>>,
+}
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    // The debug library can break out of the sandbox, so it is not made
+    // global. Only its read-only functions are kept, for the line hook.
+    // SAFETY: luaopen_debug only builds and returns the library table.
+    let open = unsafe { lua.create_c_function(mlua::ffi::luaopen_debug)? };
+    let debug: LuaTable = open.call(())?;
+
+    let arm = lua.create_function(|lua, ()| {
+        let points = lua.app_data_ref::<Probe>().and_then(|p| p.points.clone());
+        if let Some(points) = points {
+            sandbox::set_hook(lua, Some(Box::new(line_hook(points))))?;
+        }
+        Ok(())
+    })?;
+    let trampoline = lua
+        .load("local arm = ...\nreturn function(f, ...) arm() return f(...) end")
+        .set_name("=debugger")
+        .call::<LuaFunction>(arm)?;
+
+    lua.set_app_data(Probe {
+        getlocal: debug.get("getlocal")?,
+        traceback: debug.get("traceback")?,
+        trampoline,
+        version: 0,
+        points: None,
+    });
+    Ok(())
+}
+
+/// Brings the line hook of `lua` up to date with the breakpoints. Called by
+/// the engine before it starts a handler.
+pub fn sync(lua: &Lua) {
+    let version = BREAKPOINTS_VERSION.load(Ordering::Acquire);
+    let Some(mut probe) = lua.app_data_mut::<Probe>() else {
+        return;
+    };
+    if probe.version == version {
+        return;
+    }
+    probe.version = version;
+    let points: Vec<Point> = BREAKPOINTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|bp| Point {
+            id: bp.id,
+            file: bp.file.clone(),
+            line: bp.line,
+        })
+        .collect();
+    let hook: Option<sandbox::LineHook> = if points.is_empty() {
+        probe.points = None;
+        None
+    } else {
+        let points = Rc::new(points);
+        probe.points = Some(points.clone());
+        Some(Box::new(line_hook(points)))
+    };
+    if let Err(e) = sandbox::set_hook(lua, hook) {
+        eprintln!("Failed to set breakpoints: {}", e);
+    }
+}
+
+/// Wraps a handler so that breakpoints apply to it. mlua reuses coroutines
+/// that were created before the hook was set, so each handler arms the one
+/// it runs on. Without breakpoints `func` is returned as is.
+pub fn instrument(lua: &Lua, func: LuaFunction) -> LuaFunction {
+    let Some(probe) = lua.app_data_ref::<Probe>() else {
+        return func;
+    };
+    if probe.points.is_none() {
+        return func;
+    }
+    probe.trampoline.bind(func.clone()).unwrap_or(func)
+}
+
+fn line_hook(points: Rc<Vec<Point>>) -> impl Fn(&Lua, &Debug) -> LuaResult<LuaVmState> {
+    move |lua, debug| {
+        let Some(line) = debug.current_line() else {
+            return Ok(LuaVmState::Continue);
+        };
+        if !points.iter().any(|p| p.line == line) {
+            return Ok(LuaVmState::Continue);
+        }
+        let source = debug.source();
+        let Some(file) = source.source.as_deref().and_then(|s| s.strip_prefix('@')) else {
+            return Ok(LuaVmState::Continue);
+        };
+        for point in points.iter() {
+            if point.line == line && same_file(file, &point.file) {
+                // Errors here must not reach the code being debugged
+                let _ = capture(lua, debug, point.id);
+            }
+        }
+        Ok(LuaVmState::Continue)
+    }
+}
+
+/// Whether the chunk `source` is `file`, which may leave out leading
+/// directories.
+fn same_file(source: &str, file: &str) -> bool {
+    let source = source.trim_start_matches("./");
+    let file = file.trim_start_matches("./");
+    source == file || source.ends_with(&format!("/{}", file))
+}
+
+fn capture(lua: &Lua, debug: &Debug, id: u64) -> LuaResult<()> {
+    let (getlocal, traceback) = {
+        let probe = lua
+            .app_data_ref::<Probe>()
+            .ok_or_else(|| LuaError::RuntimeError("debugger not registered".into()))?;
+        (probe.getlocal.clone(), probe.traceback.clone())
+    };
+    // Level 1 is the function running the line, since the hook adds no frame
+    let mut locals = Vec::new();
+    for n in 1.. {
+        let res: LuaMultiValue = getlocal.call((1, n))?;
+        let mut res = res.into_iter();
+        let Some(LuaValue::String(name)) = res.next() else {
+            break;
+        };
+        let name = name.to_string_lossy();
+        // Skip the slots Lua uses internally, e.g. "(for state)"
+        if name.starts_with('(') {
+            continue;
+        }
+        let mut value = repl::format_values(lua, &res.collect());
+        if value.len() > MAX_VALUE_LEN {
+            let end = value.floor_char_boundary(MAX_VALUE_LEN);
+            value.truncate(end);
+            value.push_str("...");
+        }
+        locals.push((name, value));
+    }
+    let traceback: String = traceback.call((LuaNil, 1))?;
+    let function = match debug.names().name {
+        Some(name) => name.to_string(),
+        None => {
+            let source = debug.source();
+            format!(
+                "function <{}:{}>",
+                source.short_src.as_deref().unwrap_or("?"),
+                source.line_defined.unwrap_or(0)
+            )
+        }
+    };
+
+    let mut breakpoints = BREAKPOINTS.lock().unwrap();
+    if let Some(bp) = breakpoints.iter_mut().find(|bp| bp.id == id) {
+        bp.hits += 1;
+        if bp.recent.len() == MAX_HITS {
+            bp.recent.pop_front();
+        }
+        bp.recent.push_back(Hit {
+            time: Local::now(),
+            engine: engine_name(),
+            function,
+            locals,
+            traceback,
+        });
+    }
+    Ok(())
+}
+
+/// The debugger pages, served under `config.path`.
+pub fn router(config: &DebuggerConfig) -> Router<Arc<Mutex<AppState>>> {
+    let base = config.path.trim_end_matches('/');
+    Router::new()
+        .route(&config.path, get(page))
+        .route(&format!("{}/api/state", base), get(state))
+        .route(&format!("{}/api/breakpoints", base), post(add_breakpoint))
+        .route(
+            &format!("{}/api/breakpoints/{{id}}", base),
+            delete(remove_breakpoint),
+        )
+}
+
+/// Lets the request through if it comes from one of the configured users.
+/// Anonymous visitors of `page` are sent to the login.
+async fn authorize(
+    app_state: &Arc<Mutex<AppState>>,
+    jar: &CookieJar,
+    page: Option<&str>,
+) -> Result<(), Response> {
+    let (session_config, users) = {
+        let state = app_state.lock().unwrap();
+        let users = state
+            .debugger
+            .as_ref()
+            .map(|config| config.users.clone())
+            .unwrap_or_default();
+        (state.session_config.clone(), users)
+    };
+    let cookie = jar.get(SESSION_COOKIE).map(|c| c.value().to_string());
+    let session = Session::load(&session_config, cookie.as_deref()).await;
+    match web_server::login_user(app_state, &session) {
+        Some(email) if users.iter().any(|u| u.eq_ignore_ascii_case(&email)) => Ok(()),
+        Some(_) => Err((StatusCode::FORBIDDEN, "Forbidden").into_response()),
+        None => match page {
+            Some(page) => Err(web_server::login_redirect(app_state, page).await),
+            None => Err((StatusCode::UNAUTHORIZED, "Login required").into_response()),
+        },
+    }
+}
+
+async fn page(State(app_state): State<Arc<Mutex<AppState>>>, jar: CookieJar) -> Response {
+    let path = app_state
+        .lock()
+        .unwrap()
+        .debugger
+        .as_ref()
+        .map(|config| config.path.clone())
+        .unwrap_or_default();
+    if let Err(resp) = authorize(&app_state, &jar, Some(&path)).await {
+        return resp;
+    }
+    Html(include_str!("debugger.html")).into_response()
+}
+
+async fn state(State(app_state): State<Arc<Mutex<AppState>>>, jar: CookieJar) -> Response {
+    if let Err(resp) = authorize(&app_state, &jar, None).await {
+        return resp;
+    }
+    let (routes, cron_jobs) = {
+        let state = app_state.lock().unwrap();
+        let mut routes: Vec<JsonValue> = state
+            .routes
+            .iter()
+            .map(
+                |r| json!({ "method": r.method, "path": r.path, "require_login": r.require_login }),
+            )
+            .collect();
+        routes.extend(
+            state
+                .websocket_routes
+                .iter()
+                .map(|r| json!({ "method": "WEBSOCKET", "path": r.path, "require_login": false })),
+        );
+        let cron_jobs: Vec<JsonValue> = state
+            .cron_jobs
+            .iter()
+            .map(|job| {
+                json!({
+                    "id": job.callback_id + 1,
+                    "expression": job.schedule.describe(),
+                    "next": cron::next_run(&job.schedule).map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false)),
+                })
+            })
+            .collect();
+        (routes, cron_jobs)
+    };
+    let pending: Vec<JsonValue> = TASKS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|task| {
+            json!({
+                "label": task.label,
+                "engine": task.engine,
+                "seconds": task.started.elapsed().as_secs_f64(),
+            })
+        })
+        .collect();
+    let errors: Vec<JsonValue> = ERRORS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|e| {
+            json!({
+                "time": e.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+                "engine": e.engine,
+                "context": e.context,
+                "message": e.message,
+            })
+        })
+        .collect();
+    let breakpoints: Vec<JsonValue> = BREAKPOINTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|bp| {
+            let recent: Vec<JsonValue> = bp
+                .recent
+                .iter()
+                .rev()
+                .map(|hit| {
+                    json!({
+                        "time": hit.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+                        "engine": hit.engine,
+                        "function": hit.function,
+                        "locals": hit.locals,
+                        "traceback": hit.traceback,
+                    })
+                })
+                .collect();
+            json!({
+                "id": bp.id,
+                "file": bp.file,
+                "line": bp.line,
+                "hits": bp.hits,
+                "recent": recent,
+            })
+        })
+        .collect();
+    Json(json!({
+        "version": env!("LUMEN_VERSION"),
+        "pid": std::process::id(),
+        "routes": routes,
+        "cron": cron_jobs,
+        "pending": pending,
+        "errors": errors,
+        "breakpoints": breakpoints,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct NewBreakpoint {
+    file: String,
+    line: usize,
+}
+
+// Only JSON bodies are accepted, which other sites can't send without CORS.
+async fn add_breakpoint(
+    State(app_state): State<Arc<Mutex<AppState>>>,
+    jar: CookieJar,
+    Json(new): Json<NewBreakpoint>,
+) -> Response {
+    if let Err(resp) = authorize(&app_state, &jar, None).await {
+        return resp;
+    }
+    let file = new.file.trim().trim_start_matches('@').to_string();
+    if file.is_empty() || new.line == 0 {
+        return (StatusCode::BAD_REQUEST, "file and line are required").into_response();
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    BREAKPOINTS.lock().unwrap().push(Breakpoint {
+        id,
+        file,
+        line: new.line,
+        hits: 0,
+        recent: VecDeque::new(),
+    });
+    BREAKPOINTS_VERSION.fetch_add(1, Ordering::Release);
+    (StatusCode::CREATED, Json(json!({ "id": id }))).into_response()
+}
+
+async fn remove_breakpoint(
+    State(app_state): State<Arc<Mutex<AppState>>>,
+    jar: CookieJar,
+    AxPath(id): AxPath<u64>,
+) -> Response {
+    if let Err(resp) = authorize(&app_state, &jar, None).await {
+        return resp;
+    }
+    let mut breakpoints = BREAKPOINTS.lock().unwrap();
+    let before = breakpoints.len();
+    breakpoints.retain(|bp| bp.id != id);
+    if breakpoints.len() == before {
+        return (StatusCode::NOT_FOUND, "No such breakpoint").into_response();
+    }
+    BREAKPOINTS_VERSION.fetch_add(1, Ordering::Release);
+    StatusCode::NO_CONTENT.into_response()
+}
@@ -500,7 +500,7 @@ pub fn register(lua: &Lua, _app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
                         );
                         query.append_pair("access_type", "offline");
                         query.append_pair("prompt", "consent");
-                        query.append_pair("state", &email);
+                        query.append_pair("state", &crate::oauth::begin_google_link());
                     }
 
                     res.set("status", "unauthorized")?;
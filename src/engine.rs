@@ -0,0 +1,489 @@
+use crate::types::{AppState, EngineRequest, JobRun};
+use crate::worker::Pool;
+use crate::{cron, debugger, exit, repl, response, sandbox, web_server};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+type Pending<'lua> = FuturesUnordered<Pin<Box<dyn Future<Output = ()> + 'lua>>>;
+
+/// Lua callbacks registered by one version of the script, resolved from the
+/// registry once when it starts serving.
+pub struct Handlers {
+    /// Handlers with the path pattern they were registered for.
+    routes: Vec<(String, LuaFunction)>,
+    middlewares: Vec<(String, LuaFunction)>,
+    websocket_routes: Vec<(String, LuaFunction)>,
+    cron_jobs: Vec<LuaFunction>,
+    jobs: Vec<LuaFunction>,
+    telegram: Option<LuaFunction>,
+    shutdown_hooks: Vec<LuaFunction>,
+}
+
+impl Handlers {
+    pub fn load(lua: &Lua, state: &AppState) -> LuaResult<Self> {
+        Ok(Handlers {
+            routes: state
+                .routes
+                .iter()
+                .map(|route| Ok((route.path.clone(), lua.registry_value(&route.callback_key)?)))
+                .collect::<LuaResult<_>>()?,
+            middlewares: state
+                .middlewares
+                .iter()
+                .map(|mw| Ok((mw.prefix.clone(), lua.registry_value(&mw.callback_key)?)))
+                .collect::<LuaResult<_>>()?,
+            websocket_routes: state
+                .websocket_routes
+                .iter()
+                .map(|route| Ok((route.path.clone(), lua.registry_value(&route.callback_key)?)))
+                .collect::<LuaResult<_>>()?,
+            cron_jobs: state
+                .cron_jobs
+                .iter()
+                .map(|job| lua.registry_value(&job.callback_key))
+                .collect::<LuaResult<_>>()?,
+            jobs: state
+                .job_defs
+                .iter()
+                .map(|def| lua.registry_value(&def.callback_key))
+                .collect::<LuaResult<_>>()?,
+            telegram: state
+                .telegram_handler
+                .as_ref()
+                .map(|key| lua.registry_value(key))
+                .transpose()?,
+            shutdown_hooks: exit::shutdown_hooks(lua, state)?,
+        })
+    }
+
+    /// The middleware of the groups a route pattern belongs to. Patterns are
+    /// matched rather than request paths, since group prefixes may have
+    /// captures.
+    fn middlewares_for(&self, lua: &Lua, pattern: &str) -> Vec<LuaFunction> {
+        self.middlewares
+            .iter()
+            .filter(|(prefix, _)| web_server::path_has_prefix(pattern, prefix))
+            .map(|(_, func)| debugger::instrument(lua, func.clone()))
+            .collect()
+    }
+}
+
+/// How long in-flight work may run after `exit()` or a reload.
+pub fn drain_timeout() -> Duration {
+    let secs = crate::util::env_var("LUMEN_EXIT_TIMEOUT")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(5);
+    Duration::from_secs(secs)
+}
+
+/// Tells an engine to stop. Dropping the sender retires it.
+pub enum Stop {
+    /// A newer version has taken over.
+    Retire,
+    /// The process is exiting, so the `on_shutdown` hooks run once the
+    /// in-flight requests are done.
+    Exit,
+}
+
+/// Runs the callbacks of one version of the script until it is stopped.
+///
+/// When retired, requests that were already routed here are still served,
+/// and the loop ends once they are done and every sender is gone. On exit no
+/// new requests are taken. Either way in-flight work gets
+/// `LUMEN_EXIT_TIMEOUT` seconds before it is cancelled.
+///
+/// With a `pool`, rest, cron, job and telegram requests are passed on to the
+/// workers. `load` counts the requests running here.
+pub async fn run(
+    lua: Lua,
+    handlers: Handlers,
+    mut req_rx: mpsc::Receiver<EngineRequest>,
+    mut stop: oneshot::Receiver<Stop>,
+    pool: Option<Pool>,
+    load: Arc<AtomicUsize>,
+) {
+    let lua = &lua;
+    let mut pending_requests: Pending = FuturesUnordered::new();
+    let mut exiting = false;
+    let mut stopped = false;
+    let mut closed = false;
+    let sleep = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(sleep);
+    let mut timeout_active = false;
+
+    loop {
+        tokio::select! {
+            req = req_rx.recv(), if !closed && !exiting => {
+                match req {
+                    Some(req) => {
+                        let req = match &pool {
+                            Some(pool) if shared(&req) => pool.dispatch(req),
+                            _ => Some(req),
+                        };
+                        if let Some(req) = req {
+                            debugger::sync(lua);
+                            let before = pending_requests.len();
+                            dispatch(lua, &handlers, req, &mut pending_requests);
+                            if pending_requests.len() > before {
+                                load.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    None => closed = true,
+                }
+            }
+            Some(_) = pending_requests.next() => {
+                load.fetch_sub(1, Ordering::Relaxed);
+            }
+            res = &mut stop, if !stopped => {
+                stopped = true;
+                exiting = matches!(res, Ok(Stop::Exit));
+            }
+            _ = &mut sleep, if timeout_active => {
+                if exiting {
+                    println!(
+                        "Exit timeout reached. Cancelling {} pending requests.",
+                        pending_requests.len()
+                    );
+                } else {
+                    println!(
+                        "Reload timeout reached. Cancelling {} pending requests of the previous version.",
+                        pending_requests.len()
+                    );
+                }
+                break;
+            }
+        }
+
+        if pending_requests.is_empty() && (exiting || (stopped && closed)) {
+            break;
+        }
+        if stopped && !timeout_active {
+            sleep
+                .as_mut()
+                .reset(tokio::time::Instant::now() + drain_timeout());
+            timeout_active = true;
+        }
+    }
+
+    drop(pending_requests);
+    if exiting {
+        exit::run_shutdown_hooks(handlers.shutdown_hooks).await;
+    }
+}
+
+/// Whether every Lua state of the version can handle `req`, so that it may
+/// go to a worker. Cron jobs added at runtime only exist in one of them.
+fn shared(req: &EngineRequest) -> bool {
+    match req {
+        EngineRequest::Rest(_) | EngineRequest::TelegramUpdate(_) | EngineRequest::Job(_) => true,
+        EngineRequest::Cron(run) => cron::static_index(run.key).is_some(),
+        _ => false,
+    }
+}
+
+fn dispatch<'lua>(
+    lua: &'lua Lua,
+    handlers: &Handlers,
+    req: EngineRequest,
+    pending_requests: &mut Pending<'lua>,
+) {
+    match req {
+        EngineRequest::Rest(req) => {
+            let Some((pattern, func)) = handlers.routes.get(req.callback_id).cloned() else {
+                req.response_tx
+                    .send(Err("Invalid callback ID".to_string()))
+                    .ok();
+                return;
+            };
+            let middlewares = handlers.middlewares_for(lua, &pattern);
+            let func = debugger::instrument(lua, func);
+            let request = req.request;
+            let label = format!("{} {}", request.method, request.path);
+            let response_tx = req.response_tx;
+
+            // Create future for the request
+            let fut = async move {
+                let res: LuaResult<response::HandlerResponse> = sandbox::limited(async {
+                    let req_table = web_server::request_to_lua(lua, &request)?;
+                    let val = web_server::call_handler(lua, func, middlewares, req_table).await?;
+                    response::from_lua_value(lua, val)
+                })
+                .await;
+
+                match res {
+                    Ok(response::HandlerResponse::Full(res)) => {
+                        response_tx.send(Ok((res, None))).ok();
+                    }
+                    Ok(response::HandlerResponse::Stream(stream)) => {
+                        let (chunk_tx, chunk_rx) = mpsc::channel(16);
+                        if response_tx
+                            .send(Ok((stream.head.clone(), Some(chunk_rx))))
+                            .is_ok()
+                        {
+                            sandbox::limited(response::drive_stream(lua, stream, chunk_tx)).await;
+                        }
+                    }
+                    Err(e) => {
+                        let mut err_msg = e.to_string();
+                        if exit::exit_code(&e).is_some() {
+                            err_msg = "Process exiting".to_string();
+                        } else {
+                            debugger::report(
+                                format!(
+                                    "Error in rest handler {} {}",
+                                    request.method, request.path
+                                ),
+                                &e,
+                            );
+                        }
+                        response_tx.send(Err(err_msg)).ok();
+                    }
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(label, fut)));
+        }
+        EngineRequest::WebSocket(req) => {
+            let Some((pattern, func)) = handlers.websocket_routes.get(req.callback_id).cloned()
+            else {
+                eprintln!("Failed to retrieve websocket callback: Invalid websocket callback ID");
+                let _ = req.accept_tx.send(Err("Invalid callback ID".to_string()));
+                return;
+            };
+            let middlewares = handlers.middlewares_for(lua, &pattern);
+            let func = debugger::instrument(lua, func);
+            let request = req.request;
+            let label = format!("websocket {}", request.path);
+            let accept = Rc::new(Cell::new(Some(req.accept_tx)));
+            let connection_rx = Cell::new(Some(req.connection_rx));
+            let fut = async move {
+                // Reaching the handler means the middleware let the upgrade
+                // through, so it waits for the socket and serves it
+                let inner_accept = accept.clone();
+                let res: LuaResult<LuaValue> = sandbox::limited(async {
+                    let handler =
+                        lua.create_async_function(move |_, (_, req): (LuaValue, LuaTable)| {
+                            let func = func.clone();
+                            let accept = inner_accept.take();
+                            let connection_rx = connection_rx.take();
+                            async move {
+                                let (Some(accept), Some(connection_rx)) = (accept, connection_rx)
+                                else {
+                                    return Err(LuaError::RuntimeError(
+                                        "websocket handler already called".into(),
+                                    ));
+                                };
+                                let _ = accept.send(Ok(None));
+                                if let Ok(connection) = connection_rx.await {
+                                    let _guard = connection.guard();
+                                    func.call_async::<()>((connection, req)).await?;
+                                }
+                                Ok(LuaValue::Nil)
+                            }
+                        })?;
+                    let req_table = web_server::request_to_lua(lua, &request)?;
+                    web_server::call_handler(lua, handler, middlewares, req_table).await
+                })
+                .await;
+                // Middleware that answers itself refuses the upgrade
+                if let Some(accept) = accept.take() {
+                    let res = match &res {
+                        Ok(val) => match response::from_lua_value(lua, val.clone()) {
+                            Ok(response::HandlerResponse::Full(res)) => Ok(Some(res)),
+                            Ok(response::HandlerResponse::Stream(stream)) => Ok(Some(stream.head)),
+                            Err(e) => Err(e.to_string()),
+                        },
+                        Err(e) => Err(e.to_string()),
+                    };
+                    let _ = accept.send(res);
+                }
+                match res {
+                    Err(e) if exit::exit_code(&e).is_none() => {
+                        debugger::report(
+                            format!("Error in websocket handler {}", request.path),
+                            &e,
+                        );
+                    }
+                    _ => {}
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(label, fut)));
+        }
+        EngineRequest::Cron(run) => {
+            let func = match cron::static_index(run.key) {
+                Some(index) => handlers.cron_jobs.get(index).cloned(),
+                None => cron::dynamic_callback(lua, run.key, run.once),
+            };
+            let Some(func) = func else {
+                eprintln!("Failed to retrieve cron callback function");
+                return;
+            };
+            let label = format!("cron job {}", run.name);
+            let name = run.name;
+            let func = debugger::instrument(lua, func);
+            let timeout = run.timeout;
+            let done = run.done;
+            let fut = async move {
+                // Call Lua function with no arguments
+                let call = sandbox::limited(func.call_async::<()>(()));
+                let res = match timeout {
+                    // Dropping the call cancels the coroutine
+                    Some(limit) => tokio::time::timeout(limit, call).await.unwrap_or_else(|_| {
+                        Err(LuaError::RuntimeError(format!(
+                            "timed out after {} s",
+                            limit.as_secs_f64()
+                        )))
+                    }),
+                    None => call.await,
+                };
+                match res {
+                    Ok(()) => {
+                        done.send(Ok(())).ok();
+                    }
+                    Err(e) if exit::exit_code(&e).is_none() => {
+                        debugger::report(format!("Error executing cron job {}", name), &e);
+                        done.send(Err(e.to_string())).ok();
+                    }
+                    _ => {}
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(label, fut)));
+        }
+        EngineRequest::Job(run) => {
+            let Some(func) = handlers.jobs.get(run.index).cloned() else {
+                eprintln!("Failed to retrieve job callback function");
+                return;
+            };
+            let JobRun {
+                id,
+                name,
+                payload,
+                attempt,
+                timeout,
+                done,
+                ..
+            } = *run;
+            let label = format!("job {} #{}", name, id);
+            let func = debugger::instrument(lua, func);
+            let fut = async move {
+                let args = (|| {
+                    let info = lua.create_table()?;
+                    info.set("id", id)?;
+                    info.set("name", name.as_str())?;
+                    info.set("attempt", attempt)?;
+                    Ok::<_, LuaError>((lua.to_value(&payload)?, info))
+                })();
+                let res = match args {
+                    Ok(args) => {
+                        let call = sandbox::limited(func.call_async::<()>(args));
+                        match timeout {
+                            Some(limit) => {
+                                tokio::time::timeout(limit, call).await.unwrap_or_else(|_| {
+                                    Err(LuaError::RuntimeError(format!(
+                                        "timed out after {} s",
+                                        limit.as_secs_f64()
+                                    )))
+                                })
+                            }
+                            None => call.await,
+                        }
+                    }
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(()) => {
+                        done.send(Ok(())).ok();
+                    }
+                    Err(e) if exit::exit_code(&e).is_none() => {
+                        debugger::report(format!("Error executing job {} #{}", name, id), &e);
+                        done.send(Err(e.to_string())).ok();
+                    }
+                    _ => {}
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(label, fut)));
+        }
+        EngineRequest::TelegramUpdate(update) => {
+            let Some(func) = handlers.telegram.clone() else {
+                eprintln!("Failed to retrieve telegram callback function");
+                return;
+            };
+            let func = debugger::instrument(lua, func);
+            let fut = async move {
+                let update_val = lua.to_value(&update).unwrap_or(LuaValue::Nil);
+                match sandbox::limited(func.call_async::<()>(update_val)).await {
+                    Err(e) if exit::exit_code(&e).is_none() => {
+                        debugger::report("Error executing telegram handler".to_string(), &e);
+                    }
+                    _ => {}
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(
+                "telegram update".to_string(),
+                fut,
+            )));
+        }
+        EngineRequest::ProxyAuth(req) => {
+            let func: LuaFunction = match lua.registry_value(&req.callback_key) {
+                Ok(f) => debugger::instrument(lua, f),
+                Err(e) => {
+                    req.response_tx.send(false).ok();
+                    eprintln!("Failed to get proxy auth callback: {}", e);
+                    return;
+                }
+            };
+            let email = req.email;
+            let domain = req.domain;
+            let response_tx = req.response_tx;
+            let label = format!("proxy auth {} for {}", email, domain);
+
+            let fut = async move {
+                let res: LuaResult<LuaValue> =
+                    sandbox::limited(func.call_async((email, domain))).await;
+                match res {
+                    Ok(val) => {
+                        let allowed = match val {
+                            LuaValue::Boolean(b) => b,
+                            _ => false,
+                        };
+                        response_tx.send(allowed).ok();
+                    }
+                    Err(e) => {
+                        if exit::exit_code(&e).is_none() {
+                            debugger::report("Error in proxy auth callback".to_string(), &e);
+                        }
+                        response_tx.send(false).ok();
+                    }
+                }
+            };
+            pending_requests.push(Box::pin(debugger::tracked(label, fut)));
+        }
+        EngineRequest::Eval(req) => {
+            let fut = async move {
+                let reply = match repl::eval(lua, "=admin", &req.code).await {
+                    Ok(values) => Some(Ok(repl::format_values(lua, &values))),
+                    Err(e) if repl::is_incomplete(&e) => None,
+                    Err(e) => match exit::exit_code(&e) {
+                        Some(code) => Some(Ok(format!("Exiting with code {}", code))),
+                        None => Some(Err(e.to_string())),
+                    },
+                };
+                req.response_tx.send(reply).ok();
+            };
+            pending_requests.push(Box::pin(debugger::tracked(
+                "admin console".to_string(),
+                fut,
+            )));
+        }
+    }
+}
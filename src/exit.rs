@@ -0,0 +1,114 @@
+use crate::types::AppState;
+use mlua::prelude::*;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Error raised by `exit()`. `pcall`, `xpcall` and `coroutine.resume` pass it
+/// on, so it always unwinds to the engine.
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "script called exit({})", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// The exit code if `err` was raised by `exit()`.
+pub fn exit_code(err: &LuaError) -> Option<i32> {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<Exit>())
+        .map(|exit| exit.0)
+}
+
+pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
+    let state = app_state.clone();
+    let exit_func = lua.create_function(move |_, code: Option<i32>| {
+        let code = code.unwrap_or(0);
+        let _ = state.lock().unwrap().exit_tx.send(code);
+        Err::<(), _>(LuaError::external(Exit(code)))
+    })?;
+    lua.globals().set("exit", exit_func)?;
+
+    let on_shutdown = lua.create_function(move |lua, func: LuaFunction| {
+        let key = lua.create_registry_value(func)?;
+        app_state.lock().unwrap().shutdown_hooks.push(key);
+        Ok(())
+    })?;
+    lua.globals().set("on_shutdown", on_shutdown)?;
+
+    // Limits of the sandbox stop the callback the same way
+    let unwinds = lua.create_function(|_, value: LuaValue| {
+        Ok(matches!(value, LuaValue::Error(e)
+            if exit_code(&e).is_some() || crate::sandbox::limit_exceeded(&e)))
+    })?;
+    let globals = lua.globals();
+    let coroutine: LuaTable = globals.get("coroutine")?;
+    lua.load(
+        r#"
+        local unwinds, pcall, xpcall, resume, error = ...
+        local function rethrow(ok, ...)
+            if not ok and unwinds((...)) then
+                error((...), 0)
+            end
+            return ok, ...
+        end
+        _G.pcall = function(f, ...)
+            return rethrow(pcall(f, ...))
+        end
+        _G.xpcall = function(f, handler, ...)
+            return rethrow(xpcall(f, function(e)
+                if unwinds(e) then
+                    return e
+                end
+                return handler(e)
+            end, ...))
+        end
+        coroutine.resume = function(co, ...)
+            return rethrow(resume(co, ...))
+        end
+        "#,
+    )
+    .set_name("=exit")
+    .call::<()>((
+        unwinds,
+        globals.get::<LuaFunction>("pcall")?,
+        globals.get::<LuaFunction>("xpcall")?,
+        coroutine.get::<LuaFunction>("resume")?,
+        globals.get::<LuaFunction>("error")?,
+    ))
+}
+
+/// Resolves the `on_shutdown` hooks registered in `state`.
+pub fn shutdown_hooks(lua: &Lua, state: &AppState) -> LuaResult<Vec<LuaFunction>> {
+    state
+        .shutdown_hooks
+        .iter()
+        .map(|key| lua.registry_value(key))
+        .collect()
+}
+
+/// Calls the hooks in the order they were registered, giving them
+/// `LUMEN_EXIT_TIMEOUT` seconds in total.
+pub async fn run_shutdown_hooks(hooks: Vec<LuaFunction>) {
+    if hooks.is_empty() {
+        return;
+    }
+    let run = async {
+        for hook in hooks {
+            if let Err(e) = hook.call_async::<()>(()).await
+                && exit_code(&e).is_none()
+            {
+                eprintln!("Error in shutdown hook: {}", e);
+            }
+        }
+    };
+    if tokio::time::timeout(crate::engine::drain_timeout(), run)
+        .await
+        .is_err()
+    {
+        println!("Shutdown hooks timed out.");
+    }
+}
@@ -19,6 +19,16 @@ impl LuaUserData for FileObject {
         fields.add_field_method_get("mime_type", |_, this| Ok(this.mime_type.clone()));
         fields.add_field_method_get("path", |_, this| Ok(this.path.clone()));
         fields.add_field_method_get("id", |_, this| Ok(this.id.clone()));
+        fields.add_field_method_get("size", |_, this| {
+            if let Some(ref b) = this.blob {
+                return Ok(Some(b.len() as u64));
+            }
+            Ok(this
+                .path
+                .as_ref()
+                .and_then(|p| fs::metadata(p).ok())
+                .map(|m| m.len()))
+        });
         fields.add_field_method_set("id", |_, this, id: Option<String>| {
             this.id = id;
             Ok(())
@@ -32,6 +42,7 @@ impl LuaUserData for FileObject {
         });
 
         methods.add_method_mut("path", |_, this, p: String| {
+            crate::sandbox::check_path(&p, false)?;
             this.path = Some(p);
             this.blob = None;
             if this.mime_type.is_none() {
@@ -53,6 +64,22 @@ impl LuaUserData for FileObject {
             }
         });
 
+        // Writes the content to a local file; uploads are otherwise removed
+        // once the request is finished.
+        methods.add_method("save", |_, this, dest: String| {
+            crate::sandbox::check_path(&dest, true)?;
+            let res = if let Some(ref b) = this.blob {
+                fs::write(&dest, b)
+            } else if let Some(ref p) = this.path {
+                fs::copy(p, &dest).map(|_| ())
+            } else {
+                return Err(LuaError::RuntimeError(
+                    "File object has no local content".into(),
+                ));
+            };
+            res.map_err(|e| LuaError::RuntimeError(format!("Failed to save {}: {}", dest, e)))
+        });
+
         methods.add_method_mut("set_downloader", |lua, this, func: LuaFunction| {
             this.downloader = Some(Arc::new(lua.create_registry_value(func)?));
             Ok(this.clone())
@@ -91,6 +118,12 @@ pub fn detect_mime(name: &str) -> String {
         "json" => "application/json".to_string(),
         "txt" => "text/plain".to_string(),
         "html" => "text/html".to_string(),
+        "csv" => "text/csv".to_string(),
+        "css" => "text/css".to_string(),
+        "js" => "text/javascript".to_string(),
+        "png" => "image/png".to_string(),
+        "jpg" | "jpeg" => "image/jpeg".to_string(),
+        "svg" => "image/svg+xml".to_string(),
         "pdf" => "application/pdf".to_string(),
         "zip" => "application/zip".to_string(),
         _ => "application/octet-stream".to_string(),
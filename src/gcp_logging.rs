@@ -155,7 +155,7 @@ impl GcpWorker {
 }
 
 fn load_credentials() -> Option<GcpCredentials> {
-    let path = if let Ok(env_path) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+    let path = if let Some(env_path) = crate::util::env_var("GOOGLE_APPLICATION_CREDENTIALS") {
         PathBuf::from(env_path)
     } else {
         let mut p = dirs::home_dir()?;
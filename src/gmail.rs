@@ -151,6 +151,7 @@ impl LuaUserData for Mailbox {
                 if let Some(att_table) = attachments_lua {
                     for pair in att_table.pairs::<String, String>() {
                         let (name, path) = pair?;
+                        crate::sandbox::check_path(&path, false)?;
                         let data =
                             fs::read(path).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
                         attachments.push((name, data));
@@ -219,6 +220,7 @@ impl LuaUserData for Mailbox {
                 if let Some(att_table) = attachments_lua {
                     for pair in att_table.pairs::<String, String>() {
                         let (name, path) = pair?;
+                        crate::sandbox::check_path(&path, false)?;
                         let data =
                             fs::read(path).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
                         attachments.push((name, data));
@@ -618,7 +620,7 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
                     query.append_pair("scope", "openid email https://www.googleapis.com/auth/gmail.modify https://www.googleapis.com/auth/gmail.compose https://www.googleapis.com/auth/drive");
                     query.append_pair("access_type", "offline");
                     query.append_pair("prompt", "consent");
-                    query.append_pair("state", &email);
+                    query.append_pair("state", &crate::oauth::begin_google_link());
                 }
 
                 res.set("status", "unauthorized")?;
@@ -686,8 +688,8 @@ pub async fn handle_callback(
 
 pub async fn init_gmail_state() -> Result<Arc<GmailState>, Box<dyn std::error::Error>> {
     let secrets_path = Path::new(".secrets");
-    let mut google_client_secret = std::env::var("GOOGLE_CLIENT_SECRET").ok();
-    let mut attachment_dir_str = std::env::var("GMAIL_ATTACHMENT_DIR").ok();
+    let mut google_client_secret = crate::util::env_var("GOOGLE_CLIENT_SECRET");
+    let mut attachment_dir_str = crate::util::env_var("GMAIL_ATTACHMENT_DIR");
 
     if secrets_path.exists() {
         let content = fs::read_to_string(secrets_path)?;
@@ -745,7 +747,7 @@ pub async fn init_gmail_state() -> Result<Arc<GmailState>, Box<dyn std::error::E
 
     let attachment_dir = attachment_dir_str
         .map(|s| s.to_string())
-        .or_else(|| std::env::var("GMAIL_ATTACHMENT_DIR").ok())
+        .or_else(|| crate::util::env_var("GMAIL_ATTACHMENT_DIR"))
         .unwrap_or_else(|| "attachments".to_string());
 
     let attachment_dir = PathBuf::from(attachment_dir);
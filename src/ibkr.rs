@@ -6,7 +6,6 @@ use rsa::RsaPrivateKey;
 use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::env;
 use std::fs;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -200,10 +199,10 @@ impl IbkrState {
 }
 
 pub fn register(lua: &Lua) -> LuaResult<()> {
-    let client_id = match env::var("IBKR_CLIENT_ID") {
-        Ok(val) => val,
-        Err(_) => {
-            println!(
+    let client_id = match crate::util::env_var("IBKR_CLIENT_ID") {
+        Some(val) => val,
+        None => {
+            eprintln!(
                 "Warning: IBKR_CLIENT_ID environment variable is missing. IBKR support disabled."
             );
             return Ok(());
@@ -214,7 +213,7 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
         .map(|h| h.join(".secrets"))
         .ok_or_else(|| LuaError::RuntimeError("Could not find home directory".to_string()))?;
 
-    let mut private_key_pem = env::var("IBKR_PRIVATE_KEY").ok();
+    let mut private_key_pem = crate::util::env_var("IBKR_PRIVATE_KEY");
 
     if private_key_pem.is_none() && secrets_path.exists() {
         let content = fs::read_to_string(&secrets_path).unwrap_or_default();
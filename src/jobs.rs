@@ -0,0 +1,540 @@
+use crate::session::db_path;
+use crate::types::{AppState, EngineRequest, JobDefInfo, JobRun};
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde_json::Value as JsonValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Notify, oneshot};
+
+/// Wakes the dispatcher when a job is enqueued or an attempt is over.
+static WAKE: Notify = Notify::const_new();
+
+/// Set once the attempts that a previous process left running are pending
+/// again.
+static RECOVERED: AtomicBool = AtomicBool::new(false);
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest wait before a retry, in seconds.
+const MAX_BACKOFF: f64 = 24.0 * 3600.0;
+
+fn open() -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path())?;
+    conn.busy_timeout(crate::kv::BUSY_TIMEOUT)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            state TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            max_attempts INTEGER NOT NULL,
+            backoff REAL NOT NULL,
+            run_at REAL NOT NULL,
+            created REAL NOT NULL,
+            failed REAL,
+            error TEXT
+        );
+        CREATE INDEX IF NOT EXISTS jobs_due ON jobs (name, state, run_at);",
+    )?;
+    Ok(conn)
+}
+
+async fn with_db<T: Send + 'static>(
+    f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, String> {
+    tokio::task::spawn_blocking(move || {
+        let mut conn = open().map_err(|e| e.to_string())?;
+        f(&mut conn).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn seconds(secs: f64, what: &str) -> LuaResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| LuaError::RuntimeError(format!("{} must be a number of seconds >= 0", what)))
+}
+
+/// An attempt taken from the queue.
+struct Claimed {
+    id: i64,
+    name: String,
+    payload: String,
+    attempt: u32,
+}
+
+/// Marks the due jobs that the concurrency `limits` allow as running, and
+/// returns them with the time the next pending job is due.
+fn claim(
+    conn: &mut Connection,
+    limits: &[(String, usize)],
+) -> rusqlite::Result<(Vec<Claimed>, Option<f64>)> {
+    let now = now();
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut claimed = Vec::new();
+    let mut next: Option<f64> = None;
+    {
+        let mut running =
+            tx.prepare("SELECT COUNT(*) FROM jobs WHERE name = ? AND state = 'running'")?;
+        let mut due = tx.prepare(
+            "SELECT id, payload, attempts FROM jobs
+             WHERE name = ? AND state = 'pending' AND run_at <= ?
+             ORDER BY run_at, id LIMIT ?",
+        )?;
+        let mut start =
+            tx.prepare("UPDATE jobs SET state = 'running', attempts = attempts + 1 WHERE id = ?")?;
+        let mut later = tx.prepare(
+            "SELECT MIN(run_at) FROM jobs WHERE name = ? AND state = 'pending' AND run_at > ?",
+        )?;
+        for (name, limit) in limits {
+            let busy: i64 = running.query_row(params![name], |row| row.get(0))?;
+            let free = (*limit as i64 - busy).max(0);
+            if free > 0 {
+                let rows = due
+                    .query_map(params![name, now, free], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get::<_, u32>(2)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<(i64, String, u32)>>>()?;
+                for (id, payload, attempts) in rows {
+                    start.execute(params![id])?;
+                    claimed.push(Claimed {
+                        id,
+                        name: name.clone(),
+                        payload,
+                        attempt: attempts + 1,
+                    });
+                }
+            }
+            let at: Option<f64> = later.query_row(params![name, now], |row| row.get(0))?;
+            next = match (next, at) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+    }
+    tx.commit()?;
+    Ok((claimed, next))
+}
+
+/// Records how an attempt at job `id` went: a success removes the job, an
+/// error retries it after its backoff, doubled for every attempt, or moves
+/// it to the dead letters once it is out of attempts. A cancelled attempt
+/// doesn't count. Returns whether the job is dead.
+fn finish(
+    conn: &Connection,
+    id: i64,
+    result: Option<Result<(), String>>,
+) -> rusqlite::Result<bool> {
+    let error = match result {
+        Some(Ok(())) => {
+            conn.execute("DELETE FROM jobs WHERE id = ?", params![id])?;
+            return Ok(false);
+        }
+        Some(Err(e)) => e,
+        None => {
+            conn.execute(
+                "UPDATE jobs SET state = 'pending', attempts = attempts - 1
+                 WHERE id = ? AND state = 'running'",
+                params![id],
+            )?;
+            return Ok(false);
+        }
+    };
+    let job: Option<(u32, u32, f64)> = conn
+        .query_row(
+            "SELECT attempts, max_attempts, backoff FROM jobs WHERE id = ?",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let Some((attempts, max_attempts, backoff)) = job else {
+        return Ok(false);
+    };
+    let now = now();
+    if attempts >= max_attempts {
+        conn.execute(
+            "UPDATE jobs SET state = 'dead', failed = ?, error = ? WHERE id = ?",
+            params![now, error, id],
+        )?;
+        return Ok(true);
+    }
+    let delay = (backoff * 2f64.powi(attempts as i32 - 1)).min(MAX_BACKOFF);
+    conn.execute(
+        "UPDATE jobs SET state = 'pending', run_at = ?, failed = ?, error = ? WHERE id = ?",
+        params![now + delay, now, error, id],
+    )?;
+    Ok(false)
+}
+
+/// What the dispatcher needs to know about a definition.
+#[derive(Clone)]
+struct Definition {
+    index: usize,
+    name: String,
+    concurrency: usize,
+    timeout: Option<Duration>,
+}
+
+/// Hands a claimed attempt to the engine, and records its outcome once the
+/// engine is done with it, even if the dispatcher was stopped by a reload by
+/// then.
+async fn send(tx: &Sender<EngineRequest>, def: &Definition, job: Claimed) {
+    let (done, rx) = oneshot::channel();
+    let id = job.id;
+    let name = job.name;
+    let run = JobRun {
+        index: def.index,
+        id,
+        name: name.clone(),
+        payload: serde_json::from_str(&job.payload).unwrap_or(JsonValue::Null),
+        attempt: job.attempt,
+        timeout: def.timeout,
+        done,
+    };
+    // If this fails `rx` sees the attempt as cancelled
+    let _ = tx.send(EngineRequest::Job(Box::new(run))).await;
+    tokio::spawn(async move {
+        let result = rx.await.ok();
+        match with_db(move |conn| finish(conn, id, result)).await {
+            Ok(true) => println!(
+                "Job {} #{} failed {} times, moved to the dead letters",
+                name, id, job.attempt
+            ),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to record job {} #{}: {}", name, id, e),
+        }
+        WAKE.notify_one();
+    });
+}
+
+/// Stops the dispatcher when dropped. It is never interrupted between
+/// claiming attempts and handing them to the engine, so every claimed
+/// attempt gets its outcome recorded.
+pub struct JobsGuard {
+    _stop: oneshot::Sender<()>,
+}
+
+/// Runs the queued jobs of the definitions in `app_state` until the guard is
+/// dropped, as many at once as each definition allows. `None` if nothing is
+/// defined.
+///
+/// Jobs live in `server.db`. Attempts that a reload interrupts are recorded
+/// by the previous version when it is done with them; those of a process
+/// that stopped are run again on the next start.
+pub fn start(app_state: Arc<Mutex<AppState>>, tx: Sender<EngineRequest>) -> Option<JobsGuard> {
+    let defs: Vec<Definition> = app_state
+        .lock()
+        .unwrap()
+        .job_defs
+        .iter()
+        .map(|def| Definition {
+            index: def.callback_id,
+            name: def.name.clone(),
+            concurrency: def.concurrency,
+            timeout: def.timeout,
+        })
+        .collect();
+    if defs.is_empty() {
+        return None;
+    }
+
+    let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        if !RECOVERED.swap(true, Ordering::Relaxed) {
+            let res = with_db(|conn| {
+                conn.execute(
+                    "UPDATE jobs SET state = 'pending', attempts = attempts - 1
+                     WHERE state = 'running'",
+                    [],
+                )
+            })
+            .await;
+            match res {
+                Ok(0) => {}
+                Ok(n) => println!("Requeued {} job(s) interrupted by the last stop", n),
+                Err(e) => eprintln!("Failed to requeue interrupted jobs: {}", e),
+            }
+        }
+
+        let limits: Vec<(String, usize)> = defs
+            .iter()
+            .map(|def| (def.name.clone(), def.concurrency))
+            .collect();
+        loop {
+            let limits = limits.clone();
+            let next = match with_db(move |conn| claim(conn, &limits)).await {
+                Ok((claimed, next)) => {
+                    for job in claimed {
+                        if let Some(def) = defs.iter().find(|def| def.name == job.name) {
+                            send(&tx, def, job).await;
+                        }
+                    }
+                    next
+                }
+                Err(e) => {
+                    eprintln!("Failed to read the job queue: {}", e);
+                    Some(now() + 5.0)
+                }
+            };
+            let wait = next.map(|at| Duration::from_secs_f64((at - now()).max(0.0)));
+
+            tokio::select! {
+                biased;
+                _ = &mut stop_rx => return,
+                _ = WAKE.notified() => {}
+                _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+            }
+        }
+    });
+    Some(JobsGuard { _stop: stop_tx })
+}
+
+/// Options of `jobs.define` and `jobs.enqueue`.
+fn retry_options(opts: &LuaTable, max_attempts: &mut u32, backoff: &mut Duration) -> LuaResult<()> {
+    if let Some(n) = opts.get::<Option<u32>>("max_attempts")? {
+        if n == 0 {
+            return Err(LuaError::RuntimeError(
+                "max_attempts must be at least 1".into(),
+            ));
+        }
+        *max_attempts = n;
+    }
+    if let Some(secs) = opts.get::<Option<f64>>("backoff")? {
+        *backoff = seconds(secs, "backoff")?;
+    }
+    Ok(())
+}
+
+fn dead_letters(conn: &mut Connection, name: Option<String>) -> rusqlite::Result<Vec<DeadLetter>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, payload, attempts, failed, error FROM jobs
+         WHERE state = 'dead' AND (?1 IS NULL OR name = ?1) ORDER BY failed DESC, id DESC",
+    )?;
+    let rows = stmt.query_map(params![name], |row| {
+        Ok(DeadLetter {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            payload: row.get(2)?,
+            attempts: row.get(3)?,
+            failed: row.get(4)?,
+            error: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+struct DeadLetter {
+    id: i64,
+    name: String,
+    payload: String,
+    attempts: u32,
+    failed: Option<f64>,
+    error: Option<String>,
+}
+
+/// Registers `jobs`, a queue of background jobs kept in `server.db`.
+pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
+    let jobs = lua.create_table()?;
+
+    let define_state = app_state.clone();
+    jobs.set(
+        "define",
+        lua.create_function(
+            move |lua, (name, func, opts): (String, LuaFunction, Option<LuaTable>)| {
+                let mut state = define_state.lock().unwrap();
+                if state.engine_tx.is_some() {
+                    return Err(LuaError::RuntimeError(
+                        "jobs.define must be called while the script loads".into(),
+                    ));
+                }
+                if state.job_defs.iter().any(|def| def.name == name) {
+                    return Err(LuaError::RuntimeError(format!(
+                        "A job named '{}' is already defined",
+                        name
+                    )));
+                }
+                let mut def = JobDefInfo {
+                    name,
+                    callback_id: state.job_defs.len(),
+                    callback_key: lua.create_registry_value(func)?,
+                    concurrency: 1,
+                    max_attempts: DEFAULT_MAX_ATTEMPTS,
+                    backoff: DEFAULT_BACKOFF,
+                    timeout: None,
+                };
+                if let Some(opts) = opts {
+                    if let Some(n) = opts.get::<Option<usize>>("concurrency")? {
+                        if n == 0 {
+                            return Err(LuaError::RuntimeError(
+                                "concurrency must be at least 1".into(),
+                            ));
+                        }
+                        def.concurrency = n;
+                    }
+                    retry_options(&opts, &mut def.max_attempts, &mut def.backoff)?;
+                    if let Some(secs) = opts.get::<Option<f64>>("timeout")? {
+                        def.timeout = Some(seconds(secs, "job timeout")?);
+                    }
+                }
+                state.job_defs.push(def);
+                Ok(())
+            },
+        )?,
+    )?;
+
+    jobs.set(
+        "enqueue",
+        lua.create_async_function(
+            move |lua, (name, payload, opts): (String, LuaValue, Option<LuaTable>)| {
+                let app_state = app_state.clone();
+                async move {
+                    let (mut max_attempts, mut backoff) = {
+                        let state = app_state.lock().unwrap();
+                        let def = state
+                            .job_defs
+                            .iter()
+                            .find(|def| def.name == name)
+                            .ok_or_else(|| {
+                                LuaError::RuntimeError(format!("No job named '{}' is defined", name))
+                            })?;
+                        (def.max_attempts, def.backoff)
+                    };
+                    let mut delay = Duration::ZERO;
+                    if let Some(opts) = &opts {
+                        retry_options(opts, &mut max_attempts, &mut backoff)?;
+                        if let Some(secs) = opts.get::<Option<f64>>("delay")? {
+                            delay = seconds(secs, "delay")?;
+                        }
+                    }
+                    let payload: JsonValue = lua.from_value(payload)?;
+                    let payload = payload.to_string();
+                    let now = now();
+                    let id = with_db(move |conn| {
+                        conn.execute(
+                            "INSERT INTO jobs (name, payload, max_attempts, backoff, run_at, created)
+                             VALUES (?, ?, ?, ?, ?, ?)",
+                            params![
+                                name,
+                                payload,
+                                max_attempts,
+                                backoff.as_secs_f64(),
+                                now + delay.as_secs_f64(),
+                                now
+                            ],
+                        )?;
+                        Ok(conn.last_insert_rowid())
+                    })
+                    .await
+                    .map_err(LuaError::RuntimeError)?;
+                    WAKE.notify_one();
+                    Ok(id)
+                }
+            },
+        )?,
+    )?;
+
+    // Dead letters: jobs that ran out of attempts
+    jobs.set(
+        "dead",
+        lua.create_async_function(|lua, name: Option<String>| async move {
+            let letters = with_db(move |conn| dead_letters(conn, name))
+                .await
+                .map_err(LuaError::RuntimeError)?;
+            let result = lua.create_table()?;
+            for letter in letters {
+                let entry = lua.create_table()?;
+                entry.set("id", letter.id)?;
+                entry.set("name", letter.name)?;
+                let payload: JsonValue =
+                    serde_json::from_str(&letter.payload).unwrap_or(JsonValue::Null);
+                entry.set("payload", lua.to_value(&payload)?)?;
+                entry.set("attempts", letter.attempts)?;
+                entry.set("failed", letter.failed)?;
+                entry.set("error", letter.error)?;
+                result.push(entry)?;
+            }
+            Ok(result)
+        })?,
+    )?;
+    jobs.set(
+        "retry",
+        lua.create_async_function(|_, id: i64| async move {
+            let changed = with_db(move |conn| {
+                conn.execute(
+                    "UPDATE jobs SET state = 'pending', attempts = 0, run_at = ?
+                     WHERE id = ? AND state = 'dead'",
+                    params![now(), id],
+                )
+            })
+            .await
+            .map_err(LuaError::RuntimeError)?;
+            WAKE.notify_one();
+            Ok(changed > 0)
+        })?,
+    )?;
+    jobs.set(
+        "discard",
+        lua.create_async_function(|_, id: i64| async move {
+            let changed = with_db(move |conn| {
+                conn.execute(
+                    "DELETE FROM jobs WHERE id = ? AND state != 'running'",
+                    params![id],
+                )
+            })
+            .await
+            .map_err(LuaError::RuntimeError)?;
+            Ok(changed > 0)
+        })?,
+    )?;
+    jobs.set(
+        "stats",
+        lua.create_async_function(|lua, ()| async move {
+            let counts = with_db(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT name, state, COUNT(*) FROM jobs GROUP BY name, state")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await
+            .map_err(LuaError::RuntimeError)?;
+            let result = lua.create_table()?;
+            for (name, state, count) in counts {
+                let entry = match result.get::<Option<LuaTable>>(name.as_str())? {
+                    Some(entry) => entry,
+                    None => {
+                        let entry = lua.create_table()?;
+                        entry.set("pending", 0)?;
+                        entry.set("running", 0)?;
+                        entry.set("dead", 0)?;
+                        result.set(name.as_str(), &entry)?;
+                        entry
+                    }
+                };
+                entry.set(state, count)?;
+            }
+            Ok(result)
+        })?,
+    )?;
+
+    lua.globals().set("jobs", jobs)?;
+    Ok(())
+}
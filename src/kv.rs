@@ -0,0 +1,125 @@
+use crate::session::db_path;
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+/// Workers write concurrently, so wait for the lock instead of failing.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn open() -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path())?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS kv (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+    )?;
+    Ok(conn)
+}
+
+async fn with_db<T: Send + 'static>(
+    f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> LuaResult<T> {
+    tokio::task::spawn_blocking(move || {
+        let mut conn = open().map_err(|e| e.to_string())?;
+        f(&mut conn).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| LuaError::RuntimeError(e.to_string()))?
+    .map_err(LuaError::RuntimeError)
+}
+
+fn get(conn: &Connection, key: &str) -> rusqlite::Result<Option<JsonValue>> {
+    let value: Option<String> = conn
+        .query_row("SELECT value FROM kv WHERE key = ?", params![key], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+/// Registers `kv`, a key-value store in `server.db`. Unlike `persist` it is
+/// kept across restarts, and `kv.incr` is atomic across workers.
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let kv = lua.create_table()?;
+    kv.set(
+        "get",
+        lua.create_async_function(|lua, key: String| async move {
+            match with_db(move |conn| get(conn, &key)).await? {
+                Some(v) => lua.to_value(&v),
+                None => Ok(LuaValue::Nil),
+            }
+        })?,
+    )?;
+    kv.set(
+        "set",
+        lua.create_async_function(|lua, (key, value): (String, LuaValue)| async move {
+            if value.is_nil() {
+                return with_db(move |conn| {
+                    conn.execute("DELETE FROM kv WHERE key = ?", params![key])
+                        .map(|_| ())
+                })
+                .await;
+            }
+            let json_val: JsonValue = lua.from_value(value)?;
+            let text = json_val.to_string();
+            with_db(move |conn| {
+                conn.execute(
+                    "INSERT INTO kv (key, value) VALUES (?, ?)
+                     ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    params![key, text],
+                )
+                .map(|_| ())
+            })
+            .await
+        })?,
+    )?;
+    kv.set(
+        "delete",
+        lua.create_async_function(|_, key: String| async move {
+            with_db(move |conn| {
+                conn.execute("DELETE FROM kv WHERE key = ?", params![key])
+                    .map(|n| n > 0)
+            })
+            .await
+        })?,
+    )?;
+    kv.set(
+        "incr",
+        lua.create_async_function(|lua, (key, by): (String, Option<f64>)| async move {
+            let by = by.unwrap_or(1.0);
+            let value = with_db(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let next = match get(&tx, &key)? {
+                    None => by,
+                    Some(JsonValue::Number(n)) => n.as_f64().unwrap_or(0.0) + by,
+                    Some(_) => return Ok(None),
+                };
+                // Keep counters integers
+                let next = match next.fract() == 0.0 && next.abs() < 9007199254740992.0 {
+                    true => JsonValue::from(next as i64),
+                    false => JsonValue::from(next),
+                };
+                tx.execute(
+                    "INSERT INTO kv (key, value) VALUES (?, ?)
+                     ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                    params![key, next.to_string()],
+                )?;
+                tx.commit()?;
+                Ok(Some(next))
+            })
+            .await?;
+            match value {
+                Some(v) => lua.to_value(&v),
+                None => Err(LuaError::RuntimeError(
+                    "kv.incr: value is not a number".into(),
+                )),
+            }
+        })?,
+    )?;
+    lua.globals().set("kv", kv)?;
+    Ok(())
+}
@@ -6,15 +6,19 @@ pub struct SimpleLogger {
 }
 
 impl SimpleLogger {
-    pub fn init() {
+    /// Uses `level` if given, otherwise `RUST_LOG`, otherwise info.
+    pub fn init(level: Option<log::LevelFilter>) {
         let gcp_client = GcpLoggerClient::new();
         if gcp_client.is_none() {
-            println!("GCP Logging disabled: credentials not found.");
+            eprintln!("GCP Logging disabled: credentials not found.");
         }
 
-        let level_filter = std::env::var("RUST_LOG")
-            .ok()
-            .and_then(|s| s.parse::<log::LevelFilter>().ok())
+        let level_filter = level
+            .or_else(|| {
+                std::env::var("RUST_LOG")
+                    .ok()
+                    .and_then(|s| s.parse::<log::LevelFilter>().ok())
+            })
             .unwrap_or(log::LevelFilter::Info);
 
         let logger = SimpleLogger { gcp_client };
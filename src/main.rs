@@ -1,34 +1,59 @@
+mod admin;
+mod check;
+mod cli;
 mod cron;
+mod debugger;
 mod drive;
+mod engine;
+mod exit;
 mod file_obj;
 mod gcp_logging;
 mod gmail;
 mod ibkr;
+mod jobs;
+mod kv;
 mod logger;
+mod oauth;
+mod persist;
 mod re;
+mod repl;
+mod response;
 mod reverse_proxy;
+mod sandbox;
+mod session;
 mod sql;
 mod telegram;
+mod test;
 mod types;
+mod upload;
 mod util;
+mod watcher;
 mod web_client;
 mod web_server;
+mod websocket;
+mod worker;
 
+use crate::cli::{Cli, Command};
 use crate::types::{AppState, EngineRequest};
 use futures::StreamExt;
 use futures::stream::FuturesUnordered;
 use mlua::prelude::*;
-use mlua::serde::LuaSerdeExt;
 use std::fs;
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use tokio::signal::unix::{SignalKind, signal};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
 fn register_modules(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
     sql::register(lua)?;
     util::register(lua)?;
+    exit::register(lua, app_state.clone())?;
+    debugger::register(lua)?;
+    persist::register(lua)?;
+    kv::register(lua)?;
     file_obj::register(lua)?;
     re::register(lua)?;
     // Help with finding libraries
@@ -38,10 +63,12 @@ fn register_modules(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()>
     web_client::register(lua)?;
     web_server::register(lua, app_state.clone())?;
     cron::register(lua, app_state.clone())?;
+    jobs::register(lua, app_state.clone())?;
     telegram::register(lua, app_state.clone())?;
     gmail::register(lua, app_state.clone())?;
     drive::register(lua, app_state.clone())?;
     reverse_proxy::register(lua, app_state.clone())?;
+    oauth::register(lua, app_state.clone())?;
 
     // Help with random strings
     let uuid_func = lua.create_function(|_, ()| Ok(Uuid::new_v4().to_string()))?;
@@ -69,54 +96,228 @@ fn register_modules(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()>
     lua.globals().set("LUMEN_VERSION", env!("LUMEN_VERSION"))?;
     lua.globals().set("LUMEN_ENV", env!("LUMEN_BUILD_ENV"))?;
 
-    // Register exit function
-    let app_state_exit = app_state.clone();
-    let exit_func = lua.create_function(move |_, code: Option<i32>| {
-        let code = code.unwrap_or(0);
-        let tx = {
-            let state = app_state_exit.lock().unwrap();
-            state.engine_tx.clone()
-        };
-        if let Some(tx) = tx {
-            let _ = tx.try_send(EngineRequest::Exit(code));
-        } else {
-            std::process::exit(code);
+    // Last, so that it sees everything the script can reach
+    sandbox::register(lua)?;
+    Ok(())
+}
+
+/// Loads `.secrets` from the same directory as the script.
+fn load_script_secrets(abs_path: &Path) {
+    if let Some(parent) = abs_path.parent() {
+        let mut secrets_path = parent.to_path_buf();
+        secrets_path.push(".secrets");
+        if secrets_path.exists() {
+            util::load_secrets_from_path(&secrets_path);
         }
-        Err::<(), _>(LuaError::RuntimeError(format!("__LUMEN_EXIT__:{}", code)))
-    })?;
-    lua.globals().set("exit", exit_func)?;
+    }
+}
 
-    Ok(())
+type Engines = FuturesUnordered<Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Background services of the running version of the script.
+#[derive(Default)]
+struct Services {
+    server: Option<web_server::ServerGuard>,
+    /// Admin console, which outlives reloads.
+    admin: Option<admin::AdminGuard>,
+    cron: Option<tokio::task::JoinHandle<()>>,
+    jobs: Option<jobs::JobsGuard>,
+    telegram: Option<telegram::TelegramBotGuard>,
+    /// Stop the engines of the running version, which are retired if these
+    /// are dropped.
+    stop: Vec<oneshot::Sender<engine::Stop>>,
+}
+
+impl Services {
+    fn stop(&mut self) {
+        self.server = None;
+        self.admin = None;
+        if let Some(handle) = self.cron.take() {
+            handle.abort();
+        }
+        self.jobs = None;
+        self.telegram = None;
+        self.stop.clear();
+    }
+}
+
+enum Wake<T> {
+    Done(T),
+    Reload,
+    Exit(i32),
+}
+
+/// Awaits `fut` while the engines keep serving, unless a reload or an exit
+/// comes first.
+async fn serve_until<T>(
+    fut: impl Future<Output = T>,
+    engines: &mut Engines,
+    reload_rx: &mut mpsc::Receiver<()>,
+    exit_rx: &mut mpsc::UnboundedReceiver<i32>,
+) -> Wake<T> {
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            // An exit() that ends `fut` is reported as its result
+            biased;
+            out = &mut fut => return Wake::Done(out),
+            Some(()) = reload_rx.recv() => return Wake::Reload,
+            Some(code) = exit_rx.recv() => return Wake::Exit(code),
+            Some(()) = engines.next() => {}
+        }
+    }
+}
+
+/// Serves until the next reload signal, or exits the process.
+async fn wait_for_reload(
+    services: &mut Services,
+    engines: &mut Engines,
+    reload_rx: &mut mpsc::Receiver<()>,
+    exit_rx: &mut mpsc::UnboundedReceiver<i32>,
+) {
+    match serve_until(std::future::pending::<()>(), engines, reload_rx, exit_rx).await {
+        Wake::Exit(code) => shutdown(services, engines, code).await,
+        _ => println!("Reload signal received."),
+    }
+}
+
+/// Stops the services, lets every engine drain and run its `on_shutdown`
+/// hooks, and exits.
+async fn shutdown(services: &mut Services, engines: &mut Engines, code: i32) -> ! {
+    println!("Exit requested with code {}", code);
+    for stop in services.stop.drain(..) {
+        let _ = stop.send(engine::Stop::Exit);
+    }
+    let server = services.server.take();
+    services.stop();
+    let close = async {
+        if let Some(server) = server {
+            server.close(engine::drain_timeout()).await;
+        }
+    };
+    let drain = async { while engines.next().await.is_some() {} };
+    futures::join!(close, drain);
+    std::process::exit(code);
+}
+
+/// Creates a Lua state with every module registered, as used by the main
+/// version of the script and the one-off commands.
+fn new_state(
+    gmail_state: Option<Arc<gmail::GmailState>>,
+    exit_tx: mpsc::UnboundedSender<i32>,
+) -> LuaResult<(Lua, Arc<Mutex<AppState>>)> {
+    let lua = Lua::new();
+    let app_state = Arc::new(Mutex::new(AppState::new(gmail_state, exit_tx)));
+    register_modules(&lua, app_state.clone())?;
+    lua.globals().set("LUMEN_WORKER", 0)?;
+    Ok((lua, app_state))
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> LuaResult<()> {
-    util::load_secrets();
-    logger::SimpleLogger::init();
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    match cli.command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Version => {
+            println!(
+                "lumen {} ({})",
+                env!("LUMEN_VERSION"),
+                env!("LUMEN_BUILD_ENV")
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    let args: Vec<String> = std::env::args().collect();
-    let path_str = if args.len() > 1 {
-        &args[1]
-    } else {
-        "examples/example.lua"
+    if let Some(dir) = &cli.cwd {
+        std::env::set_current_dir(dir).map_err(|e| {
+            LuaError::RuntimeError(format!("Failed to change to {}: {}", dir.display(), e))
+        })?;
+    }
+    if let Some(path) = &cli.sandbox {
+        sandbox::load(path).map_err(LuaError::RuntimeError)?;
+    }
+    // Loaded first so that its values win over the .secrets files
+    let env_file = match &cli.env_file {
+        Some(path) => {
+            let path = fs::canonicalize(path).map_err(|e| {
+                LuaError::RuntimeError(format!("Env file {}: {}", path.display(), e))
+            })?;
+            util::load_secrets_from_path(&path);
+            Some(path)
+        }
+        None => None,
+    };
+    util::load_secrets();
+    logger::SimpleLogger::init(cli.log_level);
+
+    let path_str = match cli.command {
+        Command::Run(path) => path,
+        Command::Test(options) => std::process::exit(test::run(options).await),
+        Command::Attach(target) => {
+            let Some(target) = target.or_else(|| util::env_var("LUMEN_ADMIN")) else {
+                eprintln!("attach needs a socket path or address, or LUMEN_ADMIN");
+                std::process::exit(2);
+            };
+            std::process::exit(admin::attach(&target).await);
+        }
+        command => {
+            let (exit_tx, _) = mpsc::unbounded_channel();
+            let gmail_state = gmail::init_gmail_state().await.ok();
+            let (lua, app_state) = new_state(gmail_state, exit_tx)?;
+            let code = match command {
+                Command::Check(path) => check::run(&lua, &app_state, &path).await,
+                Command::Eval(code) => repl::eval_once(&lua, &code).await,
+                _ => repl::run(&lua).await,
+            };
+            let hooks = exit::shutdown_hooks(&lua, &app_state.lock().unwrap());
+            exit::run_shutdown_hooks(hooks.unwrap_or_default()).await;
+            std::process::exit(code);
+        }
     };
+    run(&path_str, env_file).await
+}
+
+/// `lumen run`: serves the script, reloading it on changes, until it exits.
+async fn run(path_str: &str, env_file: Option<PathBuf>) -> LuaResult<()> {
     let path = Path::new(path_str);
     let abs_path = fs::canonicalize(path).map_err(|e| {
         LuaError::RuntimeError(format!("Failed to canonicalize path {}: {}", path_str, e))
     })?;
 
-    // Load secrets from the same directory as the script
-    if let Some(parent) = abs_path.parent() {
-        let mut secrets_path = parent.to_path_buf();
-        secrets_path.push(".secrets");
-        if secrets_path.exists() {
-            util::load_secrets_from_path(&secrets_path);
-        }
-    }
-
-    println!("Watching file: {:?}", abs_path);
+    load_script_secrets(&abs_path);
 
     let (tx, mut rx) = mpsc::channel(1);
+    let (exit_tx, mut exit_rx) = mpsc::unbounded_channel::<i32>();
+
+    // Reload when the script, a required module or .secrets changes
+    let file_watcher = match watcher::FileWatcher::new(tx.clone()) {
+        Ok(w) => {
+            println!("Watching file: {:?}", abs_path);
+            w.watch(&abs_path);
+            w.watch(Path::new(".secrets"));
+            if let Some(env_file) = &env_file {
+                w.watch(env_file);
+            }
+            if let Some(parent) = abs_path.parent() {
+                w.watch(&parent.join(".secrets"));
+            }
+            Some(w)
+        }
+        Err(e) => {
+            eprintln!("Warning: file watching disabled: {}", e);
+            None
+        }
+    };
 
     // Setup SIGHUP signal handler for reload
     let mut sighup = signal(SignalKind::hangup())
@@ -131,7 +332,27 @@ async fn main() -> LuaResult<()> {
         }
     });
 
-    let lua = Lua::new();
+    // Shut down gracefully on SIGTERM or Ctrl-C, at once on the second one
+    let mut sigterm = signal(SignalKind::terminate())
+        .map_err(|e| LuaError::RuntimeError(format!("Failed to setup SIGTERM handler: {}", e)))?;
+    let mut sigint = signal(SignalKind::interrupt())
+        .map_err(|e| LuaError::RuntimeError(format!("Failed to setup SIGINT handler: {}", e)))?;
+    let exit_tx_signal = exit_tx.clone();
+    tokio::spawn(async move {
+        let mut received = false;
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = sigint.recv() => {}
+            }
+            if std::mem::replace(&mut received, true) {
+                std::process::exit(130);
+            }
+            println!("Termination signal received, shutting down...");
+            let _ = exit_tx_signal.send(0);
+        }
+    });
+
     let gmail_state = match gmail::init_gmail_state().await {
         Ok(state) => Some(state),
         Err(e) => {
@@ -161,336 +382,240 @@ async fn main() -> LuaResult<()> {
         }
     }
 
-    let app_state = Arc::new(Mutex::new(AppState {
-        routes: Vec::new(),
-        static_routes: Vec::new(),
-        cron_jobs: Vec::new(),
-        reverse_proxies: Vec::new(),
-        telegram_handler: None,
-        config: None,
-        gmail_state: gmail_state.clone(),
-        drive_state: gmail_state,
-        engine_tx: None,
-    }));
-    register_modules(&lua, app_state.clone())?;
+    let mut services = Services::default();
+    let mut engines: Engines = FuturesUnordered::new();
+    let admin_engine = admin::CurrentEngine::default();
+    if let Some(target) = util::env_var("LUMEN_ADMIN") {
+        services.admin = admin::start(&target, admin_engine.clone()).await;
+    }
 
+    let mut first_run = true;
     loop {
         // Drain pending reload signals
         while rx.try_recv().is_ok() {}
 
-        {
-            let mut state = app_state.lock().unwrap();
-            state.routes.clear();
-            state.static_routes.clear();
-            state.cron_jobs.clear();
-            state.reverse_proxies.clear();
-            state.telegram_handler = None;
-            state.config = None;
-            state.engine_tx = None;
+        if !std::mem::take(&mut first_run) {
+            if let Some(env_file) = &env_file {
+                util::load_secrets_from_path(env_file);
+            }
+            util::load_secrets();
+            load_script_secrets(&abs_path);
+        }
+
+        // Every version runs in a fresh Lua state, so nothing but `persist`
+        // leaks from the previous one, which keeps serving until this one
+        // has loaded successfully.
+        let (lua, app_state) = new_state(gmail_state.clone(), exit_tx.clone())?;
+        if let Some(w) = &file_watcher {
+            w.track_requires(&lua)?;
         }
 
-        let content = fs::read_to_string(&abs_path)
-            .map_err(|e| LuaError::RuntimeError(format!("Failed to read {}: {}", path_str, e)))?;
+        let content = match fs::read_to_string(&abs_path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path_str, e);
+                wait_for_reload(&mut services, &mut engines, &mut rx, &mut exit_rx).await;
+                continue;
+            }
+        };
+        let chunk_name = format!("@{}", path_str);
 
         println!("--- Running Lua script: {} ---", path_str);
+        let run_fut = sandbox::limited(
+            lua.load(&content)
+                .set_name(&chunk_name)
+                .call_async::<()>(()),
+        );
+        let res = match serve_until(run_fut, &mut engines, &mut rx, &mut exit_rx).await {
+            Wake::Done(res) => res,
+            Wake::Reload => {
+                println!("Reload signal received (during execution).");
+                continue;
+            }
+            Wake::Exit(code) => shutdown(&mut services, &mut engines, code).await,
+        };
 
-        let run_fut = lua.load(&content).call_async::<()>(());
-
-        tokio::select! {
-            res = run_fut => {
-                let mut exit_code_during_run = None;
-                if let Err(e) = res {
-                    let err_msg = e.to_string();
-                    if err_msg.contains("__LUMEN_EXIT__:") {
-                        if let Some(start) = err_msg.find("__LUMEN_EXIT__:") {
-                            let code_part = &err_msg[start + "__LUMEN_EXIT__:".len()..];
-                            let code_str: String = code_part.chars().take_while(|c| c.is_ascii_digit()).collect();
-                            exit_code_during_run = Some(code_str.parse::<i32>().unwrap_or(0));
-                        }
-                    } else {
-                        eprintln!("Lua execution error: {}", e);
-                        // On error, wait for change
-                        let _ = rx.recv().await;
-                        continue;
-                    }
+        match res {
+            Err(e) => {
+                if let Some(code) = exit::exit_code(&e) {
+                    let hooks = exit::shutdown_hooks(&lua, &app_state.lock().unwrap());
+                    exit::run_shutdown_hooks(hooks.unwrap_or_default()).await;
+                    shutdown(&mut services, &mut engines, code).await;
                 }
-
-                if exit_code_during_run.is_none() {
-                    println!("--- Lua script finished ---");
+                debugger::report("Lua execution error".to_string(), &e);
+                if !services.stop.is_empty() {
+                    eprintln!("Reload failed, still serving the previous version.");
                 }
+            }
+            Ok(()) => {
+                println!("--- Lua script finished ---");
 
                 // Check if we should start server/cron/telegram logic
                 let should_run = {
                     let state = app_state.lock().unwrap();
                     !state.routes.is_empty()
+                        || !state.websocket_routes.is_empty()
                         || !state.static_routes.is_empty()
                         || !state.cron_jobs.is_empty()
+                        || !state.job_defs.is_empty()
                         || !state.reverse_proxies.is_empty()
                         || state.telegram_handler.is_some()
                         || state.gmail_state.is_some()
                 };
 
-                if should_run {
-                        // This creates the engine request channel
-                        let (tx_engine, mut req_rx) = mpsc::channel::<EngineRequest>(100);
-
-                        {
-                            let mut state = app_state.lock().unwrap();
-                            state.engine_tx = Some(tx_engine.clone());
-                        }
-
-                        // Start Web Server
-                        let mut server_guard_opt =
-                            web_server::start(app_state.clone(), tx_engine.clone(), abs_path.clone())
-                                .await;
-
-                        // Start Cron Scheduler
-                        let mut sched_opt = cron::start(app_state.clone(), tx_engine.clone()).await;
+                if !should_run {
+                    // Script finished cleanly with no background tasks
+                    println!("No endpoints registered. Script finished.");
+                    services.stop();
+                    while engines.next().await.is_some() {}
+                    let hooks = exit::shutdown_hooks(&lua, &app_state.lock().unwrap());
+                    exit::run_shutdown_hooks(hooks.unwrap_or_default()).await;
+                    break;
+                }
 
-                        // Start Telegram Bot
-                        let mut tg_opt = telegram::start(app_state.clone(), tx_engine.clone()).await;
+                let handlers = {
+                    let state = app_state.lock().unwrap();
+                    engine::Handlers::load(&lua, &state)
+                };
+                let handlers = match handlers {
+                    Ok(handlers) => handlers,
+                    Err(e) => {
+                        debugger::report("Failed to load handlers".to_string(), &e);
+                        wait_for_reload(&mut services, &mut engines, &mut rx, &mut exit_rx).await;
+                        continue;
+                    }
+                };
 
-                        if server_guard_opt.is_some() || sched_opt.is_some() || tg_opt.is_some() {
-                            if server_guard_opt.is_some() {
-                                println!("Web Server running. Waiting for changes...");
-                            }
-                            if sched_opt.is_some() {
-                                println!("Cron Scheduler running. Waiting for changes...");
-                            }
-                            if tg_opt.is_some() {
-                                println!("Telegram Bot running. Waiting for changes...");
+                // Jobs added at runtime, by any Lua state of this version
+                let (cron_tx, cron_rx) = mpsc::unbounded_channel();
+
+                // Optional worker threads, each running its own copy of the script
+                let mut pool = None;
+                let mut workers = Vec::new();
+                let count = worker::worker_count();
+                if count > 0 {
+                    println!("Starting {} workers...", count);
+                    let (worker_pool, mut handles) = worker::spawn(
+                        count,
+                        &chunk_name,
+                        &content,
+                        gmail_state.clone(),
+                        exit_tx.clone(),
+                        cron_tx.clone(),
+                    );
+                    let ready = futures::future::join_all(handles.iter_mut().map(|h| &mut h.ready));
+                    let results =
+                        match serve_until(ready, &mut engines, &mut rx, &mut exit_rx).await {
+                            Wake::Done(results) => results,
+                            Wake::Reload => {
+                                println!("Reload signal received (during execution).");
+                                continue;
                             }
+                            Wake::Exit(code) => shutdown(&mut services, &mut engines, code).await,
+                        };
+                    let errors: Vec<String> = results
+                        .into_iter()
+                        .filter_map(|r| r.unwrap_or_else(|_| Err("worker stopped".into())).err())
+                        .collect();
+                    if !errors.is_empty() {
+                        for e in &errors {
+                            debugger::report("Worker failed to load the script".to_string(), e);
+                        }
+                        if !services.stop.is_empty() {
+                            eprintln!("Reload failed, still serving the previous version.");
+                        }
+                        wait_for_reload(&mut services, &mut engines, &mut rx, &mut exit_rx).await;
+                        continue;
+                    }
+                    pool = Some(worker_pool);
+                    workers = handles;
+                }
 
-                            let mut pending_requests: FuturesUnordered<
-                                std::pin::Pin<Box<dyn std::future::Future<Output = ()>>>,
-                            > = FuturesUnordered::new();
-                            let mut exit_code: Option<i32> = None;
-                            let sleep = tokio::time::sleep(std::time::Duration::from_secs(0));
-                            tokio::pin!(sleep);
-                            let mut timeout_active = false;
-
-                            loop {
-                                tokio::select! {
-                                    Some(req_enum) = req_rx.recv(), if exit_code.is_none() => {
-                                        match req_enum {
-                                            EngineRequest::Exit(code) => {
-                                                println!("Exit requested with code {}", code);
-                                                exit_code = Some(code);
-                                                // Stop background services
-                                                let _ = server_guard_opt.take();
-                                                if let Some(ref handle) = sched_opt {
-                                                    handle.abort();
-                                                }
-                                                let _ = sched_opt.take();
-                                                let _ = tg_opt.take();
-
-                                                if pending_requests.is_empty() {
-                                                    break;
-                                                }
-
-                                                let timeout_secs = std::env::var("LUMEN_EXIT_TIMEOUT")
-                                                    .ok()
-                                                    .and_then(|s| s.parse().ok())
-                                                    .unwrap_or(5);
-                                                sleep.as_mut().reset(tokio::time::Instant::now() + std::time::Duration::from_secs(timeout_secs));
-                                                timeout_active = true;
-                                            }
-                                            EngineRequest::Rest(req) => {
-                                                // Handle REST request
-                                                let func_res: LuaResult<LuaFunction> = {
-                                                    let state = app_state.lock().unwrap();
-                                                    if req.callback_id >= state.routes.len() {
-                                                         Err(LuaError::RuntimeError("Invalid callback ID".into()))
-                                                    } else {
-                                                        let route = &state.routes[req.callback_id];
-                                                        let func: LuaFunction = lua.registry_value(&route.callback_key)?;
-                                                        Ok(func)
-                                                    }
-                                                };
-
-                                                match func_res {
-                                                    Ok(func) => {
-                                                        let params = req.params;
-                                                        let response_tx = req.response_tx;
-                                                        let lua_ref = &lua;
-
-                                                        // Create future for the request
-                                                        let fut = async move {
-                                                            let res: LuaResult<serde_json::Value> = (async {
-                                                                let params_table = lua_ref.create_table()?;
-                                                                for (k, v) in params {
-                                                                    params_table.set(k, v)?;
-                                                                }
-                                                                let val: LuaValue = func.call_async(params_table).await?;
-                                                                let json_val: serde_json::Value = lua_ref.from_value(val)?;
-                                                                Ok(json_val)
-                                                            }).await;
-
-                                                            match res {
-                                                                Ok(val) => { response_tx.send(Ok(val)).ok(); },
-                                                                Err(e) => {
-                                                                    let mut err_msg = e.to_string();
-                                                                    if matches!(&e, LuaError::RuntimeError(msg) if msg.starts_with("__LUMEN_EXIT__:")) {
-                                                                        err_msg = "Process exiting".to_string();
-                                                                    }
-                                                                    response_tx.send(Err(err_msg)).ok();
-                                                                }
-                                                            }
-                                                        };
-                                                        pending_requests.push(Box::pin(fut));
-                                                    },
-                                                    Err(e) => {
-                                                        req.response_tx.send(Err(e.to_string())).ok();
-                                                    }
-                                                }
-                                            },
-                                            EngineRequest::Cron(id) => {
-                                                // Handle Cron request
-                                                let func_res: LuaResult<LuaFunction> = {
-                                                    let state = app_state.lock().unwrap();
-                                                    if id >= state.cron_jobs.len() {
-                                                        Err(LuaError::RuntimeError(
-                                                            "Invalid cron callback ID".into(),
-                                                        ))
-                                                    } else {
-                                                        let job = &state.cron_jobs[id];
-                                                        let func: LuaFunction =
-                                                            lua.registry_value(&job.callback_key)?;
-                                                        Ok(func)
-                                                    }
-                                                };
-
-                                                if let Ok(func) = func_res {
-                                                    let _lua_ref = &lua;
-                                                    let fut = async move {
-                                                        // Call Lua function with no arguments
-                                                        match func.call_async::<()>(()).await {
-                                                            Err(e) if !e.to_string().contains("__LUMEN_EXIT__:") => {
-                                                                eprintln!("Error executing cron job: {}", e);
-                                                            }
-                                                            _ => {}
-                                                        }
-                                                    };
-                                                    pending_requests.push(Box::pin(fut));
-                                                } else {
-                                                    eprintln!(
-                                                        "Failed to retrieve cron callback function"
-                                                    );
-                                                }
-                                            }
-                                            EngineRequest::TelegramUpdate(update) => {
-                                                // Handle Telegram update
-                                                let func_res: LuaResult<LuaFunction> = {
-                                                    let state = app_state.lock().unwrap();
-                                                    if let Some(ref key) = state.telegram_handler {
-                                                        lua.registry_value(key)
-                                                    } else {
-                                                        Err(LuaError::RuntimeError(
-                                                            "No telegram handler registered"
-                                                                .into(),
-                                                        ))
-                                                    }
-                                                };
-
-                                                if let Ok(func) = func_res {
-                                                    let lua_ref = &lua;
-                                                    let fut = async move {
-                                                        let update_val = lua_ref
-                                                            .to_value(&update)
-                                                            .unwrap_or(LuaValue::Nil);
-                                                        match func.call_async::<()>(update_val).await {
-                                                            Err(e) if !e.to_string().contains("__LUMEN_EXIT__:") => {
-                                                                eprintln!("Error executing telegram handler: {}", e);
-                                                            }
-                                                            _ => {}
-                                                        }
-                                                    };
-                                                    pending_requests.push(Box::pin(fut));
-                                                } else {
-                                                    eprintln!(
-                                                        "Failed to retrieve telegram callback function"
-                                                    );
-                                                }
-                                            }
-                                            EngineRequest::ProxyAuth(req) => {
-                                                let func: LuaFunction = match lua
-                                                    .registry_value(&req.callback_key)
-                                                {
-                                                    Ok(f) => f,
-                                                    Err(e) => {
-                                                        req.response_tx.send(false).ok();
-                                                        eprintln!(
-                                                            "Failed to get proxy auth callback: {}",
-                                                            e
-                                                        );
-                                                        continue;
-                                                    }
-                                                };
-                                                let email = req.email;
-                                                let domain = req.domain;
-                                                let response_tx = req.response_tx;
-
-                                                let fut = async move {
-                                                    let res: LuaResult<LuaValue> =
-                                                        func.call_async((email, domain)).await;
-                                                    match res {
-                                                        Ok(val) => {
-                                                            let allowed = match val {
-                                                                LuaValue::Boolean(b) => b,
-                                                                _ => false,
-                                                            };
-                                                            response_tx.send(allowed).ok();
-                                                        }
-                                                        Err(e) => {
-                                                            if !e.to_string().contains("__LUMEN_EXIT__:") {
-                                                                eprintln!("Error in proxy auth callback: {}", e);
-                                                            }
-                                                            response_tx.send(false).ok();
-                                                        }
-                                                    }
-                                                };
-                                                pending_requests.push(Box::pin(fut));
-                                            }
-                                        }
-                                    }
-                                    Some(_) = pending_requests.next() => {
-                                        if exit_code.is_some() && pending_requests.is_empty() {
-                                            break;
-                                        }
-                                    }
-                                    _ = &mut sleep, if timeout_active => {
-                                        println!("Exit timeout reached. Terminating...");
-                                        break;
-                                    }
-                                    _ = rx.recv(), if exit_code.is_none() => {
-                                        println!("Reload signal received.");
-                                        break; // Break logic loop to reload
-                                    }
-                                }
-                            }
+                // This creates the engine request channel
+                let (tx_engine, req_rx) = mpsc::channel::<EngineRequest>(100);
+                {
+                    let mut state = app_state.lock().unwrap();
+                    state.engine_tx = Some(tx_engine.downgrade());
+                    state.cron_tx = Some(cron_tx);
+                }
 
-                            if let Some(code) = exit_code {
-                                std::process::exit(code);
-                            }
-                        } else {
-                             // Failed to start server/cron
-                             println!("Waiting for changes to {}...", path_str);
-                             let _ = rx.recv().await;
-                        }
-                    } else if let Some(code) = exit_code_during_run {
-                        // Exit was called but no background tasks to wait for
-                        std::process::exit(code);
-                    } else {
-                         // Script finished cleanly with no background tasks
-                         println!("No endpoints registered. Script finished.");
-                         break;
+                // Start Web Server, or point the running one at the new routes
+                services.server = web_server::start(
+                    app_state.clone(),
+                    tx_engine.clone(),
+                    abs_path.clone(),
+                    services.server.take(),
+                )
+                .await;
+
+                // Restart Telegram Bot
+                services.telegram = None;
+                services.telegram = telegram::start(app_state.clone(), tx_engine.clone()).await;
+
+                // Restart Cron Scheduler, which also takes the jobs that
+                // handlers add
+                if let Some(handle) = services.cron.take() {
+                    handle.abort();
+                }
+                let has_jobs = !app_state.lock().unwrap().cron_jobs.is_empty();
+
+                // Restart the job queue. Attempts in flight are recorded by
+                // the previous version
+                services.jobs = None;
+                services.jobs = jobs::start(app_state.clone(), tx_engine.clone());
+
+                if services.server.is_some()
+                    || has_jobs
+                    || services.jobs.is_some()
+                    || services.telegram.is_some()
+                {
+                    services.cron =
+                        Some(cron::start(app_state.clone(), tx_engine.clone(), cron_rx));
+                    if services.server.is_some() {
+                        println!("Web Server running. Waiting for changes...");
                     }
-            }
-            _ = rx.recv() => {
-               // Reloading
-               println!("Reload signal received (during execution).");
+                    if has_jobs {
+                        println!("Cron Scheduler running. Waiting for changes...");
+                    }
+                    if services.jobs.is_some() {
+                        println!("Job queue running. Waiting for changes...");
+                    }
+                    if services.telegram.is_some() {
+                        println!("Telegram Bot running. Waiting for changes...");
+                    }
+
+                    // Requests already routed to the previous version finish there
+                    let (stop_tx, stop_rx) = oneshot::channel();
+                    let mut stop = vec![stop_tx];
+                    for handle in workers {
+                        stop.push(handle.stop);
+                        let done = handle.done;
+                        engines.push(Box::pin(async move {
+                            let _ = done.await;
+                        }));
+                    }
+                    *admin_engine.lock().unwrap() = Some(tx_engine.downgrade());
+                    for old in std::mem::replace(&mut services.stop, stop) {
+                        let _ = old.send(engine::Stop::Retire);
+                    }
+                    engines.push(Box::pin(engine::run(
+                        lua,
+                        handlers,
+                        req_rx,
+                        stop_rx,
+                        pool,
+                        Arc::default(),
+                    )));
+                } else {
+                    // Failed to start server/cron
+                    services.stop.clear();
+                    *admin_engine.lock().unwrap() = None;
+                    println!("Waiting for changes to {}...", path_str);
+                }
             }
         }
+
+        wait_for_reload(&mut services, &mut engines, &mut rx, &mut exit_rx).await;
     }
 
     Ok(())
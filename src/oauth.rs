@@ -0,0 +1,824 @@
+use crate::types::AppState;
+use crate::util::random_token;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use mlua::prelude::*;
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a started login may take before its `state` is forgotten.
+pub const PENDING_TTL: Duration = Duration::from_secs(600);
+
+/// How long fetched signing keys are used before being fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+
+const GOOGLE_DISCOVERY: &str = "https://accounts.google.com/.well-known/openid-configuration";
+
+static PENDING: LazyLock<Mutex<HashMap<String, Pending>>> = LazyLock::new(Default::default);
+static JWKS_CACHE: LazyLock<Mutex<HashMap<String, (Instant, JwkSet)>>> =
+    LazyLock::new(Default::default);
+/// Set by `lumen test`, the only place mock providers can be registered.
+static MOCK_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Provider endpoints, named as in an OpenID Connect discovery document.
+#[derive(Clone, Deserialize)]
+pub struct Endpoints {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+    pub issuer: Option<String>,
+}
+
+enum Kind {
+    /// OpenID Connect: identity comes from a signed ID token.
+    Oidc,
+    /// Plain OAuth2: identity comes from the userinfo endpoint.
+    OAuth2,
+    /// OAuth2 with GitHub's way of exposing private emails.
+    GitHub,
+    Mock(MockIssuer),
+}
+
+pub struct Provider {
+    pub name: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    kind: Kind,
+    discovery: Option<String>,
+    endpoints: tokio::sync::OnceCell<Endpoints>,
+}
+
+fn required(opts: &LuaTable, key: &str, provider: &str) -> LuaResult<String> {
+    opts.get::<Option<String>>(key)?.ok_or_else(|| {
+        LuaError::RuntimeError(format!(
+            "oauth provider {} requires the {} option",
+            provider, key
+        ))
+    })
+}
+
+impl Provider {
+    /// Builds a provider from the options of `oauth.provider`. The type
+    /// defaults to the name for the built-in presets.
+    fn from_lua(name: &str, opts: &LuaTable) -> LuaResult<Provider> {
+        let kind_name = match opts.get::<Option<String>>("type")? {
+            Some(t) => t,
+            None if ["google", "github", "microsoft", "mock"].contains(&name) => name.to_string(),
+            None if opts.contains_key("discovery")? || opts.contains_key("issuer")? => {
+                "oidc".to_string()
+            }
+            None => "oauth2".to_string(),
+        };
+
+        let mut endpoints = None;
+        let mut discovery = None;
+        let (kind, default_scopes) = match kind_name.as_str() {
+            "google" => {
+                discovery = Some(GOOGLE_DISCOVERY.to_string());
+                (Kind::Oidc, "openid email profile")
+            }
+            "microsoft" => {
+                let tenant = opts
+                    .get::<Option<String>>("tenant")?
+                    .unwrap_or_else(|| "common".to_string());
+                discovery = Some(format!(
+                    "https://login.microsoftonline.com/{}/v2.0/.well-known/openid-configuration",
+                    tenant
+                ));
+                (Kind::Oidc, "openid email profile")
+            }
+            "github" => {
+                endpoints = Some(Endpoints {
+                    authorization_endpoint: "https://github.com/login/oauth/authorize".into(),
+                    token_endpoint: "https://github.com/login/oauth/access_token".into(),
+                    userinfo_endpoint: Some("https://api.github.com/user".into()),
+                    jwks_uri: None,
+                    issuer: None,
+                });
+                (Kind::GitHub, "read:user user:email")
+            }
+            "oidc" => {
+                discovery = Some(match opts.get::<Option<String>>("discovery")? {
+                    Some(url) => url,
+                    None => format!(
+                        "{}/.well-known/openid-configuration",
+                        required(opts, "issuer", name)?.trim_end_matches('/')
+                    ),
+                });
+                (Kind::Oidc, "openid email profile")
+            }
+            "oauth2" => {
+                endpoints = Some(Endpoints {
+                    authorization_endpoint: required(opts, "auth_url", name)?,
+                    token_endpoint: required(opts, "token_url", name)?,
+                    userinfo_endpoint: opts.get("userinfo_url")?,
+                    jwks_uri: None,
+                    issuer: None,
+                });
+                (Kind::OAuth2, "")
+            }
+            // Logs in anyone as anyone, so never on a live server
+            "mock" if !MOCK_ALLOWED.load(Ordering::Relaxed) => {
+                return Err(LuaError::RuntimeError(
+                    "Mock oauth providers are only available under lumen test".into(),
+                ));
+            }
+            "mock" => {
+                endpoints = Some(Endpoints {
+                    authorization_endpoint: format!("/auth/{}/authorize", name),
+                    token_endpoint: String::new(),
+                    userinfo_endpoint: None,
+                    jwks_uri: None,
+                    issuer: Some(format!("lumen-mock:{}", name)),
+                });
+                (
+                    Kind::Mock(MockIssuer::from_lua(opts)?),
+                    "openid email profile",
+                )
+            }
+            other => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Unknown oauth provider type: {}",
+                    other
+                )));
+            }
+        };
+
+        let is_mock = matches!(kind, Kind::Mock(_));
+        let client_id = match opts.get::<Option<String>>("client_id")? {
+            Some(id) => id,
+            None if is_mock => "lumen-mock".to_string(),
+            None => required(opts, "client_id", name)?,
+        };
+        let redirect_uri = match opts.get::<Option<String>>("redirect_uri")? {
+            Some(uri) => uri,
+            None if is_mock => format!("/auth/{}/callback", name),
+            None => required(opts, "redirect_uri", name)?,
+        };
+        let scopes = match opts.get::<Option<LuaValue>>("scopes")? {
+            Some(LuaValue::Table(t)) => t
+                .sequence_values::<String>()
+                .collect::<LuaResult<Vec<_>>>()?
+                .join(" "),
+            Some(LuaValue::String(s)) => s.to_str()?.to_string(),
+            _ => default_scopes.to_string(),
+        };
+
+        Ok(Provider {
+            name: name.to_string(),
+            client_id,
+            client_secret: opts.get("client_secret")?,
+            redirect_uri,
+            scopes,
+            kind,
+            discovery,
+            endpoints: match endpoints {
+                Some(e) => tokio::sync::OnceCell::new_with(Some(e)),
+                None => tokio::sync::OnceCell::new(),
+            },
+        })
+    }
+
+    /// The Google login configured through the Gmail client credentials.
+    fn google(client_id: &str, client_secret: &str, redirect_uri: &str) -> Provider {
+        Provider {
+            name: "google".to_string(),
+            client_id: client_id.to_string(),
+            client_secret: Some(client_secret.to_string()),
+            redirect_uri: redirect_uri.to_string(),
+            scopes: "openid email".to_string(),
+            kind: Kind::Oidc,
+            discovery: Some(GOOGLE_DISCOVERY.to_string()),
+            endpoints: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// Endpoints, fetched from the discovery document on first use.
+    async fn endpoints(&self) -> Result<&Endpoints, String> {
+        self.endpoints
+            .get_or_try_init(|| async {
+                let url = self
+                    .discovery
+                    .clone()
+                    .ok_or("provider has no endpoints configured")?;
+                tokio::task::spawn_blocking(move || {
+                    ureq::get(&url)
+                        .call()
+                        .map_err(|e| format!("discovery failed: {}", e))?
+                        .into_json::<Endpoints>()
+                        .map_err(|e| format!("invalid discovery document: {}", e))
+                })
+                .await
+                .map_err(|e| e.to_string())?
+            })
+            .await
+    }
+}
+
+pub enum PendingKind {
+    /// A user login, returning to the given path afterwards.
+    Login { return_to: String },
+    /// Granting Gmail/Drive access through `gmail.login` or `drive.login`.
+    GoogleLink,
+}
+
+pub struct Pending {
+    provider: String,
+    verifier: String,
+    nonce: String,
+    created: Instant,
+    pub kind: PendingKind,
+}
+
+fn begin(provider: &str, kind: PendingKind) -> (String, String, String) {
+    let state = random_token();
+    let verifier = random_token();
+    let nonce = random_token();
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.created.elapsed() < PENDING_TTL);
+    pending.insert(
+        state.clone(),
+        Pending {
+            provider: provider.to_string(),
+            verifier: verifier.clone(),
+            nonce: nonce.clone(),
+            created: Instant::now(),
+            kind,
+        },
+    );
+    (state, verifier, nonce)
+}
+
+/// Registers a Gmail/Drive authorization and returns its `state` value.
+pub fn begin_google_link() -> String {
+    begin("google", PendingKind::GoogleLink).0
+}
+
+/// Claims the login a callback's `state` refers to. Each state is only
+/// accepted once, only by the provider it was issued for and, for logins,
+/// only from the browser whose state cookie (`browser_state`) matches.
+pub fn take_pending(state: &str, provider: &str, browser_state: Option<&str>) -> Option<Pending> {
+    let mut pending = PENDING.lock().unwrap();
+    // Checked before claiming, so a forged callback can't use up the state
+    if matches!(pending.get(state)?.kind, PendingKind::Login { .. }) && browser_state != Some(state)
+    {
+        return None;
+    }
+    let pending = pending.remove(state)?;
+    (pending.provider == provider && pending.created.elapsed() < PENDING_TTL).then_some(pending)
+}
+
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Starts a login and returns the provider URL to send the browser to, with its state.
+pub async fn authorization_url(
+    provider: &Provider,
+    return_to: &str,
+    login_hint: Option<&str>,
+) -> Result<(String, String), String> {
+    let endpoints = provider.endpoints().await?;
+    let kind = PendingKind::Login {
+        return_to: return_to.to_string(),
+    };
+    let (state, verifier, nonce) = begin(&provider.name, kind);
+
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri)
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &code_challenge(&verifier))
+        .append_pair("code_challenge_method", "S256");
+    if !provider.scopes.is_empty() {
+        query.append_pair("scope", &provider.scopes);
+    }
+    if matches!(provider.kind, Kind::Oidc | Kind::Mock(_)) {
+        query.append_pair("nonce", &nonce);
+    }
+    if let Some(hint) = login_hint {
+        query.append_pair("login_hint", hint);
+    }
+    let sep = if endpoints.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let url = format!(
+        "{}{}{}",
+        endpoints.authorization_endpoint,
+        sep,
+        query.finish()
+    );
+    Ok((url, state))
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn get_json(url: &str, access_token: &str) -> Result<JsonValue, String> {
+    ureq::get(url)
+        .set("Authorization", &format!("Bearer {}", access_token))
+        .set("Accept", "application/json")
+        .call()
+        .map_err(|e| format!("{} failed: {}", url, e))?
+        .into_json()
+        .map_err(|e| e.to_string())
+}
+
+/// Payload of a JWT, read without checking the signature.
+fn unverified_claims(token: &str) -> Option<JsonValue> {
+    let payload = token.split('.').nth(1)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+async fn fetch_jwks(uri: &str, kid: Option<&str>) -> Result<JwkSet, String> {
+    {
+        let cache = JWKS_CACHE.lock().unwrap();
+        if let Some((fetched, jwks)) = cache.get(uri)
+            && fetched.elapsed() < JWKS_TTL
+            && kid.is_none_or(|kid| jwks.find(kid).is_some())
+        {
+            return Ok(jwks.clone());
+        }
+    }
+    // Unknown key ids are refetched to pick up key rotation
+    let url = uri.to_string();
+    let jwks = tokio::task::spawn_blocking(move || {
+        ureq::get(&url)
+            .call()
+            .map_err(|e| format!("fetching signing keys failed: {}", e))?
+            .into_json::<JwkSet>()
+            .map_err(|e| format!("invalid signing keys: {}", e))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    JWKS_CACHE
+        .lock()
+        .unwrap()
+        .insert(uri.to_string(), (Instant::now(), jwks.clone()));
+    Ok(jwks)
+}
+
+/// Checks an ID token's signature against the provider keys, its audience,
+/// issuer, expiry and nonce, and returns its claims.
+fn verify_id_token(
+    token: &str,
+    jwks: &JwkSet,
+    client_id: &str,
+    issuer: Option<&str>,
+    nonce: &str,
+) -> Result<JsonValue, String> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err("ID tokens must be signed with the provider's public key".into());
+    }
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or("ID token signed with an unknown key")?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[client_id]);
+    if let Some(issuer) = issuer {
+        // Multi-tenant issuers (Microsoft "common") embed the tenant id
+        let issuer = match unverified_claims(token)
+            .as_ref()
+            .and_then(|c| c.get("tid"))
+            .and_then(|t| t.as_str())
+        {
+            Some(tid) => issuer.replace("{tenantid}", tid),
+            None => issuer.to_string(),
+        };
+        validation.set_issuer(&[issuer]);
+    }
+    let data = jsonwebtoken::decode::<JsonValue>(token, &key, &validation)
+        .map_err(|e| format!("invalid ID token: {}", e))?;
+    if data.claims.get("nonce").and_then(|n| n.as_str()) != Some(nonce) {
+        return Err("ID token nonce does not match the login".into());
+    }
+    Ok(data.claims)
+}
+
+/// Finishes a login: exchanges the code and returns the identity claims.
+/// `sub`, `email` (when known) and `provider` are always normalized.
+pub async fn complete(
+    provider: &Provider,
+    code: &str,
+    pending: &Pending,
+) -> Result<JsonValue, String> {
+    let endpoints = provider.endpoints().await?;
+
+    let tokens = match &provider.kind {
+        Kind::Mock(mock) => mock.exchange(code, &pending.verifier, provider).await?,
+        _ => {
+            let mut form = vec![
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code.to_string()),
+                ("redirect_uri", provider.redirect_uri.clone()),
+                ("client_id", provider.client_id.clone()),
+                ("code_verifier", pending.verifier.clone()),
+            ];
+            if let Some(secret) = &provider.client_secret {
+                form.push(("client_secret", secret.clone()));
+            }
+            let url = endpoints.token_endpoint.clone();
+            tokio::task::spawn_blocking(move || {
+                let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                ureq::post(&url)
+                    .set("Accept", "application/json")
+                    .send_form(&form)
+                    .map_err(|e| format!("token exchange failed: {}", e))?
+                    .into_json::<TokenResponse>()
+                    .map_err(|e| format!("invalid token response: {}", e))
+            })
+            .await
+            .map_err(|e| e.to_string())??
+        }
+    };
+    if let Some(error) = tokens.error {
+        return Err(match tokens.error_description {
+            Some(desc) => format!("{}: {}", error, desc),
+            None => error,
+        });
+    }
+    let access_token = tokens.access_token.unwrap_or_default();
+
+    let mut claims = match (&tokens.id_token, &provider.kind) {
+        (Some(id_token), Kind::Mock(mock)) => verify_id_token(
+            id_token,
+            &mock.signing_key().await?.1,
+            &provider.client_id,
+            endpoints.issuer.as_deref(),
+            &pending.nonce,
+        )?,
+        (Some(id_token), _) => {
+            let jwks_uri = endpoints
+                .jwks_uri
+                .as_deref()
+                .ok_or("provider publishes no signing keys")?;
+            let kid = jsonwebtoken::decode_header(id_token)
+                .map_err(|e| e.to_string())?
+                .kid;
+            let jwks = fetch_jwks(jwks_uri, kid.as_deref()).await?;
+            verify_id_token(
+                id_token,
+                &jwks,
+                &provider.client_id,
+                endpoints.issuer.as_deref(),
+                &pending.nonce,
+            )?
+        }
+        (None, Kind::Oidc) => return Err("provider returned no ID token".into()),
+        (None, _) => JsonValue::Object(Map::new()),
+    };
+
+    if claims.get("email").is_none_or(|e| e.is_null())
+        && let Some(url) = endpoints.userinfo_endpoint.clone()
+        && !access_token.is_empty()
+    {
+        let is_github = matches!(provider.kind, Kind::GitHub);
+        let token = access_token.clone();
+        let userinfo = tokio::task::spawn_blocking(move || {
+            let mut info = get_json(&url, &token)?;
+            // The profile email of GitHub may be private or unverified, so
+            // only the primary verified address is used
+            if is_github {
+                if let Some(info) = info.as_object_mut() {
+                    info.remove("email");
+                }
+                let emails = get_json("https://api.github.com/user/emails", &token)?;
+                let primary = emails.as_array().and_then(|list| {
+                    list.iter().find(|e| {
+                        e.get("primary").and_then(|p| p.as_bool()) == Some(true)
+                            && e.get("verified").and_then(|v| v.as_bool()) == Some(true)
+                    })
+                });
+                if let Some(email) = primary.and_then(|e| e.get("email")) {
+                    info["email"] = email.clone();
+                    info["email_verified"] = JsonValue::Bool(true);
+                }
+            }
+            Ok::<_, String>(info)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        if let (Some(claims), JsonValue::Object(info)) = (claims.as_object_mut(), userinfo) {
+            for (k, v) in info {
+                claims.entry(k).or_insert(v);
+            }
+        }
+    }
+
+    let claims_map = claims
+        .as_object_mut()
+        .ok_or("identity claims are not an object")?;
+    if !claims_map.contains_key("sub")
+        && let Some(id) = claims_map.get("id").cloned()
+    {
+        let sub = match id {
+            JsonValue::String(s) => s,
+            other => other.to_string(),
+        };
+        claims_map.insert("sub".into(), JsonValue::String(sub));
+    }
+    if !claims_map.contains_key("sub") {
+        return Err("provider returned no user id".into());
+    }
+    // Token bookkeeping is of no use to the application
+    for key in [
+        "nonce",
+        "aud",
+        "azp",
+        "iat",
+        "exp",
+        "at_hash",
+        "c_hash",
+        "auth_time",
+    ] {
+        claims_map.remove(key);
+    }
+    claims_map.insert("provider".into(), JsonValue::String(provider.name.clone()));
+    Ok(claims)
+}
+
+/// The email of a completed login, if the provider vouches for it. Others
+/// may be unverified or changeable by the user, so they can't identify them.
+pub fn verified_email(identity: &JsonValue) -> Option<&str> {
+    let verified = match identity.get("email_verified") {
+        Some(JsonValue::Bool(verified)) => *verified,
+        // Some providers send it as a string
+        Some(JsonValue::String(verified)) => verified == "true",
+        _ => false,
+    };
+    verified.then(|| identity.get("email")?.as_str()).flatten()
+}
+
+struct MockCode {
+    nonce: Option<String>,
+    challenge: Option<String>,
+    login_hint: Option<String>,
+    created: Instant,
+}
+
+/// A local identity provider for tests: its authorize endpoint logs in
+/// immediately as the configured user (or `login_hint`), and it signs real
+/// RS256 ID tokens so logins go through the same verification as real ones.
+pub struct MockIssuer {
+    identity: Map<String, JsonValue>,
+    key: OnceLock<(EncodingKey, JwkSet)>,
+    codes: Mutex<HashMap<String, MockCode>>,
+}
+
+impl MockIssuer {
+    fn from_lua(opts: &LuaTable) -> LuaResult<MockIssuer> {
+        let email = opts
+            .get::<Option<String>>("email")?
+            .unwrap_or_else(|| "user@example.com".to_string());
+        let mut identity = Map::new();
+        if let Some(claims) = opts.get::<Option<LuaTable>>("claims")?
+            && let JsonValue::Object(claims) =
+                serde_json::to_value(&claims).map_err(|e| LuaError::RuntimeError(e.to_string()))?
+        {
+            identity.extend(claims);
+        }
+        identity
+            .entry("sub")
+            .or_insert_with(|| JsonValue::String(email.clone()));
+        identity.insert("email".into(), JsonValue::String(email));
+        identity
+            .entry("email_verified")
+            .or_insert(JsonValue::Bool(true));
+        Ok(MockIssuer {
+            identity,
+            key: OnceLock::new(),
+            codes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Generates the signing key on first use, off the async runtime.
+    async fn signing_key(&self) -> Result<&(EncodingKey, JwkSet), String> {
+        if let Some(key) = self.key.get() {
+            return Ok(key);
+        }
+        let key = tokio::task::spawn_blocking(generate_mock_key)
+            .await
+            .map_err(|e| e.to_string())??;
+        Ok(self.key.get_or_init(|| key))
+    }
+
+    /// Handles the authorize request and returns the redirect back to the app.
+    pub fn authorize(&self, params: &HashMap<String, String>) -> Result<String, String> {
+        let redirect_uri = params.get("redirect_uri").ok_or("missing redirect_uri")?;
+        let state = params.get("state").ok_or("missing state")?;
+        let code = random_token();
+        let mut codes = self.codes.lock().unwrap();
+        codes.retain(|_, c| c.created.elapsed() < PENDING_TTL);
+        codes.insert(
+            code.clone(),
+            MockCode {
+                nonce: params.get("nonce").cloned(),
+                challenge: params.get("code_challenge").cloned(),
+                login_hint: params.get("login_hint").cloned(),
+                created: Instant::now(),
+            },
+        );
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("code", &code)
+            .append_pair("state", state)
+            .finish();
+        let sep = if redirect_uri.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}{}", redirect_uri, sep, query))
+    }
+
+    async fn exchange(
+        &self,
+        code: &str,
+        verifier: &str,
+        provider: &Provider,
+    ) -> Result<TokenResponse, String> {
+        let entry = self
+            .codes
+            .lock()
+            .unwrap()
+            .remove(code)
+            .ok_or("invalid authorization code")?;
+        if entry.challenge.as_deref() != Some(code_challenge(verifier).as_str()) {
+            return Err("PKCE verification failed".into());
+        }
+
+        let mut claims = self.identity.clone();
+        if let Some(hint) = entry.login_hint {
+            claims.insert("sub".into(), JsonValue::String(hint.clone()));
+            claims.insert("email".into(), JsonValue::String(hint));
+        }
+        let now = chrono::Utc::now().timestamp();
+        claims.insert("iss".into(), json!(format!("lumen-mock:{}", provider.name)));
+        claims.insert("aud".into(), json!(provider.client_id));
+        claims.insert("iat".into(), json!(now));
+        claims.insert("exp".into(), json!(now + 300));
+        if let Some(nonce) = entry.nonce {
+            claims.insert("nonce".into(), json!(nonce));
+        }
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("mock".to_string());
+        let id_token = jsonwebtoken::encode(&header, &claims, &self.signing_key().await?.0)
+            .map_err(|e| e.to_string())?;
+        Ok(TokenResponse {
+            access_token: Some(random_token()),
+            id_token: Some(id_token),
+            error: None,
+            error_description: None,
+        })
+    }
+}
+
+fn generate_mock_key() -> Result<(EncodingKey, JwkSet), String> {
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+    use rsa::traits::PublicKeyParts;
+    let private_key =
+        rsa::RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048).map_err(|e| e.to_string())?;
+    let pem = private_key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| e.to_string())?;
+    let encoding_key = EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|e| e.to_string())?;
+    let jwks: JwkSet = serde_json::from_value(json!({
+        "keys": [{
+            "kty": "RSA",
+            "kid": "mock",
+            "use": "sig",
+            "alg": "RS256",
+            "n": URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be()),
+        }]
+    }))
+    .map_err(|e| e.to_string())?;
+    Ok((encoding_key, jwks))
+}
+
+/// Looks up a provider configured with `oauth.provider`. Without explicit
+/// configuration, "google" uses the Gmail client credentials.
+pub fn find_provider(app_state: &Arc<Mutex<AppState>>, name: &str) -> Option<Arc<Provider>> {
+    let mut state = app_state.lock().unwrap();
+    if let Some(provider) = state.oauth_providers.get(name) {
+        return Some(provider.clone());
+    }
+    if name != "google" {
+        return None;
+    }
+    // Kept with this version's state, so reloaded credentials are picked up
+    let gs = state.gmail_state.clone()?;
+    let provider = Arc::new(Provider::google(
+        &gs.config.client_id,
+        &gs.config.client_secret,
+        &gs.config.redirect_uri,
+    ));
+    state
+        .oauth_providers
+        .insert(name.to_string(), provider.clone());
+    Some(provider)
+}
+
+/// The provider used by `require_login` routes and the reverse proxy.
+pub fn login_provider(app_state: &Arc<Mutex<AppState>>) -> String {
+    let state = app_state.lock().unwrap();
+    state
+        .login_provider
+        .clone()
+        .unwrap_or_else(|| "google".to_string())
+}
+
+/// Lets scripts register mock providers, for `lumen test`.
+pub fn allow_mock() {
+    MOCK_ALLOWED.store(true, Ordering::Relaxed);
+}
+
+/// Mock providers serve their own authorize endpoint.
+pub fn mock_issuer(provider: &Provider) -> Option<&MockIssuer> {
+    match &provider.kind {
+        Kind::Mock(mock) => Some(mock),
+        _ => None,
+    }
+}
+
+pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
+    let oauth = lua.create_table()?;
+
+    let state_clone = app_state.clone();
+    oauth.set(
+        "provider",
+        lua.create_function(move |_, (name, opts): (String, Option<LuaTable>)| {
+            let opts = match opts {
+                Some(o) => o,
+                None => {
+                    return Err(LuaError::RuntimeError(
+                        "oauth.provider needs options".into(),
+                    ));
+                }
+            };
+            let provider = Provider::from_lua(&name, &opts)?;
+            let mut state = state_clone.lock().unwrap();
+            if opts.get::<Option<bool>>("default")?.unwrap_or(false) {
+                state.login_provider = Some(name.clone());
+            }
+            state.oauth_providers.insert(name, Arc::new(provider));
+            Ok(())
+        })?,
+    )?;
+
+    let state_clone = app_state.clone();
+    oauth.set(
+        "login_url",
+        lua.create_function(move |_, (name, opts): (String, Option<LuaTable>)| {
+            if find_provider(&state_clone, &name).is_none() {
+                return Err(LuaError::RuntimeError(format!(
+                    "Unknown oauth provider: {}",
+                    name
+                )));
+            }
+            // The local route sets the state cookie before leaving for the provider
+            let mut query = url::form_urlencoded::Serializer::new(String::new());
+            if let Some(o) = opts {
+                if let Some(return_to) = o.get::<Option<String>>("return_to")? {
+                    query.append_pair("return_to", &return_to);
+                }
+                if let Some(hint) = o.get::<Option<String>>("login_hint")? {
+                    query.append_pair("login_hint", &hint);
+                }
+            }
+            let query = query.finish();
+            let path = format!("/auth/{}/login", urlencoding::encode(&name));
+            Ok(if query.is_empty() {
+                path
+            } else {
+                format!("{}?{}", path, query)
+            })
+        })?,
+    )?;
+
+    lua.globals().set("oauth", oauth)?;
+    Ok(())
+}
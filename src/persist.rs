@@ -0,0 +1,44 @@
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+/// Values stored through `persist`. They belong to the process rather than a
+/// Lua state, so they survive reloads but not restarts.
+static VALUES: LazyLock<Mutex<HashMap<String, JsonValue>>> = LazyLock::new(Default::default);
+
+/// Registers the global `persist` table. Reading a key returns a copy of the
+/// stored value and assigning stores a copy, so nested changes only stick once
+/// the value is assigned again.
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let meta = lua.create_table()?;
+    meta.set(
+        "__index",
+        lua.create_function(|lua, (_, key): (LuaTable, String)| {
+            match VALUES.lock().unwrap().get(&key) {
+                Some(v) => lua.to_value(v),
+                None => Ok(LuaValue::Nil),
+            }
+        })?,
+    )?;
+    meta.set(
+        "__newindex",
+        lua.create_function(|lua, (_, key, value): (LuaTable, String, LuaValue)| {
+            if value.is_nil() {
+                VALUES.lock().unwrap().remove(&key);
+                return Ok(());
+            }
+            let json_val: JsonValue = lua.from_value(value).map_err(|e| {
+                LuaError::RuntimeError(format!("persist.{} must be plain data: {}", key, e))
+            })?;
+            VALUES.lock().unwrap().insert(key, json_val);
+            Ok(())
+        })?,
+    )?;
+
+    let persist = lua.create_table()?;
+    persist.set_metatable(Some(meta))?;
+    lua.globals().set("persist", persist)?;
+    Ok(())
+}
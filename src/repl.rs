@@ -0,0 +1,127 @@
+use crate::exit;
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use serde_json::Value as JsonValue;
+use std::io::Write;
+use tokio::sync::mpsc;
+
+/// Runs `code` as an expression if it is one, otherwise as a chunk of
+/// statements.
+pub async fn eval(lua: &Lua, name: &str, code: &str) -> LuaResult<LuaMultiValue> {
+    let func = match lua
+        .load(format!("return {}", code))
+        .set_name(name)
+        .into_function()
+    {
+        Ok(func) => func,
+        Err(_) => lua.load(code).set_name(name).into_function()?,
+    };
+    func.call_async(()).await
+}
+
+/// Whether more input could complete the chunk that failed with `err`.
+pub fn is_incomplete(err: &LuaError) -> bool {
+    matches!(
+        err,
+        LuaError::SyntaxError {
+            incomplete_input: true,
+            ..
+        }
+    )
+}
+
+/// Formats values the way `print` does, except that tables are shown as JSON.
+pub fn format_values(lua: &Lua, values: &LuaMultiValue) -> String {
+    values
+        .iter()
+        .map(|value| format_value(lua, value))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+fn format_value(lua: &Lua, value: &LuaValue) -> String {
+    if value.is_table()
+        && let Ok(json) = lua.from_value::<JsonValue>(value.clone())
+    {
+        return json.to_string();
+    }
+    value.to_string().unwrap_or_else(|_| format!("{:?}", value))
+}
+
+/// `lumen eval`: prints the results of `code` and returns the exit code.
+pub async fn eval_once(lua: &Lua, code: &str) -> i32 {
+    match eval(lua, "=eval", code).await {
+        Ok(values) => {
+            if !values.is_empty() {
+                println!("{}", format_values(lua, &values));
+            }
+            0
+        }
+        Err(e) => exit::exit_code(&e).unwrap_or_else(|| {
+            eprintln!("{}", e);
+            1
+        }),
+    }
+}
+
+/// `lumen repl`: evaluates lines from stdin until end of input or `exit()`.
+/// Ctrl-C cancels the running chunk, or discards the unfinished one.
+pub async fn run(lua: &Lua) -> i32 {
+    let (line_tx, mut line_rx) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    println!(
+        "Lumen {} ({}). Press Ctrl-D to quit.",
+        env!("LUMEN_VERSION"),
+        env!("LUMEN_BUILD_ENV")
+    );
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { ">> " });
+        let _ = std::io::stdout().flush();
+        let line = tokio::select! {
+            line = line_rx.recv() => line,
+            _ = tokio::signal::ctrl_c() => {
+                println!();
+                buffer.clear();
+                continue;
+            }
+        };
+        let Some(line) = line else {
+            println!();
+            return 0;
+        };
+        buffer.push_str(&line);
+        buffer.push('\n');
+
+        let res = tokio::select! {
+            res = eval(lua, "=repl", &buffer) => res,
+            _ = tokio::signal::ctrl_c() => {
+                println!("\nInterrupted.");
+                buffer.clear();
+                continue;
+            }
+        };
+        match res {
+            Ok(values) => {
+                if !values.is_empty() {
+                    println!("{}", format_values(lua, &values));
+                }
+            }
+            Err(e) if is_incomplete(&e) => continue,
+            Err(e) => {
+                if let Some(code) = exit::exit_code(&e) {
+                    return code;
+                }
+                eprintln!("{}", e);
+            }
+        }
+        buffer.clear();
+    }
+}
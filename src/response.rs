@@ -0,0 +1,451 @@
+use crate::file_obj::detect_mime;
+use crate::types::HttpResponse;
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use futures::StreamExt;
+use mlua::prelude::*;
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+/// Interval of comment lines keeping idle event streams open through proxies.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn json(status: u16, value: &JsonValue) -> LuaResult<Self> {
+        let mut res = Self::new(status);
+        res.body = serde_json::to_vec(value).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+        res.set_header("Content-Type", "application/json");
+        Ok(res)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replaces any existing header with the same (case-insensitive) name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    fn set_body(&mut self, lua: &Lua, body: LuaValue) -> LuaResult<()> {
+        self.body = match body {
+            LuaValue::Nil => Vec::new(),
+            LuaValue::String(s) => s.as_bytes().to_vec(),
+            other => lua
+                .coerce_string(other)?
+                .ok_or_else(|| LuaError::RuntimeError("body must be a string".into()))?
+                .as_bytes()
+                .to_vec(),
+        };
+        Ok(())
+    }
+}
+
+fn build_cookie(name: String, value: String, opts: Option<LuaTable>) -> LuaResult<String> {
+    let mut cookie = Cookie::build((name, value)).path("/");
+    if let Some(opts) = opts {
+        if let Some(path) = opts.get::<Option<String>>("path")? {
+            cookie = cookie.path(path);
+        }
+        if let Some(domain) = opts.get::<Option<String>>("domain")? {
+            cookie = cookie.domain(domain);
+        }
+        if let Some(max_age) = opts.get::<Option<i64>>("max_age")? {
+            cookie = cookie.max_age(cookie::time::Duration::seconds(max_age));
+        }
+        if let Some(http_only) = opts.get::<Option<bool>>("http_only")? {
+            cookie = cookie.http_only(http_only);
+        }
+        if let Some(secure) = opts.get::<Option<bool>>("secure")? {
+            cookie = cookie.secure(secure);
+        }
+        if let Some(same_site) = opts.get::<Option<String>>("same_site")? {
+            let same_site = match same_site.to_lowercase().as_str() {
+                "strict" => SameSite::Strict,
+                "lax" => SameSite::Lax,
+                "none" => SameSite::None,
+                other => {
+                    return Err(LuaError::RuntimeError(format!(
+                        "Invalid same_site value: {}",
+                        other
+                    )));
+                }
+            };
+            cookie = cookie.same_site(same_site);
+        }
+    }
+    Ok(cookie.build().to_string())
+}
+
+impl LuaUserData for HttpResponse {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // Builders return the same userdata, so chains modify one response
+        methods.add_function("status", |_, (ud, status): (LuaAnyUserData, u16)| {
+            ud.borrow_mut::<HttpResponse>()?.status = status;
+            Ok(ud)
+        });
+
+        methods.add_function(
+            "header",
+            |_, (ud, name, value): (LuaAnyUserData, String, String)| {
+                ud.borrow_mut::<HttpResponse>()?.set_header(&name, &value);
+                Ok(ud)
+            },
+        );
+
+        methods.add_function(
+            "cookie",
+            |_, (ud, name, value, opts): (LuaAnyUserData, String, String, Option<LuaTable>)| {
+                let cookie = build_cookie(name, value, opts)?;
+                ud.borrow_mut::<HttpResponse>()?
+                    .headers
+                    .push(("Set-Cookie".to_string(), cookie));
+                Ok(ud)
+            },
+        );
+
+        methods.add_function("body", |lua, (ud, body): (LuaAnyUserData, LuaValue)| {
+            ud.borrow_mut::<HttpResponse>()?.set_body(lua, body)?;
+            Ok(ud)
+        });
+
+        methods.add_function("text", |lua, (ud, body): (LuaAnyUserData, LuaValue)| {
+            let mut this = ud.borrow_mut::<HttpResponse>()?;
+            this.set_body(lua, body)?;
+            this.set_header("Content-Type", "text/plain; charset=utf-8");
+            drop(this);
+            Ok(ud)
+        });
+
+        methods.add_function("html", |lua, (ud, body): (LuaAnyUserData, LuaValue)| {
+            let mut this = ud.borrow_mut::<HttpResponse>()?;
+            this.set_body(lua, body)?;
+            this.set_header("Content-Type", "text/html; charset=utf-8");
+            drop(this);
+            Ok(ud)
+        });
+
+        methods.add_function("json", |lua, (ud, value): (LuaAnyUserData, LuaValue)| {
+            let json_val: JsonValue = lua.from_value(value)?;
+            let mut this = ud.borrow_mut::<HttpResponse>()?;
+            this.body =
+                serde_json::to_vec(&json_val).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            this.set_header("Content-Type", "application/json");
+            drop(this);
+            Ok(ud)
+        });
+
+        methods.add_function(
+            "redirect",
+            |_, (ud, location, status): (LuaAnyUserData, String, Option<u16>)| {
+                let mut this = ud.borrow_mut::<HttpResponse>()?;
+                this.status = status.unwrap_or(302);
+                this.set_header("Location", &location);
+                drop(this);
+                Ok(ud)
+            },
+        );
+    }
+}
+
+/// A response whose body is produced over time by a Lua function running as
+/// a coroutine: every value passed to `coroutine.yield` becomes a chunk (or an
+/// event, for server-sent events).
+pub struct StreamResponse {
+    pub head: HttpResponse,
+    func: LuaFunction,
+    args: LuaMultiValue,
+    sse: bool,
+}
+
+impl LuaUserData for StreamResponse {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function(
+            "header",
+            |_, (ud, name, value): (LuaAnyUserData, String, String)| {
+                ud.borrow_mut::<StreamResponse>()?
+                    .head
+                    .set_header(&name, &value);
+                Ok(ud)
+            },
+        );
+
+        methods.add_function("status", |_, (ud, status): (LuaAnyUserData, u16)| {
+            ud.borrow_mut::<StreamResponse>()?.head.status = status;
+            Ok(ud)
+        });
+    }
+}
+
+pub enum HandlerResponse {
+    Full(HttpResponse),
+    Stream(StreamResponse),
+}
+
+/// Converts the value returned by a Lua handler into an HTTP response.
+///
+/// Responses built with `rest.response` or `rest.stream` are used as-is,
+/// anything else is serialized as a JSON body with status 200.
+pub fn from_lua_value(lua: &Lua, value: LuaValue) -> LuaResult<HandlerResponse> {
+    if let LuaValue::UserData(ud) = &value {
+        if let Ok(res) = ud.borrow::<HttpResponse>() {
+            return Ok(HandlerResponse::Full(res.clone()));
+        }
+        if ud.is::<StreamResponse>() {
+            return Ok(HandlerResponse::Stream(ud.take::<StreamResponse>()?));
+        }
+    }
+    let json_val: JsonValue = lua.from_value(value)?;
+    Ok(HandlerResponse::Full(HttpResponse::json(200, &json_val)?))
+}
+
+pub fn stream_response(
+    func: LuaFunction,
+    args: LuaMultiValue,
+    sse: bool,
+    opts: Option<LuaTable>,
+) -> LuaResult<StreamResponse> {
+    let mut head = HttpResponse::new(200);
+    if sse {
+        head.set_header("Content-Type", "text/event-stream");
+        head.set_header("Cache-Control", "no-cache");
+        head.set_header("X-Accel-Buffering", "no");
+    } else {
+        head.set_header("Content-Type", "application/octet-stream");
+    }
+    if let Some(opts) = opts {
+        if let Some(status) = opts.get::<Option<u16>>("status")? {
+            head.status = status;
+        }
+        if let Some(headers) = opts.get::<Option<LuaTable>>("headers")? {
+            for pair in headers.pairs::<String, String>() {
+                let (k, v) = pair?;
+                head.set_header(&k, &v);
+            }
+        }
+        if let Some(content_type) = opts.get::<Option<String>>("content_type")? {
+            head.set_header("Content-Type", &content_type);
+        }
+    }
+    Ok(StreamResponse {
+        head,
+        func,
+        args,
+        sse,
+    })
+}
+
+fn value_to_text(lua: &Lua, value: LuaValue) -> LuaResult<String> {
+    match value {
+        LuaValue::String(s) => Ok(s.to_string_lossy()),
+        LuaValue::Table(_) => {
+            let json_val: JsonValue = lua.from_value(value)?;
+            serde_json::to_string(&json_val).map_err(|e| LuaError::RuntimeError(e.to_string()))
+        }
+        other => Ok(lua
+            .coerce_string(other)?
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default()),
+    }
+}
+
+/// Formats a yielded value as a server-sent event. Tables with `event`,
+/// `data`, `id` or `retry` keys describe a full event, anything else is sent
+/// as the event data (tables as JSON).
+fn format_event(lua: &Lua, value: LuaValue) -> LuaResult<String> {
+    let mut out = String::new();
+    let data = match &value {
+        LuaValue::Table(t)
+            if ["event", "data", "id", "retry"]
+                .iter()
+                .any(|k| t.contains_key(*k).unwrap_or(false)) =>
+        {
+            if let Some(event) = t.get::<Option<String>>("event")? {
+                out.push_str(&format!("event: {}\n", event));
+            }
+            if let Some(id) = t.get::<Option<String>>("id")? {
+                out.push_str(&format!("id: {}\n", id));
+            }
+            if let Some(retry) = t.get::<Option<u64>>("retry")? {
+                out.push_str(&format!("retry: {}\n", retry));
+            }
+            value_to_text(lua, t.get("data")?)?
+        }
+        _ => value_to_text(lua, value)?,
+    };
+    for line in data.split('\n') {
+        out.push_str(&format!("data: {}\n", line));
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+fn format_chunk(lua: &Lua, value: LuaValue) -> LuaResult<Vec<u8>> {
+    match value {
+        LuaValue::String(s) => Ok(s.as_bytes().to_vec()),
+        // Tables are streamed as newline-delimited JSON
+        LuaValue::Table(_) => Ok(format!("{}\n", value_to_text(lua, value)?).into_bytes()),
+        other => Ok(value_to_text(lua, other)?.into_bytes()),
+    }
+}
+
+/// Resumes the stream's coroutine and forwards every yielded value to the
+/// client until the function returns, fails or the client goes away.
+pub async fn drive_stream(lua: &Lua, stream: StreamResponse, tx: Sender<Vec<u8>>) {
+    let thread = match lua.create_thread(stream.func) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to start response stream: {}", e);
+            return;
+        }
+    };
+    let mut values = match thread.into_async::<LuaValue>(stream.args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Failed to start response stream: {}", e);
+            return;
+        }
+    };
+    let mut keepalive = tokio::time::interval(SSE_KEEPALIVE);
+    keepalive.tick().await;
+
+    loop {
+        let next = tokio::select! {
+            next = values.next() => next,
+            _ = keepalive.tick(), if stream.sse => {
+                if tx.send(b": keepalive\n\n".to_vec()).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ = tx.closed() => return,
+        };
+        let value = match next {
+            Some(Ok(LuaValue::Nil)) => continue,
+            Some(Ok(value)) => value,
+            Some(Err(e)) => {
+                if crate::exit::exit_code(&e).is_none() {
+                    crate::debugger::report("Error in response stream".to_string(), &e);
+                }
+                return;
+            }
+            None => return,
+        };
+        let chunk = if stream.sse {
+            format_event(lua, value).map(String::into_bytes)
+        } else {
+            format_chunk(lua, value)
+        };
+        match chunk {
+            Ok(chunk) => {
+                if tx.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                eprintln!("Invalid value yielded from response stream: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+pub fn register(lua: &Lua, rest: &LuaTable) -> LuaResult<()> {
+    rest.set(
+        "response",
+        lua.create_function(|lua, opts: Option<LuaTable>| {
+            let mut res = HttpResponse::new(200);
+            if let Some(opts) = opts {
+                if let Some(status) = opts.get::<Option<u16>>("status")? {
+                    res.status = status;
+                }
+                if let Some(headers) = opts.get::<Option<LuaTable>>("headers")? {
+                    for pair in headers.pairs::<String, String>() {
+                        let (k, v) = pair?;
+                        res.set_header(&k, &v);
+                    }
+                }
+                if let Some(content_type) = opts.get::<Option<String>>("content_type")? {
+                    res.set_header("Content-Type", &content_type);
+                }
+                let json: LuaValue = opts.get("json")?;
+                if !json.is_nil() {
+                    let json_val: JsonValue = lua.from_value(json)?;
+                    res.body = serde_json::to_vec(&json_val)
+                        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+                    res.set_header("Content-Type", "application/json");
+                } else {
+                    res.set_body(lua, opts.get("body")?)?;
+                }
+            }
+            Ok(res)
+        })?,
+    )?;
+
+    rest.set(
+        "stream",
+        lua.create_function(|_, (func, opts): (LuaFunction, Option<LuaTable>)| {
+            stream_response(func, LuaMultiValue::new(), false, opts)
+        })?,
+    )?;
+
+    rest.set(
+        "sse",
+        lua.create_function(|_, (func, opts): (LuaFunction, Option<LuaTable>)| {
+            stream_response(func, LuaMultiValue::new(), true, opts)
+        })?,
+    )?;
+
+    rest.set(
+        "redirect",
+        lua.create_function(|_, (location, status): (String, Option<u16>)| {
+            let mut res = HttpResponse::new(status.unwrap_or(302));
+            res.set_header("Location", &location);
+            Ok(res)
+        })?,
+    )?;
+
+    rest.set(
+        "file",
+        lua.create_function(|_, (path, opts): (String, Option<LuaTable>)| {
+            crate::sandbox::check_path(&path, false)?;
+            let body = fs::read(&path).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            let mut res = HttpResponse::new(200);
+            res.body = body;
+
+            let mut content_type = None;
+            let mut filename = None;
+            if let Some(opts) = opts {
+                content_type = opts.get::<Option<String>>("content_type")?;
+                filename = opts.get::<Option<String>>("filename")?;
+            }
+            let content_type = content_type.unwrap_or_else(|| detect_mime(&path));
+            res.set_header("Content-Type", &content_type);
+            if let Some(filename) = filename {
+                res.set_header(
+                    "Content-Disposition",
+                    &format!(
+                        "attachment; filename=\"{}\"",
+                        filename.replace(['"', '\\'], "_")
+                    ),
+                );
+            }
+            Ok(res)
+        })?,
+    )?;
+
+    Ok(())
+}
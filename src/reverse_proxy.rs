@@ -75,6 +75,7 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
         "add",
         lua.create_function(
             move |_, (host, path_prefix, remote_base): (String, String, String)| {
+                crate::sandbox::check_url(&remote_base)?;
                 let mut state = state_clone.lock().unwrap();
                 let index = state.reverse_proxies.len();
                 state.reverse_proxies.push(ReverseProxyInfo {
@@ -93,8 +94,8 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
     )?;
 
     // Setup database for domain management
-    let db_conn =
-        Connection::open("server.db").map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+    let db_conn = Connection::open(crate::session::db_path())
+        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
     db_conn
         .execute(
             "CREATE TABLE IF NOT EXISTS authorized_users (
@@ -118,6 +119,21 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
         })?,
     )?;
 
+    let state_clone = app_state.clone();
+    // Logs a user out everywhere, e.g. after removing them from a domain
+    reverse_proxy.set(
+        "revoke_sessions",
+        lua.create_async_function(move |_, email: String| {
+            let store = state_clone.lock().unwrap().session_config.store.clone();
+            async move {
+                tokio::task::spawn_blocking(move || store.revoke(&email))
+                    .await
+                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?
+                    .map_err(LuaError::RuntimeError)
+            }
+        })?,
+    )?;
+
     lua.globals().set("reverse_proxy", reverse_proxy.clone())?;
 
     // Also register domain as a global function as requested
@@ -0,0 +1,85 @@
+-- Restricts the standard library to what the sandbox manifest allows. What
+-- is left checks its arguments with `check_path` and `env_allowed`.
+local check_path, env_allowed, exit = ...
+local io_open, io_lines, io_input, io_output = io.open, io.lines, io.input, io.output
+local os_getenv, os_remove, os_rename = os.getenv, os.remove, os.rename
+local load, loadfile, searchpath = load, loadfile, package.searchpath
+
+io.open = function(path, mode)
+    check_path(path, mode ~= nil and mode:find("[wa+]") ~= nil)
+    return io_open(path, mode)
+end
+io.lines = function(path, ...)
+    if path ~= nil then
+        check_path(path, false)
+    end
+    return io_lines(path, ...)
+end
+io.input = function(file)
+    if type(file) == "string" then
+        check_path(file, false)
+    end
+    return io_input(file)
+end
+io.output = function(file)
+    if type(file) == "string" then
+        check_path(file, true)
+    end
+    return io_output(file)
+end
+-- Shell commands can't be checked, util.execute can
+io.popen = nil
+os.execute = nil
+os.tmpname = nil
+
+os.exit = exit
+os.getenv = function(name)
+    if env_allowed(name) then
+        return os_getenv(name)
+    end
+end
+os.remove = function(path)
+    check_path(path, true)
+    return os_remove(path)
+end
+os.rename = function(from, to)
+    check_path(from, true)
+    check_path(to, true)
+    return os_rename(from, to)
+end
+
+-- Precompiled chunks can crash the VM, so only source is loaded
+_G.load = function(chunk, name, _, env)
+    return load(chunk, name, "t", env)
+end
+local function checked_loadfile(path, _, env)
+    if path ~= nil then
+        check_path(path, false)
+    end
+    return loadfile(path, "t", env)
+end
+_G.loadfile = checked_loadfile
+_G.dofile = function(path)
+    local chunk, err = checked_loadfile(path)
+    if not chunk then
+        error(err, 2)
+    end
+    return chunk()
+end
+
+-- Modules are found on package.path as usual, but read like loadfile
+package.cpath = ""
+package.searchers = {
+    package.searchers[1],
+    function(name)
+        local path, err = searchpath(name, package.path)
+        if not path then
+            return err
+        end
+        local chunk, load_err = checked_loadfile(path)
+        if not chunk then
+            error(string.format("error loading module '%s' from file '%s':\n\t%s", name, path, load_err))
+        end
+        return chunk, path
+    end,
+}
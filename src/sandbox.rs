@@ -0,0 +1,322 @@
+use mlua::prelude::*;
+use mlua::{Debug, DebugEvent};
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Instructions between two checks of the limits.
+const CHECK_EVERY: u32 = 1000;
+
+/// What a script may do, from the file given with `--sandbox`. Without one
+/// everything is allowed.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Manifest {
+    /// Directories and files that may be read.
+    read: Vec<PathBuf>,
+    /// Directories and files that may be written, and read.
+    write: Vec<PathBuf>,
+    /// Hosts for `http` and the reverse proxy. `*.example.com` covers the
+    /// subdomains and `*` any host.
+    hosts: Vec<String>,
+    /// Programs for `util.execute`, by name or path.
+    commands: Vec<String>,
+    /// Environment variables the script can see. `APP_*` covers a prefix.
+    env: Vec<String>,
+    limits: Limits,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    /// Memory of the whole Lua state, in megabytes.
+    memory_mb: Option<usize>,
+    /// Instructions per callback.
+    instructions: Option<u64>,
+    /// Seconds a callback may run without yielding.
+    time: Option<f64>,
+}
+
+static MANIFEST: OnceLock<Manifest> = OnceLock::new();
+
+pub fn enabled() -> bool {
+    MANIFEST.get().is_some()
+}
+
+/// Reads the manifest, which then applies to every Lua state of the process.
+pub fn load(path: &Path) -> Result<(), String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Sandbox manifest {}: {}", path.display(), e))?;
+    let mut manifest: Manifest = serde_json::from_str(&content)
+        .map_err(|e| format!("Sandbox manifest {}: {}", path.display(), e))?;
+    if manifest
+        .limits
+        .time
+        .is_some_and(|t| !(t.is_finite() && t > 0.0))
+    {
+        return Err(format!(
+            "Sandbox manifest {}: limits.time must be a positive number of seconds",
+            path.display()
+        ));
+    }
+    manifest.read = manifest.read.iter().map(|p| resolve(p)).collect();
+    manifest.write = manifest.write.iter().map(|p| resolve(p)).collect();
+    println!("Sandboxed by {}", path.display());
+    MANIFEST
+        .set(manifest)
+        .map_err(|_| "Sandbox manifest loaded twice".to_string())
+}
+
+fn denied(message: String) -> LuaError {
+    LuaError::RuntimeError(format!("sandbox: {}", message))
+}
+
+/// Absolute form of `path`, with the symlinks of the part that exists
+/// resolved, so `..` and links can't lead out of an allowed directory.
+fn resolve(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let components: Vec<Component> = path.components().collect();
+    for i in (1..=components.len()).rev() {
+        let prefix: PathBuf = components[..i].iter().collect();
+        if let Ok(mut real) = std::fs::canonicalize(&prefix) {
+            for component in &components[i..] {
+                match component {
+                    Component::ParentDir => {
+                        real.pop();
+                    }
+                    Component::Normal(name) => real.push(name),
+                    _ => {}
+                }
+            }
+            return real;
+        }
+    }
+    path
+}
+
+/// Fails unless the manifest allows reading, or writing, `path`.
+pub fn check_path(path: impl AsRef<Path>, write: bool) -> LuaResult<()> {
+    let Some(manifest) = MANIFEST.get() else {
+        return Ok(());
+    };
+    let path = path.as_ref();
+    let real = resolve(path);
+    let allowed = manifest
+        .write
+        .iter()
+        .chain(if write { &[][..] } else { &manifest.read[..] })
+        .any(|root| real.starts_with(root));
+    if allowed {
+        Ok(())
+    } else {
+        Err(denied(format!(
+            "{} access to {} is not allowed",
+            if write { "write" } else { "read" },
+            path.display()
+        )))
+    }
+}
+
+/// Fails unless the manifest allows requests to the host of `url`.
+pub fn check_url(url: &str) -> LuaResult<()> {
+    let Some(manifest) = MANIFEST.get() else {
+        return Ok(());
+    };
+    let parsed = url::Url::parse(url).map_err(|e| denied(format!("invalid URL {}: {}", url, e)))?;
+    let host = parsed.host_str().unwrap_or("");
+    let allowed = manifest.hosts.iter().any(|pattern| {
+        pattern == "*"
+            || pattern.eq_ignore_ascii_case(host)
+            || pattern.strip_prefix("*.").is_some_and(|domain| {
+                host.len() > domain.len()
+                    && host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+            })
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(denied(format!("requests to {} are not allowed", host)))
+    }
+}
+
+/// The program to run for `util.execute(name)`. In the sandbox `name` must
+/// be allowed, and is looked up in `PATH` here so that the command's own
+/// environment can't point it elsewhere.
+pub fn command(name: &str) -> LuaResult<PathBuf> {
+    let Some(manifest) = MANIFEST.get() else {
+        return Ok(PathBuf::from(name));
+    };
+    let found = if name.contains('/') {
+        std::fs::canonicalize(name).ok()
+    } else {
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+                .and_then(|path| std::fs::canonicalize(path).ok())
+        })
+    };
+    let allowed = |program: &PathBuf| {
+        manifest.commands.iter().any(|entry| {
+            if entry.contains('/') {
+                std::fs::canonicalize(entry).is_ok_and(|path| &path == program)
+            } else {
+                entry == name
+            }
+        })
+    };
+    match found {
+        Some(program) if allowed(&program) => Ok(program),
+        _ => Err(denied(format!("command {} is not allowed", name))),
+    }
+}
+
+/// Whether the script may see the environment variable `name`.
+pub fn env_allowed(name: &str) -> bool {
+    let Some(manifest) = MANIFEST.get() else {
+        return true;
+    };
+    manifest
+        .env
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+}
+
+/// Error raised when a callback goes over a limit. Like `exit()`, `pcall`
+/// passes it on, so the callback is stopped.
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sandbox: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+pub fn limit_exceeded(err: &LuaError) -> bool {
+    err.chain()
+        .any(|e| e.downcast_ref::<LimitExceeded>().is_some())
+}
+
+/// What the callback running on this thread has used so far.
+struct Budget {
+    instructions: Cell<u64>,
+    /// When it was last resumed.
+    resumed: Cell<Instant>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Budget>>> = const { RefCell::new(None) };
+}
+
+fn limits() -> Option<Limits> {
+    MANIFEST
+        .get()
+        .map(|manifest| manifest.limits)
+        .filter(|limits| limits.instructions.is_some() || limits.time.is_some())
+}
+
+/// Runs `fut` as one callback: the instruction limit applies to all of it,
+/// the time limit to each stretch it runs without yielding.
+pub async fn limited<F: Future>(fut: F) -> F::Output {
+    if limits().is_none() {
+        return fut.await;
+    }
+    let budget = Rc::new(Budget {
+        instructions: Cell::new(0),
+        resumed: Cell::new(Instant::now()),
+    });
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        budget.resumed.set(Instant::now());
+        let previous = CURRENT.replace(Some(budget.clone()));
+        let res = fut.as_mut().poll(cx);
+        CURRENT.set(previous);
+        res
+    })
+    .await
+}
+
+fn check_limits(limits: &Limits) -> LuaResult<LuaVmState> {
+    let Some(budget) = CURRENT.with_borrow(|budget| budget.clone()) else {
+        return Ok(LuaVmState::Continue);
+    };
+    // Once over a limit every check fails, until the callback is gone
+    let instructions = budget.instructions.get() + CHECK_EVERY as u64;
+    budget.instructions.set(instructions);
+    if let Some(max) = limits.instructions
+        && instructions > max
+    {
+        return Err(LuaError::external(LimitExceeded(format!(
+            "instruction limit of {} exceeded",
+            max
+        ))));
+    }
+    if let Some(time) = limits.time
+        && budget.resumed.get().elapsed().as_secs_f64() > time
+    {
+        return Err(LuaError::external(LimitExceeded(format!(
+            "time limit of {} s exceeded",
+            time
+        ))));
+    }
+    Ok(LuaVmState::Continue)
+}
+
+pub type LineHook = Box<dyn Fn(&Lua, &Debug) -> LuaResult<LuaVmState>>;
+
+/// Sets the global hook of `lua`: the limits, if any, and `line`, the
+/// debugger's line hook.
+pub fn set_hook(lua: &Lua, line: Option<LineHook>) -> LuaResult<()> {
+    let limits = limits();
+    if limits.is_none() && line.is_none() {
+        lua.remove_global_hook();
+        return Ok(());
+    }
+    let triggers = LuaHookTriggers {
+        every_line: line.is_some(),
+        every_nth_instruction: limits.map(|_| CHECK_EVERY),
+        ..Default::default()
+    };
+    lua.set_global_hook(triggers, move |lua, debug| match debug.event() {
+        DebugEvent::Count => match &limits {
+            Some(limits) => check_limits(limits),
+            None => Ok(LuaVmState::Continue),
+        },
+        _ => match &line {
+            Some(line) => line(lua, debug),
+            None => Ok(LuaVmState::Continue),
+        },
+    })
+}
+
+/// Applies the manifest to `lua`, once every module is registered.
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let Some(manifest) = MANIFEST.get() else {
+        return Ok(());
+    };
+    if let Some(mb) = manifest.limits.memory_mb {
+        lua.set_memory_limit(mb.saturating_mul(1024 * 1024))?;
+    }
+    set_hook(lua, None)?;
+
+    let check_path =
+        lua.create_function(|_, (path, write): (String, bool)| check_path(&path, write))?;
+    let env_allowed = lua.create_function(|_, name: String| Ok(env_allowed(&name)))?;
+    let exit: LuaFunction = lua.globals().get("exit")?;
+    lua.load(include_str!("sandbox.lua"))
+        .set_name("=sandbox")
+        .call::<()>((check_path, env_allowed, exit))
+}
@@ -0,0 +1,483 @@
+use crate::util::random_token;
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use hmac::{Hmac, Mac};
+use mlua::prelude::*;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::{Map, Value as JsonValue};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SESSION_COOKIE: &str = "lumen_session";
+
+/// Default lifetime of a session.
+pub const SESSION_TTL_SECS: i64 = 7 * 24 * 3600;
+
+/// Overrides `server.db`, for `lumen test`.
+static DB_PATH: RwLock<Option<String>> = RwLock::new(None);
+
+/// The database of sessions, `kv`, `jobs` and cron state.
+pub fn db_path() -> String {
+    DB_PATH
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| "server.db".to_string())
+}
+
+/// Points `db_path` at `path`, or back at `server.db`.
+pub fn set_db_path(path: Option<String>) {
+    *DB_PATH.write().unwrap() = path;
+}
+
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+static MEMORY_STORE: OnceLock<Arc<MemoryStore>> = OnceLock::new();
+
+#[derive(Clone, Default)]
+pub struct SessionRecord {
+    /// The logged-in user, if any.
+    pub email: Option<String>,
+    pub data: Map<String, JsonValue>,
+    pub expires_at: i64,
+}
+
+/// Backend keeping session records between requests. Methods may block.
+pub trait SessionStore: Send + Sync {
+    /// Returns the record of a live (not expired) session.
+    fn load(&self, id: &str) -> Option<SessionRecord>;
+    fn save(&self, id: &str, record: &SessionRecord) -> Result<(), String>;
+    fn delete(&self, id: &str) -> Result<(), String>;
+    /// Deletes every session of a user and returns how many there were.
+    fn revoke(&self, email: &str) -> Result<usize, String>;
+}
+
+/// Sessions in a SQLite database, surviving restarts.
+pub struct SqliteStore {
+    path: String,
+}
+
+impl SqliteStore {
+    pub fn new(path: &str) -> Self {
+        SqliteStore {
+            path: path.to_string(),
+        }
+    }
+
+    fn open(&self) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(&self.path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                email TEXT,
+                data TEXT NOT NULL DEFAULT '{}',
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+        )?;
+        // Tables created before session data was supported only held logins
+        let has_data: bool = conn
+            .prepare("SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'data'")?
+            .exists([])?;
+        if !has_data {
+            conn.execute_batch(
+                "ALTER TABLE sessions RENAME TO sessions_old;
+                CREATE TABLE sessions (
+                    id TEXT PRIMARY KEY,
+                    email TEXT,
+                    data TEXT NOT NULL DEFAULT '{}',
+                    created_at INTEGER NOT NULL,
+                    expires_at INTEGER NOT NULL
+                );
+                INSERT INTO sessions (id, email, created_at, expires_at)
+                    SELECT id, email, created_at, expires_at FROM sessions_old;
+                DROP TABLE sessions_old;",
+            )?;
+        }
+        Ok(conn)
+    }
+}
+
+impl SessionStore for SqliteStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        let conn = self.open().ok()?;
+        let row: Option<(Option<String>, String, i64)> = conn
+            .query_row(
+                "SELECT email, data, expires_at FROM sessions WHERE id = ? AND expires_at > ?",
+                params![id, chrono::Utc::now().timestamp()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .ok()?;
+        let (email, data, expires_at) = row?;
+        Some(SessionRecord {
+            email,
+            data: serde_json::from_str(&data).unwrap_or_default(),
+            expires_at,
+        })
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> Result<(), String> {
+        let conn = self.open().map_err(|e| e.to_string())?;
+        let now = chrono::Utc::now().timestamp();
+        let data = serde_json::to_string(&record.data).map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM sessions WHERE expires_at <= ?", params![now])
+            .map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO sessions (id, email, data, created_at, expires_at) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET email = excluded.email, data = excluded.data,
+                expires_at = excluded.expires_at",
+            params![id, record.email, data, now, record.expires_at],
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), String> {
+        let conn = self.open().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM sessions WHERE id = ?", params![id])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn revoke(&self, email: &str) -> Result<usize, String> {
+        let conn = self.open().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM sessions WHERE email = ?", params![email])
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Sessions kept in process memory: fast, but lost on restart. Shared by all
+/// script reloads.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(id)
+            .filter(|r| r.expires_at > chrono::Utc::now().timestamp())
+            .cloned()
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> Result<(), String> {
+        let now = chrono::Utc::now().timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, r| r.expires_at > now);
+        sessions.insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), String> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn revoke(&self, email: &str) -> Result<usize, String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, r| r.email.as_deref() != Some(email));
+        Ok(before - sessions.len())
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub store: Arc<dyn SessionStore>,
+    pub ttl: i64,
+    /// Whether cookies get the `Secure` flag (set when serving HTTPS).
+    pub secure: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            store: Arc::new(SqliteStore::new(&db_path())),
+            ttl: SESSION_TTL_SECS,
+            secure: false,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Builds a config from the options table of `srv:sessions`.
+    pub fn from_lua(opts: &LuaTable) -> LuaResult<Self> {
+        let mut config = SessionConfig::default();
+        match opts.get::<Option<String>>("store")?.as_deref() {
+            None | Some("sqlite") => {
+                if let Some(path) = opts.get::<Option<String>>("path")? {
+                    config.store = Arc::new(SqliteStore::new(&path));
+                }
+            }
+            Some("memory") => {
+                config.store = MEMORY_STORE
+                    .get_or_init(|| Arc::new(MemoryStore::default()))
+                    .clone();
+            }
+            Some(other) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Unknown session store: {}",
+                    other
+                )));
+            }
+        }
+        if let Some(ttl) = opts.get::<Option<i64>>("ttl")? {
+            config.ttl = ttl;
+        }
+        Ok(config)
+    }
+}
+
+/// The cookie signing key: `LUMEN_SESSION_SECRET` if set, otherwise a random
+/// key generated once and kept in `server.db` so sessions survive restarts.
+fn secret() -> &'static [u8] {
+    SECRET.get_or_init(|| {
+        if let Some(s) = crate::util::env_var("LUMEN_SESSION_SECRET")
+            && !s.is_empty()
+        {
+            return s.into_bytes();
+        }
+        let stored = (|| -> rusqlite::Result<String> {
+            let conn = Connection::open(db_path())?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS session_secret (
+                    id INTEGER PRIMARY KEY CHECK (id = 1),
+                    secret TEXT NOT NULL
+                )",
+            )?;
+            conn.execute(
+                "INSERT OR IGNORE INTO session_secret (id, secret) VALUES (1, ?)",
+                params![random_token()],
+            )?;
+            conn.query_row(
+                "SELECT secret FROM session_secret WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+        })();
+        match stored {
+            Ok(s) => s.into_bytes(),
+            Err(e) => {
+                eprintln!(
+                    "Warning: could not persist session key ({}), sessions end on restart",
+                    e
+                );
+                random_token().into_bytes()
+            }
+        }
+    })
+}
+
+fn signature(id: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret()).expect("HMAC can take key of any size");
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn sign(id: &str) -> String {
+    format!(
+        "{}.{}",
+        id,
+        hex::encode(signature(id).finalize().into_bytes())
+    )
+}
+
+/// Returns the session id of a cookie value if its signature is valid.
+fn verify(value: &str) -> Option<String> {
+    let (id, sig) = value.split_once('.')?;
+    let sig = hex::decode(sig).ok()?;
+    signature(id).verify_slice(&sig).ok()?;
+    Some(id.to_string())
+}
+
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    tokio::task::spawn_blocking(f).await.ok()
+}
+
+#[derive(Default)]
+struct SessionState {
+    id: Option<String>,
+    record: SessionRecord,
+    dirty: bool,
+    /// Ids given up through `destroy` or `regenerate`, deleted on commit.
+    stale: Vec<String>,
+}
+
+/// The session of one request, shared between the web server and the Lua
+/// handler. Changes are written to the store by `commit` once the handler
+/// has returned.
+#[derive(Clone, Default)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl Session {
+    /// Loads the session a cookie refers to, or starts an empty one.
+    pub async fn load(config: &SessionConfig, cookie: Option<&str>) -> Session {
+        let Some(id) = cookie.and_then(verify) else {
+            return Session::default();
+        };
+        let store = config.store.clone();
+        let lookup_id = id.clone();
+        match blocking(move || store.load(&lookup_id)).await.flatten() {
+            Some(record) => Session(Arc::new(Mutex::new(SessionState {
+                id: Some(id),
+                record,
+                ..Default::default()
+            }))),
+            None => Session::default(),
+        }
+    }
+
+    pub fn email(&self) -> Option<String> {
+        self.0.lock().unwrap().record.email.clone()
+    }
+
+    /// Name of the oauth provider the session logged in with.
+    pub fn provider(&self) -> Option<String> {
+        let state = self.0.lock().unwrap();
+        let identity = state.record.data.get("identity")?;
+        identity["provider"].as_str().map(str::to_string)
+    }
+
+    /// Marks the session as belonging to `email`. The id is regenerated so a
+    /// session fixed before the login can't be taken over.
+    pub fn login(&self, email: String, identity: JsonValue) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(id) = state.id.take() {
+            state.stale.push(id);
+        }
+        state.record.email = Some(email);
+        state.record.data.insert("identity".to_string(), identity);
+        state.record.expires_at = 0;
+        state.dirty = true;
+    }
+
+    pub fn destroy(&self) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(id) = state.id.take() {
+            state.stale.push(id);
+        }
+        state.record = SessionRecord::default();
+        state.dirty = false;
+    }
+
+    /// Writes pending changes to the store and returns the cookie to send, if
+    /// it changed.
+    pub async fn commit(&self, config: &SessionConfig) -> Option<Cookie<'static>> {
+        let (stale, save) = {
+            let mut state = self.0.lock().unwrap();
+            let stale = std::mem::take(&mut state.stale);
+            if !state.dirty {
+                (stale, None)
+            } else {
+                state.dirty = false;
+                let new_id = state.id.is_none();
+                let id = state.id.get_or_insert_with(random_token).clone();
+                if new_id {
+                    state.record.expires_at = chrono::Utc::now().timestamp() + config.ttl;
+                }
+                (stale, Some((id, state.record.clone(), new_id)))
+            }
+        };
+        let had_session = !stale.is_empty();
+
+        let store = config.store.clone();
+        let saved = save.clone();
+        let res = blocking(move || {
+            for id in &stale {
+                store.delete(id)?;
+            }
+            if let Some((id, record, _)) = &saved {
+                store.save(id, record)?;
+            }
+            Ok::<_, String>(())
+        })
+        .await;
+        if let Some(Err(e)) = res {
+            eprintln!("Failed to save session: {}", e);
+        }
+
+        match save {
+            Some((id, record, true)) => {
+                let max_age = record.expires_at - chrono::Utc::now().timestamp();
+                Some(cookie(sign(&id), max_age, config.secure))
+            }
+            Some(_) => None,
+            None if had_session => Some(removal_cookie()),
+            None => None,
+        }
+    }
+}
+
+fn cookie(value: String, max_age: i64, secure: bool) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, value))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        // Lax keeps the cookie on the top-level redirect back from the provider
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(max_age))
+        .build()
+}
+
+fn removal_cookie() -> Cookie<'static> {
+    let mut c = Cookie::build((SESSION_COOKIE, "")).path("/").build();
+    c.make_removal();
+    c
+}
+
+impl LuaUserData for Session {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("user", |_, this| Ok(this.email()));
+        // Claims of the last login (provider, sub, email, name, ...)
+        fields.add_field_method_get("identity", |lua, this| {
+            let state = this.0.lock().unwrap();
+            match state.record.data.get("identity") {
+                Some(v) => lua.to_value(v),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, this, key: String| {
+            let state = this.0.lock().unwrap();
+            match state.record.data.get(&key) {
+                Some(v) => lua.to_value(v),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+
+        // Values must be JSON serializable; setting nil removes the key
+        methods.add_method("set", |lua, this, (key, value): (String, LuaValue)| {
+            let mut state = this.0.lock().unwrap();
+            if value.is_nil() {
+                state.record.data.remove(&key);
+            } else {
+                let json_val: JsonValue = lua.from_value(value)?;
+                state.record.data.insert(key, json_val);
+            }
+            state.dirty = true;
+            Ok(())
+        });
+
+        methods.add_method("destroy", |_, this, ()| {
+            this.destroy();
+            Ok(())
+        });
+
+        // Moves the data to a new id, e.g. after a privilege change
+        methods.add_method("regenerate", |_, this, ()| {
+            let mut state = this.0.lock().unwrap();
+            if let Some(id) = state.id.take() {
+                state.stale.push(id);
+            }
+            state.record.expires_at = 0;
+            state.dirty = true;
+            Ok(())
+        });
+    }
+}
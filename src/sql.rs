@@ -1,5 +1,8 @@
+use crate::sandbox;
 use mlua::prelude::*;
-use rusqlite::{Connection, ToSql, params};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
+use rusqlite::{Connection, OpenFlags, ToSql, params};
 use std::sync::{Arc, Mutex};
 
 pub struct Database {
@@ -415,13 +418,47 @@ impl LuaUserData for Database {
     }
 }
 
+/// Denies statements that touch files other than the open database under the
+/// sandbox: `ATTACH`, `VACUUM INTO` (which attaches its target) and the
+/// directory pragmas.
+fn authorize(ctx: AuthContext<'_>) -> Authorization {
+    match ctx.action {
+        AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+        AuthAction::Pragma { pragma_name, .. }
+            if pragma_name.eq_ignore_ascii_case("temp_store_directory")
+                || pragma_name.eq_ignore_ascii_case("data_store_directory") =>
+        {
+            Authorization::Deny
+        }
+        _ => Authorization::Allow,
+    }
+}
+
 pub fn register(lua: &Lua) -> LuaResult<()> {
     let sqlite3 = lua.create_table()?;
     sqlite3.set(
         "open",
         lua.create_async_function(|_, path: String| async move {
+            if !path.is_empty() && path != ":memory:" {
+                sandbox::check_path(&path, true)?;
+            }
             let conn = tokio::task::spawn_blocking(move || {
-                Connection::open(path).map_err(|e| e.to_string())
+                let conn = if sandbox::enabled() {
+                    // URIs and attached databases could name any file
+                    let flags = OpenFlags::default() - OpenFlags::SQLITE_OPEN_URI;
+                    let conn =
+                        Connection::open_with_flags(path, flags).map_err(|e| e.to_string())?;
+                    conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0)
+                        .map_err(|e| e.to_string())?;
+                    conn.authorizer(Some(authorize));
+                    conn
+                } else {
+                    Connection::open(path).map_err(|e| e.to_string())?
+                };
+                // Other workers may hold the write lock
+                conn.busy_timeout(crate::kv::BUSY_TIMEOUT)
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>(conn)
             })
             .await
             .map_err(|e| LuaError::RuntimeError(e.to_string()))?
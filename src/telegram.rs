@@ -29,8 +29,8 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
     telegram.set(
         "send_message",
         lua.create_async_function(|_, (chat_id, text): (LuaValue, String)| async move {
-            let token = std::env::var("TELEGRAM_BOT_TOKEN")
-                .map_err(|_| LuaError::RuntimeError("TELEGRAM_BOT_TOKEN not set".into()))?;
+            let token = crate::util::env_var("TELEGRAM_BOT_TOKEN")
+                .ok_or_else(|| LuaError::RuntimeError("TELEGRAM_BOT_TOKEN not set".into()))?;
             let chat_id_val: JsonValue = match chat_id {
                 LuaValue::String(s) => JsonValue::String(s.to_str()?.to_string()),
                 LuaValue::Integer(i) => JsonValue::Number(i.into()),
@@ -93,9 +93,9 @@ pub async fn start(
         return None;
     }
 
-    let token = match std::env::var("TELEGRAM_BOT_TOKEN") {
-        Ok(t) => t,
-        Err(_) => {
+    let token = match crate::util::env_var("TELEGRAM_BOT_TOKEN") {
+        Some(t) => t,
+        None => {
             eprintln!("TELEGRAM_BOT_TOKEN not set, skipping telegram bot start");
             return None;
         }
@@ -0,0 +1,302 @@
+-- Test API of `lumen test`. Returns the function that lists the tests
+-- registered by a file, for the runner.
+
+local tests = {}
+local scope = { befores = {}, afters = {} }
+local stubs = {}
+
+function describe(name, fn)
+    local parent = scope
+    scope = { name = name, parent = parent, befores = {}, afters = {} }
+    fn()
+    scope = parent
+end
+
+function it(name, fn, opts)
+    local parts = { name }
+    local s = scope
+    while s.name do
+        table.insert(parts, 1, s.name)
+        s = s.parent
+    end
+    table.insert(tests, {
+        name = table.concat(parts, " > "),
+        fn = fn,
+        scope = scope,
+        timeout = opts and opts.timeout,
+    })
+end
+
+function before_each(fn)
+    table.insert(scope.befores, fn)
+end
+
+function after_each(fn)
+    table.insert(scope.afters, fn)
+end
+
+local function format(value, depth)
+    depth = depth or 0
+    if type(value) == "string" then
+        return string.format("%q", value)
+    end
+    if type(value) ~= "table" or getmetatable(value) then
+        return tostring(value)
+    end
+    if depth > 2 then
+        return "{...}"
+    end
+    local keys = {}
+    for k in pairs(value) do
+        table.insert(keys, k)
+    end
+    table.sort(keys, function(a, b)
+        return tostring(a) < tostring(b)
+    end)
+    local items = {}
+    for _, k in ipairs(keys) do
+        if math.type(k) == "integer" and k >= 1 and k <= #value then
+            table.insert(items, format(value[k], depth + 1))
+        else
+            table.insert(items, tostring(k) .. " = " .. format(value[k], depth + 1))
+        end
+    end
+    return "{ " .. table.concat(items, ", ") .. " }"
+end
+
+local function equal(a, b)
+    if rawequal(a, b) then
+        return true
+    end
+    if type(a) ~= "table" or type(b) ~= "table" then
+        return false
+    end
+    for k, v in pairs(a) do
+        if not equal(v, b[k]) then
+            return false
+        end
+    end
+    for k in pairs(b) do
+        if a[k] == nil then
+            return false
+        end
+    end
+    return true
+end
+
+local function is_mock(value)
+    local mt = type(value) == "table" and getmetatable(value)
+    return mt and mt.__mock == true
+end
+
+-- Matchers return whether the value passes and what was expected.
+local Matchers = {}
+local Expectation = {
+    __index = function(self, key)
+        if key == "never" then
+            return setmetatable({ value = self.value, negated = not self.negated }, getmetatable(self))
+        end
+        local matcher = Matchers[key]
+        if not matcher then
+            return nil
+        end
+        return function(self, ...)
+            local pass, message = matcher(self, ...)
+            if pass == self.negated then
+                -- Level 2 is the line of the test that called the matcher
+                error((self.negated and "expected not: " or "expected: ") .. message, 2)
+            end
+            return self
+        end
+    end,
+}
+
+function expect(value)
+    return setmetatable({ value = value, negated = false }, Expectation)
+end
+
+function Matchers:to_be(expected)
+    return rawequal(self.value, expected),
+        format(self.value) .. " to be " .. format(expected)
+end
+
+function Matchers:to_equal(expected)
+    return equal(self.value, expected),
+        format(self.value) .. " to equal " .. format(expected)
+end
+
+function Matchers:to_be_truthy()
+    return self.value and true or false, format(self.value) .. " to be truthy"
+end
+
+function Matchers:to_be_falsy()
+    return not self.value, format(self.value) .. " to be falsy"
+end
+
+function Matchers:to_be_nil()
+    return self.value == nil, format(self.value) .. " to be nil"
+end
+
+function Matchers:to_be_a(type_name)
+    return type(self.value) == type_name,
+        format(self.value) .. " to be a " .. type_name
+end
+
+function Matchers:to_be_close_to(expected, delta)
+    delta = delta or 1e-6
+    local pass = type(self.value) == "number" and math.abs(self.value - expected) <= delta
+    return pass, format(self.value) .. " to be within " .. delta .. " of " .. expected
+end
+
+function Matchers:to_contain(item)
+    local pass = false
+    if type(self.value) == "string" then
+        pass = self.value:find(item, 1, true) ~= nil
+    elseif type(self.value) == "table" then
+        for _, v in pairs(self.value) do
+            if equal(v, item) then
+                pass = true
+                break
+            end
+        end
+    end
+    return pass, format(self.value) .. " to contain " .. format(item)
+end
+
+function Matchers:to_match(pattern)
+    local pass = type(self.value) == "string" and self.value:match(pattern) ~= nil
+    return pass, format(self.value) .. " to match " .. format(pattern)
+end
+
+function Matchers:to_have_length(length)
+    local ok, actual = pcall(function()
+        return #self.value
+    end)
+    return ok and actual == length,
+        format(self.value) .. " to have length " .. length
+end
+
+-- `expect(fn):to_fail("message")` calls fn and expects it to raise an error
+-- containing the text, if given.
+function Matchers:to_fail(text)
+    local ok, err = pcall(self.value)
+    local pass = not ok and (text == nil or tostring(err):find(text, 1, true) ~= nil)
+    local message = "function to fail"
+    if text then
+        message = message .. " with " .. format(text)
+    end
+    if not ok then
+        message = message .. ", got " .. format(tostring(err))
+    end
+    return pass, message
+end
+
+function Matchers:to_have_been_called(times)
+    if not is_mock(self.value) then
+        error("expect(...):to_have_been_called() needs a mock", 3)
+    end
+    local count = #self.value.calls
+    local pass = times == nil and count > 0 or count == times
+    return pass, "mock to have been called " ..
+        (times and times .. " time(s)" or "") .. ", got " .. count .. " call(s)"
+end
+
+function Matchers:to_have_been_called_with(...)
+    if not is_mock(self.value) then
+        error("expect(...):to_have_been_called_with() needs a mock", 3)
+    end
+    local args = table.pack(...)
+    local pass = false
+    for _, call in ipairs(self.value.calls) do
+        if equal(call, args) then
+            pass = true
+            break
+        end
+    end
+    return pass, "mock to have been called with " .. format(args)
+end
+
+-- A callable table that records its calls in `calls` and runs `impl`.
+function mock(impl)
+    local m = { calls = {} }
+    function m:returns(...)
+        local values = table.pack(...)
+        impl = function()
+            return table.unpack(values, 1, values.n)
+        end
+        return self
+    end
+    return setmetatable(m, {
+        __mock = true,
+        __call = function(self, ...)
+            table.insert(self.calls, table.pack(...))
+            if impl then
+                return impl(...)
+            end
+        end,
+    })
+end
+
+-- Replaces `tbl[key]` with a mock until the end of the test.
+function stub(tbl, key, impl)
+    table.insert(stubs, { tbl = tbl, key = key, original = tbl[key] })
+    local m = mock(impl)
+    tbl[key] = m
+    return m
+end
+
+local function restore_stubs()
+    for i = #stubs, 1, -1 do
+        local s = stubs[i]
+        s.tbl[s.key] = s.original
+    end
+    stubs = {}
+end
+
+-- Runs the hooks of the enclosing `describe` blocks around the test and
+-- returns the error message, or nil if it passed.
+local function run(test)
+    local chain = {}
+    local s = test.scope
+    while s do
+        table.insert(chain, 1, s)
+        s = s.parent
+    end
+    local ok, err = true, nil
+    for _, s in ipairs(chain) do
+        for _, fn in ipairs(s.befores) do
+            if ok then
+                ok, err = pcall(fn)
+            end
+        end
+    end
+    if ok then
+        ok, err = pcall(test.fn)
+    end
+    for i = #chain, 1, -1 do
+        for _, fn in ipairs(chain[i].afters) do
+            local after_ok, after_err = pcall(fn)
+            if ok and not after_ok then
+                ok, err = false, after_err
+            end
+        end
+    end
+    restore_stubs()
+    if not ok then
+        return tostring(err)
+    end
+end
+
+return function()
+    local list = {}
+    for _, test in ipairs(tests) do
+        table.insert(list, {
+            name = test.name,
+            timeout = test.timeout,
+            run = function()
+                return run(test)
+            end,
+        })
+    end
+    return list, restore_stubs
+end
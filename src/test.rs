@@ -0,0 +1,606 @@
+use crate::cron::Schedule;
+use crate::session::Session;
+use crate::types::AppState;
+use crate::{engine, exit, web_server};
+use axum::Router;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use chrono::{Local, TimeZone};
+use mlua::prelude::*;
+use mlua::serde::LuaSerdeExt;
+use serde_json::{Value as JsonValue, json};
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tower::ServiceExt;
+
+/// Real time a test may take unless it sets `timeout`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Report format of `lumen test`.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Text,
+    Tap,
+    Junit,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "tap" => Ok(Format::Tap),
+            "junit" => Ok(Format::Junit),
+            _ => Err(format!("Invalid test format: {}", s)),
+        }
+    }
+}
+
+pub struct Options {
+    /// Directory to search for `*_test.lua` files, or a single file.
+    pub path: Option<String>,
+    pub format: Format,
+    /// Where the report goes instead of stdout.
+    pub output: Option<PathBuf>,
+}
+
+struct Outcome {
+    file: String,
+    name: String,
+    duration: Duration,
+    failure: Option<String>,
+}
+
+/// `lumen test`: runs every test file in its own Lua state and reports the
+/// results. Returns the exit code.
+pub async fn run(options: Options) -> i32 {
+    let root = PathBuf::from(options.path.as_deref().unwrap_or("."));
+    let files = match discover(&root) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", root.display(), e);
+            return 1;
+        }
+    };
+    if files.is_empty() {
+        eprintln!("No *_test.lua files found in {}", root.display());
+        return 1;
+    }
+
+    crate::oauth::allow_mock();
+
+    // `wait` and other timers run on a paused clock, which skips ahead
+    // whenever every task is waiting for one
+    tokio::time::pause();
+    let started = Instant::now();
+    let mut outcomes = Vec::new();
+    let mut report = String::new();
+    for (i, file) in files.iter().enumerate() {
+        let name = file.strip_prefix(".").unwrap_or(file).display().to_string();
+        // Each file gets an empty database instead of the app's `server.db`
+        let db = std::env::temp_dir().join(format!("lumen-test-{}-{}.db", std::process::id(), i));
+        crate::session::set_db_path(Some(db.display().to_string()));
+        let file_outcomes = run_file(file, &name).await;
+        std::fs::remove_file(&db).ok();
+        if let Format::Text = options.format {
+            let text = text_report(&name, &file_outcomes);
+            match options.output {
+                Some(_) => report.push_str(&text),
+                None => print!("{}", text),
+            }
+        }
+        outcomes.extend(file_outcomes);
+    }
+
+    let failed = outcomes.iter().filter(|o| o.failure.is_some()).count();
+    match options.format {
+        Format::Text => report.push_str(&format!(
+            "\n{} passed, {} failed ({:.2} s)\n",
+            outcomes.len() - failed,
+            failed,
+            started.elapsed().as_secs_f64()
+        )),
+        Format::Tap => report = tap_report(&outcomes),
+        Format::Junit => report = junit_report(&outcomes, started.elapsed()),
+    }
+    match &options.output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, &report) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+                return 1;
+            }
+            println!("{} passed, {} failed", outcomes.len() - failed, failed);
+        }
+        None => print!("{}", report),
+    }
+    if failed > 0 { 1 } else { 0 }
+}
+
+/// `root` itself if it is a file, otherwise the `*_test.lua` files below it,
+/// skipping hidden directories and `target`.
+fn discover(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if root.is_file() {
+        return Ok(vec![root.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if path.is_dir() {
+                if !name.starts_with('.') && name != "target" {
+                    dirs.push(path);
+                }
+            } else if name.ends_with("_test.lua") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+async fn run_file(file: &Path, name: &str) -> Vec<Outcome> {
+    let failed = |failure: String| {
+        vec![Outcome {
+            file: name.to_string(),
+            name: "(load)".to_string(),
+            duration: Duration::ZERO,
+            failure: Some(failure),
+        }]
+    };
+    let content = match std::fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) => return failed(e.to_string()),
+    };
+    let (exit_tx, _exit_rx) = mpsc::unbounded_channel();
+    let (lua, app_state) = match crate::new_state(None, exit_tx) {
+        Ok(state) => state,
+        Err(e) => return failed(e.to_string()),
+    };
+    let router = Rc::new(RefCell::new(None));
+    let script_dir = file.parent().unwrap_or(Path::new("."));
+    let loaded = async {
+        let collect = install(&lua, &app_state, &router, script_dir)?;
+        lua.load(&content)
+            .set_name(format!("@{}", name))
+            .call_async::<()>(())
+            .await?;
+        let (tests, restore): (Vec<LuaTable>, LuaFunction) = collect.call(())?;
+        let handlers = engine::Handlers::load(&lua, &app_state.lock().unwrap())?;
+        Ok::<_, LuaError>((tests, restore, handlers))
+    };
+    let (tests, restore, handlers) = match loaded.await {
+        Ok(loaded) => loaded,
+        Err(e) => return failed(e.to_string()),
+    };
+
+    // Routes registered by the file are served in process, by the same
+    // router and engine as with `lumen run`
+    let (tx, req_rx) = mpsc::channel(100);
+    app_state.lock().unwrap().engine_tx = Some(tx.downgrade());
+    *router.borrow_mut() = Some(web_server::build_router(&app_state, &tx, script_dir));
+    drop(tx);
+    let (stop, stop_rx) = oneshot::channel();
+    let engine = engine::run(lua.clone(), handlers, req_rx, stop_rx, None, Arc::default());
+    tokio::pin!(engine);
+    let tests = run_tests(name, tests, &restore);
+    tokio::pin!(tests);
+    let mut engine_done = false;
+    let outcomes = tokio::select! {
+        outcomes = &mut tests => outcomes,
+        _ = &mut engine => {
+            engine_done = true;
+            tests.await
+        }
+    };
+    *router.borrow_mut() = None;
+    let _ = stop.send(engine::Stop::Retire);
+    if !engine_done {
+        engine.await;
+    }
+
+    let hooks = exit::shutdown_hooks(&lua, &app_state.lock().unwrap());
+    exit::run_shutdown_hooks(hooks.unwrap_or_default()).await;
+    outcomes
+}
+
+async fn run_tests(file: &str, tests: Vec<LuaTable>, restore: &LuaFunction) -> Vec<Outcome> {
+    let mut outcomes = Vec::new();
+    for test in tests {
+        let name: String = test.get("name").unwrap_or_default();
+        let timeout = test
+            .get::<Option<f64>>("timeout")
+            .ok()
+            .flatten()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let started = Instant::now();
+        let failure = match test.get::<LuaFunction>("run") {
+            Ok(run) => match real_timeout(timeout, run.call_async::<Option<String>>(())).await {
+                Some(Ok(failure)) => failure,
+                Some(Err(e)) => Some(match exit::exit_code(&e) {
+                    Some(code) => format!("script called exit({})", code),
+                    None => e.to_string(),
+                }),
+                None => {
+                    let _ = restore.call::<()>(());
+                    Some(format!("timed out after {} s", timeout.as_secs_f64()))
+                }
+            },
+            Err(e) => Some(e.to_string()),
+        };
+        outcomes.push(Outcome {
+            file: file.to_string(),
+            name,
+            duration: started.elapsed(),
+            failure,
+        });
+    }
+    outcomes
+}
+
+/// Like `tokio::time::timeout`, but in real time, since the tokio clock is
+/// paused during tests.
+async fn real_timeout<F: Future>(limit: Duration, fut: F) -> Option<F::Output> {
+    let (fire_tx, fire_rx) = oneshot::channel::<()>();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(limit) {
+            let _ = fire_tx.send(());
+        }
+    });
+    let out = tokio::select! {
+        out = fut => Some(out),
+        _ = fire_rx => None,
+    };
+    drop(done_tx);
+    out
+}
+
+/// Wall time of a test file, which starts at the real time and moves with
+/// the paused tokio clock.
+struct Clock {
+    epoch: f64,
+    at: tokio::time::Instant,
+}
+
+impl Clock {
+    fn new(epoch: f64) -> Self {
+        Clock {
+            epoch,
+            at: tokio::time::Instant::now(),
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.epoch + self.at.elapsed().as_secs_f64()
+    }
+}
+
+fn real_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// When `cron` fires next after `time`, both in seconds since the epoch.
+fn next_fire(schedule: &Schedule, time: f64) -> Option<f64> {
+    let nanos = (time.fract() * 1e9) as u32;
+    let from = Local.timestamp_opt(time.floor() as i64, nanos).single()?;
+    let next = schedule.next_after(from)?;
+    Some(next.timestamp() as f64 + next.timestamp_subsec_nanos() as f64 / 1e9)
+}
+
+/// `clock.advance`: moves the clock forward, running the cron jobs that come
+/// due on the way in order.
+async fn advance(
+    lua: &Lua,
+    app_state: &Arc<Mutex<AppState>>,
+    clock: &RefCell<Clock>,
+    seconds: f64,
+) -> LuaResult<()> {
+    let step = |from: f64, to: f64| Duration::try_from_secs_f64(to - from).unwrap_or_default();
+    if seconds.is_nan() || seconds < 0.0 {
+        return Err(LuaError::RuntimeError(
+            "clock.advance needs a number of seconds >= 0".into(),
+        ));
+    }
+    let jobs: Vec<(Schedule, LuaFunction)> = {
+        let state = app_state.lock().unwrap();
+        state
+            .cron_jobs
+            .iter()
+            .filter(|job| !job.paused && !job.cancelled && job.schedule.expression().is_some())
+            .map(|job| Ok((job.schedule.clone(), lua.registry_value(&job.callback_key)?)))
+            .collect::<LuaResult<_>>()?
+    };
+
+    let mut cursor = clock.borrow().now();
+    let target = cursor + seconds;
+    loop {
+        let next = jobs
+            .iter()
+            .filter_map(|(schedule, _)| next_fire(schedule, cursor))
+            .min_by(f64::total_cmp)
+            .filter(|&time| time <= target);
+        let Some(time) = next else {
+            let now = clock.borrow().now();
+            tokio::time::advance(step(now, target)).await;
+            return Ok(());
+        };
+        let now = clock.borrow().now();
+        tokio::time::advance(step(now, time)).await;
+        for (schedule, func) in &jobs {
+            if next_fire(schedule, cursor) == Some(time) {
+                func.call_async::<()>(()).await?;
+            }
+        }
+        cursor = time;
+    }
+}
+
+/// `request`: sends a request through the router of the file without
+/// binding a port.
+async fn request(
+    lua: &Lua,
+    router: Router,
+    app_state: &Arc<Mutex<AppState>>,
+    method: &str,
+    path: &str,
+    opts: Option<LuaTable>,
+) -> LuaResult<LuaTable> {
+    let mut builder = axum::http::Request::builder()
+        .method(method.to_uppercase().as_str())
+        .uri(path)
+        .header("host", "localhost");
+    let mut body = Vec::new();
+    let mut cookies = Vec::new();
+    if let Some(opts) = &opts {
+        if let Some(headers) = opts.get::<Option<LuaTable>>("headers")? {
+            for pair in headers.pairs::<String, String>() {
+                let (name, value) = pair?;
+                builder = builder.header(name, value);
+            }
+        }
+        if let Some(value) = opts.get::<Option<LuaValue>>("json")? {
+            let value: JsonValue = lua.from_value(value)?;
+            body = value.to_string().into_bytes();
+            builder = builder.header("content-type", "application/json");
+        } else if let Some(form) = opts.get::<Option<LuaTable>>("form")? {
+            let mut encoder = url::form_urlencoded::Serializer::new(String::new());
+            for pair in form.pairs::<String, String>() {
+                let (name, value) = pair?;
+                encoder.append_pair(&name, &value);
+            }
+            body = encoder.finish().into_bytes();
+            builder = builder.header("content-type", "application/x-www-form-urlencoded");
+        } else if let Some(raw) = opts.get::<Option<LuaString>>("body")? {
+            body = raw.as_bytes().to_vec();
+        }
+        if let Some(values) = opts.get::<Option<LuaTable>>("cookies")? {
+            for pair in values.pairs::<String, String>() {
+                let (name, value) = pair?;
+                cookies.push(format!("{}={}", name, value));
+            }
+        }
+        // A session that is logged in as `user` by the login provider
+        if let Some(user) = opts.get::<Option<String>>("user")? {
+            let config = app_state.lock().unwrap().session_config.clone();
+            let provider = crate::oauth::login_provider(app_state);
+            let session = Session::default();
+            session.login(user.clone(), json!({ "provider": provider, "email": user }));
+            if let Some(cookie) = session.commit(&config).await {
+                cookies.push(format!("{}={}", cookie.name(), cookie.value()));
+            }
+        }
+    }
+    if !cookies.is_empty() {
+        builder = builder.header("cookie", cookies.join("; "));
+    }
+    let mut req = builder
+        .body(Body::from(body))
+        .map_err(|e| LuaError::RuntimeError(format!("Invalid request: {}", e)))?;
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+
+    let res = match router.oneshot(req).await {
+        Ok(res) => res,
+        Err(never) => match never {},
+    };
+    let (parts, body) = res.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| LuaError::RuntimeError(format!("Failed to read response: {}", e)))?;
+
+    let result = lua.create_table()?;
+    result.set("status", parts.status.as_u16())?;
+    let headers = lua.create_table()?;
+    for (name, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        let value = match headers.get::<Option<String>>(name.as_str())? {
+            Some(existing) => format!("{}, {}", existing, value),
+            None => value,
+        };
+        headers.set(name.as_str(), value)?;
+    }
+    result.set("headers", headers)?;
+    let is_json = parts
+        .headers
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+    if is_json && let Ok(value) = serde_json::from_slice::<JsonValue>(&bytes) {
+        result.set("json", lua.to_value(&value)?)?;
+    }
+    result.set("body", lua.create_string(&bytes)?)?;
+    Ok(result)
+}
+
+/// Defines the test API in `lua` and returns the function that lists the
+/// registered tests.
+fn install(
+    lua: &Lua,
+    app_state: &Arc<Mutex<AppState>>,
+    router: &Rc<RefCell<Option<Router>>>,
+    script_dir: &Path,
+) -> LuaResult<LuaFunction> {
+    // Modules next to the test file, such as the app under test
+    let package: LuaTable = lua.globals().get("package")?;
+    let path: String = package.get("path")?;
+    package.set("path", format!("{}/?.lua;{}", script_dir.display(), path))?;
+
+    let clock = Rc::new(RefCell::new(Clock::new(real_now())));
+    let now_clock = clock.clone();
+    lua.globals().set(
+        "now",
+        lua.create_function(move |_, ()| Ok(now_clock.borrow().now()))?,
+    )?;
+    let clock_table = lua.create_table()?;
+    let advance_clock = clock.clone();
+    let advance_state = app_state.clone();
+    clock_table.set(
+        "advance",
+        lua.create_async_function(move |lua, seconds: f64| {
+            let clock = advance_clock.clone();
+            let app_state = advance_state.clone();
+            async move { advance(&lua, &app_state, &clock, seconds).await }
+        })?,
+    )?;
+    clock_table.set(
+        "set",
+        lua.create_function(move |_, epoch: f64| {
+            *clock.borrow_mut() = Clock::new(epoch);
+            Ok(())
+        })?,
+    )?;
+    lua.globals().set("clock", clock_table)?;
+
+    let router = router.clone();
+    let app_state = app_state.clone();
+    lua.globals().set(
+        "request",
+        lua.create_async_function(
+            move |lua, (method, path, opts): (String, String, Option<LuaTable>)| {
+                let router = router.borrow().clone();
+                let app_state = app_state.clone();
+                async move {
+                    let router = router.ok_or_else(|| {
+                        LuaError::RuntimeError("request() can only be used in tests".into())
+                    })?;
+                    request(&lua, router, &app_state, &method, &path, opts).await
+                }
+            },
+        )?,
+    )?;
+
+    lua.load(include_str!("test.lua"))
+        .set_name("=test")
+        .call::<LuaFunction>(())
+}
+
+fn text_report(file: &str, outcomes: &[Outcome]) -> String {
+    let mut out = format!("{}\n", file);
+    for outcome in outcomes {
+        let status = if outcome.failure.is_some() {
+            "FAIL"
+        } else {
+            "ok"
+        };
+        out.push_str(&format!(
+            "  {:<4} {} ({} ms)\n",
+            status,
+            outcome.name,
+            outcome.duration.as_millis()
+        ));
+        if let Some(failure) = &outcome.failure {
+            for line in failure.lines() {
+                out.push_str(&format!("       {}\n", line));
+            }
+        }
+    }
+    out
+}
+
+fn tap_report(outcomes: &[Outcome]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", outcomes.len());
+    for (i, outcome) in outcomes.iter().enumerate() {
+        let status = if outcome.failure.is_some() {
+            "not ok"
+        } else {
+            "ok"
+        };
+        out.push_str(&format!(
+            "{} {} - {}: {}\n",
+            status,
+            i + 1,
+            outcome.file,
+            outcome.name.replace('#', "\\#")
+        ));
+        if let Some(failure) = &outcome.failure {
+            out.push_str("  ---\n  message: |\n");
+            for line in failure.lines() {
+                out.push_str(&format!("    {}\n", line));
+            }
+            out.push_str("  ...\n");
+        }
+    }
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn junit_report(outcomes: &[Outcome], elapsed: Duration) -> String {
+    let failures = outcomes.iter().filter(|o| o.failure.is_some()).count();
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"lumen\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        outcomes.len(),
+        failures,
+        elapsed.as_secs_f64()
+    );
+    let mut files: Vec<&str> = outcomes.iter().map(|o| o.file.as_str()).collect();
+    files.dedup();
+    for file in files {
+        let suite: Vec<&Outcome> = outcomes.iter().filter(|o| o.file == file).collect();
+        out.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(file),
+            suite.len(),
+            suite.iter().filter(|o| o.failure.is_some()).count(),
+            suite.iter().map(|o| o.duration.as_secs_f64()).sum::<f64>()
+        ));
+        for outcome in suite {
+            let attrs = format!(
+                "name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                xml_escape(&outcome.name),
+                xml_escape(file),
+                outcome.duration.as_secs_f64()
+            );
+            match &outcome.failure {
+                Some(failure) => out.push_str(&format!(
+                    "    <testcase {}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    attrs,
+                    xml_escape(failure.lines().next().unwrap_or_default()),
+                    xml_escape(failure)
+                )),
+                None => out.push_str(&format!("    <testcase {}/>\n", attrs)),
+            }
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
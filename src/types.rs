@@ -1,13 +1,51 @@
+use crate::session::{Session, SessionConfig};
+use crate::upload::{UploadConfig, Uploads};
+use crate::websocket::{WsClient, WsConnection};
 use mlua::RegistryKey;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::oneshot as tokio_oneshot;
 
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub params: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub remote_addr: Option<String>,
+    /// Parsed `multipart/form-data` body, if any.
+    pub uploads: Option<Uploads>,
+    pub session: Option<Session>,
+}
+
+#[derive(Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Chunks of a streaming response body, produced by a Lua coroutine.
+pub type BodyStream = tokio::sync::mpsc::Receiver<Vec<u8>>;
+
 pub struct RestRequest {
     pub callback_id: usize,
-    pub params: HashMap<String, String>,
-    pub response_tx: tokio_oneshot::Sender<Result<JsonValue, String>>,
+    pub request: HttpRequest,
+    pub response_tx: tokio_oneshot::Sender<Result<(HttpResponse, Option<BodyStream>), String>>,
+}
+
+pub struct WebSocketRequest {
+    pub callback_id: usize,
+    pub request: HttpRequest,
+    /// Answered with `None` once the middleware lets the upgrade through, or
+    /// with the response it returned instead.
+    pub accept_tx: tokio_oneshot::Sender<Result<Option<HttpResponse>, String>>,
+    /// The connection, once the upgrade is done.
+    pub connection_rx: tokio_oneshot::Receiver<WsConnection>,
 }
 
 pub struct ProxyAuthRequest {
@@ -17,12 +55,50 @@ pub struct ProxyAuthRequest {
     pub response_tx: tokio_oneshot::Sender<bool>,
 }
 
+/// Code typed into the admin console.
+pub struct EvalRequest {
+    pub code: String,
+    /// `None` if the code is an unfinished chunk.
+    pub response_tx: tokio_oneshot::Sender<Option<Result<String, String>>>,
+}
+
+/// One run of a cron job.
+pub struct CronRun {
+    /// The index of a job registered while loading, or the key of one added
+    /// at runtime, see `cron::static_index`.
+    pub key: u64,
+    pub name: String,
+    /// The job won't run again.
+    pub once: bool,
+    /// The run is cancelled after this long.
+    pub timeout: Option<Duration>,
+    /// Tells the scheduler how the run went. Dropped if it was cancelled.
+    pub done: tokio_oneshot::Sender<Result<(), String>>,
+}
+
+/// One attempt at a job from the queue.
+pub struct JobRun {
+    /// The index of the job's definition.
+    pub index: usize,
+    pub id: i64,
+    pub name: String,
+    pub payload: JsonValue,
+    /// Counts from 1.
+    pub attempt: u32,
+    /// The attempt fails after this long.
+    pub timeout: Option<Duration>,
+    /// Tells the queue how the attempt went. Dropped if it was cancelled.
+    pub done: tokio_oneshot::Sender<Result<(), String>>,
+}
+
 pub enum EngineRequest {
-    Rest(RestRequest),
-    Cron(usize),
+    Rest(Box<RestRequest>),
+    WebSocket(Box<WebSocketRequest>),
+    Cron(CronRun),
+    Job(Box<JobRun>),
     TelegramUpdate(JsonValue),
     ProxyAuth(ProxyAuthRequest),
-    Exit(i32),
+    Eval(EvalRequest),
 }
 
 pub struct RestRouteInfo {
@@ -30,15 +106,53 @@ pub struct RestRouteInfo {
     pub method: String,
     pub callback_id: usize,
     pub callback_key: RegistryKey,
+    /// Anonymous visitors are sent to the login first.
+    pub require_login: bool,
+}
+
+pub struct WebSocketRouteInfo {
+    pub path: String,
+    pub callback_id: usize,
+    pub callback_key: RegistryKey,
+    /// Anonymous clients are refused.
+    pub require_login: bool,
+}
+
+pub struct MiddlewareInfo {
+    pub prefix: String,
+    pub callback_key: RegistryKey,
 }
 
 pub struct CronJobInfo {
-    pub expression: String,
+    /// Keys the job's state in `server.db`.
+    pub name: String,
+    pub schedule: crate::cron::Schedule,
     pub callback_id: usize,
     pub callback_key: RegistryKey,
+    pub overlap: crate::cron::Overlap,
+    pub timeout: Option<Duration>,
+    pub catch_up: crate::cron::CatchUp,
+    /// Most a run is delayed at random.
+    pub jitter: Option<Duration>,
+    /// Set by the job's handle while the script loads.
+    pub paused: bool,
+    pub cancelled: bool,
 }
 
-#[derive(Clone)]
+/// A kind of job, defined with `jobs.define`.
+pub struct JobDefInfo {
+    pub name: String,
+    pub callback_id: usize,
+    pub callback_key: RegistryKey,
+    /// Most attempts running at once, across the workers.
+    pub concurrency: usize,
+    /// Defaults for `jobs.enqueue`.
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Clone, PartialEq)]
 pub enum ServerConfig {
     Http(String),
     Https(String, String, String),
@@ -55,12 +169,60 @@ pub struct ReverseProxyInfo {
 
 pub struct AppState {
     pub routes: Vec<RestRouteInfo>,
+    pub middlewares: Vec<MiddlewareInfo>,
+    pub websocket_routes: Vec<WebSocketRouteInfo>,
+    pub websocket_clients: HashMap<String, Vec<WsClient>>,
     pub static_routes: Vec<(String, String)>,
+    pub upload_config: UploadConfig,
+    pub session_config: SessionConfig,
+    pub oauth_providers: HashMap<String, Arc<crate::oauth::Provider>>,
+    /// Provider used for `require_login` routes and proxies, "google" if unset.
+    pub login_provider: Option<String>,
     pub cron_jobs: Vec<CronJobInfo>,
+    pub job_defs: Vec<JobDefInfo>,
     pub reverse_proxies: Vec<ReverseProxyInfo>,
     pub telegram_handler: Option<RegistryKey>,
     pub config: Option<ServerConfig>,
     pub gmail_state: Option<std::sync::Arc<crate::gmail::GmailState>>,
     pub drive_state: Option<std::sync::Arc<crate::gmail::GmailState>>,
-    pub engine_tx: Option<tokio::sync::mpsc::Sender<EngineRequest>>,
+    /// Weak so that a retired version's engine can see its channel close.
+    pub engine_tx: Option<tokio::sync::mpsc::WeakSender<EngineRequest>>,
+    /// Scheduler of the running version, which takes the cron jobs and
+    /// timers added at runtime. `None` while the script loads.
+    pub cron_tx: Option<tokio::sync::mpsc::UnboundedSender<crate::cron::Command>>,
+    /// Exit codes passed to `exit()`, handled by the main loop.
+    pub exit_tx: tokio::sync::mpsc::UnboundedSender<i32>,
+    pub shutdown_hooks: Vec<RegistryKey>,
+    pub debugger: Option<crate::debugger::DebuggerConfig>,
+}
+
+impl AppState {
+    pub fn new(
+        gmail_state: Option<Arc<crate::gmail::GmailState>>,
+        exit_tx: tokio::sync::mpsc::UnboundedSender<i32>,
+    ) -> Self {
+        AppState {
+            routes: Vec::new(),
+            middlewares: Vec::new(),
+            websocket_routes: Vec::new(),
+            websocket_clients: HashMap::new(),
+            static_routes: Vec::new(),
+            upload_config: UploadConfig::default(),
+            session_config: SessionConfig::default(),
+            oauth_providers: HashMap::new(),
+            login_provider: None,
+            cron_jobs: Vec::new(),
+            job_defs: Vec::new(),
+            reverse_proxies: Vec::new(),
+            telegram_handler: None,
+            config: None,
+            gmail_state: gmail_state.clone(),
+            drive_state: gmail_state,
+            engine_tx: None,
+            cron_tx: None,
+            exit_tx,
+            shutdown_hooks: Vec::new(),
+            debugger: None,
+        }
+    }
 }
@@ -0,0 +1,179 @@
+use crate::file_obj::{FileObject, detect_mime};
+use axum::body::Body;
+use axum::http::StatusCode;
+use mlua::prelude::*;
+use multer::{Constraints, Multipart, SizeLimit};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Limits applied to `multipart/form-data` requests.
+#[derive(Clone)]
+pub struct UploadConfig {
+    /// Maximum size of the whole request body.
+    pub max_size: u64,
+    /// Maximum size of a single field or file.
+    pub max_file_size: u64,
+    /// Files larger than this are written to `temp_dir` instead of memory.
+    pub memory_limit: usize,
+    pub temp_dir: PathBuf,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            max_size: 64 * 1024 * 1024,
+            max_file_size: 64 * 1024 * 1024,
+            memory_limit: 1024 * 1024,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+impl UploadConfig {
+    /// Builds a config from the options table of `srv:uploads`.
+    pub fn from_lua(opts: &LuaTable) -> LuaResult<Self> {
+        let mut config = UploadConfig::default();
+        if let Some(v) = opts.get::<Option<u64>>("max_size")? {
+            config.max_size = v;
+        }
+        if let Some(v) = opts.get::<Option<u64>>("max_file_size")? {
+            config.max_file_size = v;
+        }
+        if let Some(v) = opts.get::<Option<usize>>("memory_limit")? {
+            config.memory_limit = v;
+        }
+        if let Some(v) = opts.get::<Option<String>>("temp_dir")? {
+            config.temp_dir = PathBuf::from(v);
+        }
+        Ok(config)
+    }
+}
+
+/// Fields and files of a parsed multipart request. Files spilled to disk live
+/// in a per-request directory that is removed once the request is dropped.
+#[derive(Default)]
+pub struct Uploads {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<(String, FileObject)>,
+    dir: Option<PathBuf>,
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+impl Uploads {
+    fn temp_dir(&mut self, config: &UploadConfig) -> std::io::Result<PathBuf> {
+        if let Some(dir) = &self.dir {
+            return Ok(dir.clone());
+        }
+        let dir = config
+            .temp_dir
+            .join(format!("lumen-upload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        self.dir = Some(dir.clone());
+        Ok(dir)
+    }
+}
+
+fn upload_error(e: multer::Error) -> (StatusCode, String) {
+    match e {
+        multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Upload too large".to_string(),
+        ),
+        e => (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid multipart body: {}", e),
+        ),
+    }
+}
+
+fn io_error(e: std::io::Error) -> (StatusCode, String) {
+    eprintln!("Failed to store upload: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to store upload".to_string(),
+    )
+}
+
+/// Client supplied file names are reduced to their last component.
+fn sanitize_name(name: &str) -> String {
+    Path::new(&name.replace('\\', "/"))
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("upload")
+        .to_string()
+}
+
+/// Parses a `multipart/form-data` body. Text fields are kept in memory, file
+/// parts are buffered until they exceed `memory_limit` and then streamed to a
+/// temporary file.
+pub async fn read_multipart(
+    body: Body,
+    boundary: String,
+    config: &UploadConfig,
+) -> Result<Uploads, (StatusCode, String)> {
+    let constraints = Constraints::new().size_limit(
+        SizeLimit::new()
+            .whole_stream(config.max_size)
+            .per_field(config.max_file_size),
+    );
+    let mut multipart = Multipart::with_constraints(body.into_data_stream(), boundary, constraints);
+    let mut uploads = Uploads::default();
+
+    while let Some(mut field) = multipart.next_field().await.map_err(upload_error)? {
+        let name = field.name().unwrap_or_default().to_string();
+        let Some(file_name) = field.file_name().map(sanitize_name) else {
+            let text = field.text().await.map_err(upload_error)?;
+            uploads.fields.push((name, text));
+            continue;
+        };
+        let mime_type = field
+            .content_type()
+            .map(|m| m.to_string())
+            .unwrap_or_else(|| detect_mime(&file_name));
+
+        let mut buffer = Vec::new();
+        let mut file: Option<(tokio::fs::File, PathBuf)> = None;
+        while let Some(chunk) = field.chunk().await.map_err(upload_error)? {
+            if let Some((f, _)) = file.as_mut() {
+                f.write_all(&chunk).await.map_err(io_error)?;
+                continue;
+            }
+            buffer.extend_from_slice(&chunk);
+            if buffer.len() > config.memory_limit {
+                let dir = uploads.temp_dir(config).map_err(io_error)?;
+                let path = dir.join(format!("{}-{}", uploads.files.len(), file_name));
+                let mut f = tokio::fs::File::create(&path).await.map_err(io_error)?;
+                f.write_all(&buffer).await.map_err(io_error)?;
+                buffer = Vec::new();
+                file = Some((f, path));
+            }
+        }
+
+        let (path, blob) = match file {
+            Some((mut f, path)) => {
+                f.flush().await.map_err(io_error)?;
+                (Some(path.to_string_lossy().to_string()), None)
+            }
+            None => (None, Some(buffer)),
+        };
+        uploads.files.push((
+            name,
+            FileObject {
+                id: None,
+                name: file_name,
+                mime_type: Some(mime_type),
+                path,
+                blob,
+                downloader: None,
+            },
+        ));
+    }
+    Ok(uploads)
+}
@@ -1,14 +1,50 @@
+use crate::sandbox;
 use hmac::{Hmac, Mac};
 use mlua::prelude::*;
 use sha2::Sha256;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock};
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// 244 random bits, hex encoded.
+pub fn random_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Values from secrets files and the file each came from, so reloading a
+/// changed file can update its own values without overriding others. They
+/// are kept here rather than in the environment, which can't be changed
+/// safely while other threads may read it.
+static SECRETS: LazyLock<RwLock<HashMap<String, (String, PathBuf)>>> =
+    LazyLock::new(Default::default);
+
+/// An environment variable, or else a value from the secrets files.
+pub fn env_var(key: &str) -> Option<String> {
+    env::var(key).ok().or_else(|| {
+        let secrets = SECRETS.read().unwrap();
+        secrets.get(key).map(|(value, _)| value.clone())
+    })
+}
+
+/// Values from the secrets files that are not in the environment.
+fn secret_vars() -> Vec<(String, String)> {
+    let secrets = SECRETS.read().unwrap();
+    secrets
+        .iter()
+        .map(|(key, (value, _))| (key.clone(), value.clone()))
+        .collect()
+}
+
 pub fn load_secrets_from_path(path: &Path) {
-    println!("Loading secrets from {:?}", path);
+    eprintln!("Loading secrets from {:?}", path);
     if let Ok(content) = fs::read_to_string(path) {
         for line in content.lines() {
             let line = line.trim();
@@ -27,15 +63,17 @@ pub fn load_secrets_from_path(path: &Path) {
                     value = &value[1..value.len() - 1];
                 }
 
-                // Only set if not already set in the actual environment
-                if env::var(key).is_err() {
-                    // Handle escaped newlines (common in private keys)
-                    let val_string = value.replace("\\n", "\n");
-                    // SAFETY: This is called at the beginning of main, or via Lua which is single-threaded in our engine.
-                    unsafe {
-                        env::set_var(key, val_string);
-                    }
+                // The actual environment and files loaded earlier win
+                if env::var_os(key).is_some() {
+                    continue;
                 }
+                let mut secrets = SECRETS.write().unwrap();
+                if secrets.get(key).is_some_and(|(_, p)| p != path) {
+                    continue;
+                }
+                // Handle escaped newlines (common in private keys)
+                let val_string = value.replace("\\n", "\n");
+                secrets.insert(key.to_string(), (val_string, path.to_path_buf()));
             }
         }
     }
@@ -134,10 +172,27 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
     )?;
     lua.globals().set("url", url)?;
 
+    // Values from secrets files are not in the environment
+    let os: LuaTable = lua.globals().get("os")?;
+    os.set(
+        "getenv",
+        lua.create_function(|_, name: String| Ok(env_var(&name)))?,
+    )?;
+
     let util = lua.create_table()?;
     util.set(
         "load_secrets",
-        lua.create_function(|_, path: String| {
+        lua.create_function(|lua, path: String| {
+            sandbox::check_path(&path, false)?;
+            // The main state runs the same script and has loaded them already
+            if lua
+                .globals()
+                .get::<Option<i64>>("LUMEN_WORKER")?
+                .unwrap_or(0)
+                != 0
+            {
+                return Ok(());
+            }
             load_secrets_from_path(Path::new(&path));
             Ok(())
         })?,
@@ -151,19 +206,38 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
                     return Err(LuaError::RuntimeError("Command cannot be empty".into()));
                 }
 
-                let mut command = tokio::process::Command::new(&cmd_parts[0]);
+                let program = sandbox::command(&cmd_parts[0])?;
+                let mut command = tokio::process::Command::new(program);
                 command
                     .args(&cmd_parts[1..])
                     .stdout(std::process::Stdio::piped())
                     .stderr(std::process::Stdio::piped());
+                if sandbox::enabled() {
+                    command.env_clear();
+                    command.envs(env::vars().filter(|(k, _)| sandbox::env_allowed(k)));
+                    command.envs(
+                        secret_vars()
+                            .into_iter()
+                            .filter(|(k, _)| sandbox::env_allowed(k)),
+                    );
+                } else {
+                    command.envs(secret_vars());
+                }
 
                 if let Some(opts) = options {
                     if let Some(cwd) = opts.get::<Option<String>>("cwd")? {
+                        sandbox::check_path(&cwd, false)?;
                         command.current_dir(cwd);
                     }
                     if let Some(env) = opts.get::<Option<LuaTable>>("env")? {
                         for pair in env.pairs::<String, String>() {
                             let (k, v) = pair?;
+                            if !sandbox::env_allowed(&k) {
+                                return Err(LuaError::RuntimeError(format!(
+                                    "sandbox: environment variable {} is not allowed",
+                                    k
+                                )));
+                            }
                             command.env(k, v);
                         }
                     }
@@ -218,7 +292,14 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
                 for f in tasks {
                     futures.push(f.call_async::<()>(()));
                 }
-                futures::future::join_all(futures).await;
+                // Errors of a task are its own, except for exit()
+                for res in futures::future::join_all(futures).await {
+                    if let Err(e) = res
+                        && crate::exit::exit_code(&e).is_some()
+                    {
+                        return Err(e);
+                    }
+                }
                 Ok(())
             }
         })?,
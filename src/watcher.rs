@@ -0,0 +1,133 @@
+use mlua::prelude::*;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Saves usually arrive as a burst of events (truncate, write, rename), so a
+/// reload is only triggered once the files have been quiet for this long.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watches the entry script, the modules it requires and `.secrets` files,
+/// and sends on the reload channel when one of them changes.
+///
+/// Directories are watched instead of the files themselves so that editors
+/// which save by writing a new file and renaming it over the old one are
+/// still noticed.
+pub struct FileWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl FileWatcher {
+    pub fn new(reload_tx: mpsc::Sender<()>) -> notify::Result<Arc<Self>> {
+        let files: Arc<Mutex<HashSet<PathBuf>>> = Arc::new(Mutex::new(HashSet::new()));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel::<PathBuf>();
+
+        let files_cb = files.clone();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let event = match res {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("File watcher error: {}", e);
+                    return;
+                }
+            };
+            if !matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) {
+                return;
+            }
+            let files = files_cb.lock().unwrap();
+            for path in event.paths {
+                if files.contains(&path) {
+                    let _ = event_tx.send(path);
+                }
+            }
+        })?;
+
+        tokio::spawn(async move {
+            while let Some(path) = event_rx.recv().await {
+                // Swallow the rest of the burst
+                while let Ok(Some(_)) = tokio::time::timeout(DEBOUNCE, event_rx.recv()).await {}
+                println!("Change detected in {:?}, reloading...", path);
+                let _ = reload_tx.try_send(());
+            }
+        });
+
+        Ok(Arc::new(FileWatcher {
+            watcher: Mutex::new(watcher),
+            files,
+            dirs: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// Adds a file to the watch list. The file does not need to exist yet.
+    pub fn watch(&self, path: &Path) {
+        let path = match std::path::absolute(path) {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        let Some(dir) = path.parent().map(fs_canonical) else {
+            return;
+        };
+        let Some(name) = path.file_name() else {
+            return;
+        };
+        let path = dir.join(name);
+
+        if !self.files.lock().unwrap().insert(path.clone()) {
+            return;
+        }
+        let mut dirs = self.dirs.lock().unwrap();
+        if dirs.contains(&dir) || !dir.is_dir() {
+            return;
+        }
+        match self
+            .watcher
+            .lock()
+            .unwrap()
+            .watch(&dir, RecursiveMode::NonRecursive)
+        {
+            Ok(()) => {
+                dirs.insert(dir);
+            }
+            Err(e) => eprintln!("Failed to watch {:?}: {}", dir, e),
+        }
+    }
+
+    /// Wraps `require` so that every module file resolved through
+    /// `package.path` is added to the watch list, including modules that
+    /// fail to load and so never reach `package.loaded`.
+    pub fn track_requires(self: &Arc<Self>, lua: &Lua) -> LuaResult<()> {
+        let watcher = self.clone();
+        let record = lua.create_function(move |lua, name: String| {
+            let package: LuaTable = lua.globals().get("package")?;
+            let searchpath: LuaFunction = package.get("searchpath")?;
+            let path: String = package.get("path")?;
+            if let Some(file) = searchpath.call::<Option<String>>((name, path))? {
+                watcher.watch(Path::new(&file));
+            }
+            Ok(())
+        })?;
+        lua.load(
+            r#"
+            local record, require = ...
+            _G.require = function(name)
+                record(name)
+                return require(name)
+            end
+            "#,
+        )
+        .set_name("=require")
+        .call::<()>((record, lua.globals().get::<LuaFunction>("require")?))
+    }
+}
+
+fn fs_canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -22,6 +22,7 @@ impl LuaUserData for HttpClient {
                 let retry_delays = client.retry_delays.clone();
                 let lua_ref = lua.clone();
                 async move {
+                    crate::sandbox::check_url(&url)?;
                     let mut method = "GET".to_string();
                     let mut body = None;
                     let mut headers = HashMap::new();
@@ -194,6 +195,10 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
             if let Some(ua) = user_agent {
                 agent_builder = agent_builder.user_agent(&ua);
             }
+            // A redirect could lead to a host the sandbox doesn't allow
+            if crate::sandbox::enabled() {
+                agent_builder = agent_builder.redirects(0);
+            }
 
             Ok(HttpClient {
                 insecure,
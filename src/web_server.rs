@@ -1,50 +1,213 @@
+use crate::debugger::DebuggerConfig;
+use crate::oauth::{self, PendingKind};
+use crate::session::{SESSION_COOKIE, Session, SessionConfig};
 use crate::types::{
-    AppState, EngineRequest, ProxyAuthRequest, RestRequest, RestRouteInfo, ReverseProxyInfo,
-    ServerConfig,
+    AppState, BodyStream, EngineRequest, HttpRequest, HttpResponse, MiddlewareInfo,
+    ProxyAuthRequest, RestRequest, RestRouteInfo, ReverseProxyInfo, ServerConfig, WebSocketRequest,
+    WebSocketRouteInfo,
 };
+use crate::upload::{UploadConfig, read_multipart};
 use axum::{
-    Json, Router,
+    Router,
     body::Body,
-    extract::{Query as AxQuery, Request, State},
-    http::StatusCode,
+    extract::{
+        ConnectInfo, FromRequestParts, Path as AxPath, Query as AxQuery, RawPathParams, Request,
+        State, ws::WebSocketUpgrade,
+    },
+    http::header::SET_COOKIE,
+    http::request::Parts,
+    http::{HeaderValue, StatusCode},
     response::{IntoResponse, Redirect, Response},
-    routing::{delete, get, post, put},
+    routing::{MethodFilter, any, get, on},
 };
 use axum_extra::extract::Host;
-use axum_extra::extract::cookie::{Cookie, CookieJar};
-use axum_server::tls_openssl::OpenSSLConfig as OpenSslConfig;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use axum_server::tls_openssl::{
+    OpenSSLAcceptor as OpenSslAcceptor, OpenSSLConfig as OpenSslConfig,
+};
 use mlua::prelude::*;
 use rusqlite::params;
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::sync::{mpsc::Sender, oneshot};
+use tower::ServiceExt;
 use tower_http::services::ServeDir;
 
+/// Maximum size of a request body handed to a Lua handler.
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
 pub struct RestServer {
     state: Arc<Mutex<AppState>>,
+    /// Path prefix of a route group, empty for the server itself.
+    prefix: String,
+}
+
+impl RestServer {
+    fn add_middleware(&self, lua: &Lua, func: LuaFunction) -> LuaResult<()> {
+        let callback_key = lua.create_registry_value(func)?;
+        let mut state = self.state.lock().unwrap();
+        state.middlewares.push(MiddlewareInfo {
+            prefix: self.prefix.clone(),
+            callback_key,
+        });
+        Ok(())
+    }
+}
+
+fn join_path(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_string()
+    } else if path == "/" || path.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{}{}", prefix.trim_end_matches('/'), path)
+    }
+}
+
+/// Whether a route path falls under a middleware prefix ("/api" matches
+/// "/api" and "/api/users" but not "/apix").
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
 }
 
 impl LuaUserData for RestServer {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method(
             "register",
-            |lua, server, (path, method, func): (String, String, LuaFunction)| {
+            |lua, server, (path, method, func, opts): (String, String, LuaFunction, Option<LuaTable>)| {
+                let path = join_path(&server.prefix, &path);
+                let require_login = match opts {
+                    Some(o) => o.get::<Option<bool>>("require_login")?.unwrap_or(false),
+                    None => false,
+                };
+                let method = method.to_uppercase();
+                if method != "ANY" && method_filter(&method).is_none() {
+                    return Err(LuaError::RuntimeError(format!(
+                        "Unsupported HTTP method: {}",
+                        method
+                    )));
+                }
+                validate_path(&path)?;
+
                 let mut state = server.state.lock().unwrap();
+                check_route_conflict(&state, &path, &method)?;
                 let callback_id = state.routes.len();
                 let callback_key = lua.create_registry_value(func)?;
                 state.routes.push(RestRouteInfo {
                     path,
-                    method: method.to_uppercase(),
+                    method,
+                    callback_id,
+                    callback_key,
+                    require_login,
+                });
+                Ok(())
+            },
+        );
+
+        methods.add_method(
+            "websocket",
+            |lua, server, (path, func, opts): (String, LuaFunction, Option<LuaTable>)| {
+                let path = join_path(&server.prefix, &path);
+                let require_login = match opts {
+                    Some(o) => o.get::<Option<bool>>("require_login")?.unwrap_or(false),
+                    None => false,
+                };
+                validate_path(&path)?;
+                let mut state = server.state.lock().unwrap();
+                check_route_conflict(&state, &path, "GET")?;
+                let callback_id = state.websocket_routes.len();
+                let callback_key = lua.create_registry_value(func)?;
+                state.websocket_routes.push(WebSocketRouteInfo {
+                    path,
+                    callback_id,
+                    callback_key,
+                    require_login,
+                });
+                Ok(())
+            },
+        );
+
+        // The handler itself is the event generator: it runs as a coroutine
+        // and every yielded value is sent to the client as an event.
+        methods.add_method(
+            "sse",
+            |lua, server, (path, func, opts): (String, LuaFunction, Option<LuaTable>)| {
+                let path = join_path(&server.prefix, &path);
+                let require_login = match opts {
+                    Some(o) => o.get::<Option<bool>>("require_login")?.unwrap_or(false),
+                    None => false,
+                };
+                validate_path(&path)?;
+                let wrapper = lua.create_function(move |_, args: LuaMultiValue| {
+                    crate::response::stream_response(func.clone(), args, true, None)
+                })?;
+                let mut state = server.state.lock().unwrap();
+                check_route_conflict(&state, &path, "GET")?;
+                let callback_id = state.routes.len();
+                let callback_key = lua.create_registry_value(wrapper)?;
+                state.routes.push(RestRouteInfo {
+                    path,
+                    method: "GET".to_string(),
                     callback_id,
                     callback_key,
+                    require_login,
                 });
                 Ok(())
             },
         );
 
+        methods.add_method(
+            "broadcast",
+            |lua, server, (path, data): (String, LuaValue)| {
+                let path = join_path(&server.prefix, &path);
+                crate::websocket::broadcast(lua, &server.state, &path, data)
+            },
+        );
+
+        methods.add_method("use", |lua, server, func: LuaFunction| {
+            server.add_middleware(lua, func)
+        });
+
+        methods.add_method(
+            "group",
+            |lua, server, (prefix, middlewares): (String, LuaMultiValue)| {
+                validate_path(&prefix)?;
+                let group = RestServer {
+                    state: server.state.clone(),
+                    prefix: join_path(&server.prefix, &prefix),
+                };
+                for mw in middlewares {
+                    match mw {
+                        LuaValue::Function(f) => group.add_middleware(lua, f)?,
+                        LuaValue::Nil => {}
+                        _ => {
+                            return Err(LuaError::RuntimeError(
+                                "group middleware must be a function".into(),
+                            ));
+                        }
+                    }
+                }
+                Ok(group)
+            },
+        );
+
+        methods.add_method("debugger", |_, server, (path, opts): (String, LuaTable)| {
+            validate_path(&path)?;
+            let config = DebuggerConfig::from_lua(join_path(&server.prefix, &path), &opts)?;
+            let mut state = server.state.lock().unwrap();
+            state.debugger = Some(config);
+            Ok(())
+        });
+
         methods.add_method("listen", |_, server, addr: String| {
             let mut state = server.state.lock().unwrap();
             state.config = Some(ServerConfig::Http(addr));
@@ -60,6 +223,18 @@ impl LuaUserData for RestServer {
             },
         );
 
+        methods.add_method("sessions", |_, server, opts: LuaTable| {
+            let mut state = server.state.lock().unwrap();
+            state.session_config = SessionConfig::from_lua(&opts)?;
+            Ok(())
+        });
+
+        methods.add_method("uploads", |_, server, opts: LuaTable| {
+            let mut state = server.state.lock().unwrap();
+            state.upload_config = UploadConfig::from_lua(&opts)?;
+            Ok(())
+        });
+
         methods.add_method(
             "serve_static",
             |_, server, (url_path, fs_path): (String, String)| {
@@ -71,14 +246,176 @@ impl LuaUserData for RestServer {
     }
 }
 
-pub struct ServerGuard(tokio::task::JoinHandle<()>);
+/// The running listener. It survives reloads: a new version of the script
+/// only replaces `router`, unless it listens somewhere else.
+pub struct ServerGuard {
+    handle: tokio::task::JoinHandle<()>,
+    /// Starts a graceful shutdown when fired.
+    graceful: Option<oneshot::Sender<()>>,
+    config: ServerConfig,
+    router: Arc<RwLock<Router>>,
+}
+
+impl ServerGuard {
+    /// Stops accepting connections and waits until the port is released.
+    async fn shutdown(mut self) {
+        self.handle.abort();
+        let _ = (&mut self.handle).await;
+    }
+
+    /// Stops accepting connections and gives the open ones `timeout` to
+    /// finish before they are dropped.
+    pub async fn close(mut self, timeout: Duration) {
+        if let Some(graceful) = self.graceful.take() {
+            let _ = graceful.send(());
+        }
+        let _ = tokio::time::timeout(timeout, &mut self.handle).await;
+    }
+}
 
 impl Drop for ServerGuard {
     fn drop(&mut self) {
-        self.0.abort();
+        self.handle.abort();
     }
 }
 
+/// Serves every request with whichever router is installed at the time, so
+/// requests already in progress finish on the router they started with.
+fn swappable(router: Arc<RwLock<Router>>) -> Router {
+    Router::new().fallback(move |req: Request| {
+        let current = router.read().unwrap().clone();
+        async move { current.oneshot(req).await }
+    })
+}
+
+fn method_filter(method: &str) -> Option<MethodFilter> {
+    match method {
+        "GET" => Some(MethodFilter::GET),
+        "POST" => Some(MethodFilter::POST),
+        "PUT" => Some(MethodFilter::PUT),
+        "DELETE" => Some(MethodFilter::DELETE),
+        "PATCH" => Some(MethodFilter::PATCH),
+        "HEAD" => Some(MethodFilter::HEAD),
+        "OPTIONS" => Some(MethodFilter::OPTIONS),
+        _ => None,
+    }
+}
+
+/// axum panics on overlapping method routes, so reject them at registration.
+fn check_route_conflict(state: &AppState, path: &str, method: &str) -> LuaResult<()> {
+    let overlaps = |other: &str| other == method || other == "ANY" || method == "ANY";
+    let existing = state
+        .routes
+        .iter()
+        .find(|r| r.path == path && overlaps(&r.method))
+        .map(|r| r.method.clone())
+        .or_else(|| {
+            state
+                .websocket_routes
+                .iter()
+                .find(|r| r.path == path && overlaps("GET"))
+                .map(|_| "WEBSOCKET".to_string())
+        });
+    match existing {
+        Some(existing) => Err(LuaError::RuntimeError(format!(
+            "Route {} {} conflicts with {} {}",
+            method, path, existing, path
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Rejects paths axum would panic on when the router is built.
+fn validate_path(path: &str) -> LuaResult<()> {
+    if !path.starts_with('/') {
+        return Err(LuaError::RuntimeError(format!(
+            "Route path must start with '/': {}",
+            path
+        )));
+    }
+    for segment in path.split('/') {
+        if segment.starts_with(':') || segment.starts_with('*') {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid route segment '{}' in {}: use {{name}} or {{*name}} for captures",
+                segment, path
+            )));
+        }
+        if segment.contains("{*") && !path.ends_with(segment) {
+            return Err(LuaError::RuntimeError(format!(
+                "Wildcard capture must be the last segment: {}",
+                path
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn debugger_conflicts(state: &AppState, config: &DebuggerConfig) -> bool {
+    state.routes.iter().any(|r| config.owns(&r.path))
+        || state.websocket_routes.iter().any(|r| config.owns(&r.path))
+}
+
+/// Where the server listens if the script doesn't say.
+fn default_config() -> ServerConfig {
+    ServerConfig::Https(
+        "0.0.0.0:3443".to_string(),
+        "cert.pem".to_string(),
+        "key.pem".to_string(),
+    )
+}
+
+/// Whether `state` has anything for the web server to serve.
+fn serves(state: &AppState) -> bool {
+    !state.routes.is_empty()
+        || !state.websocket_routes.is_empty()
+        || !state.static_routes.is_empty()
+        || !state.reverse_proxies.is_empty()
+        || state.gmail_state.is_some()
+}
+
+/// Problems that `start` would run into with the configuration in `state`.
+pub fn check(state: &AppState, script_dir: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    let default = default_config();
+    let config = match &state.config {
+        Some(config) => Some(config),
+        None if serves(state) => Some(&default),
+        None => None,
+    };
+    match config {
+        Some(ServerConfig::Http(addr)) => {
+            if let Err(e) = addr.to_socket_addrs() {
+                problems.push(format!("Invalid address {}: {}", addr, e));
+            }
+        }
+        Some(ServerConfig::Https(addr, cert, key)) => {
+            if let Err(e) = addr.parse::<SocketAddr>() {
+                problems.push(format!("Invalid address {}: {}", addr, e));
+            }
+            for file in [cert, key] {
+                if !Path::new(file).is_file() {
+                    problems.push(format!("TLS file not found: {}", file));
+                }
+            }
+        }
+        None => {}
+    }
+    if let Some(config) = &state.debugger
+        && debugger_conflicts(state, config)
+    {
+        problems.push(format!("Debugger path {} is taken by a route", config.path));
+    }
+    for (url_path, fs_path) in &state.static_routes {
+        if !url_path.starts_with('/') {
+            problems.push(format!("Static path must start with '/': {}", url_path));
+        }
+        if !script_dir.join(fs_path).is_dir() {
+            problems.push(format!("Static directory not found: {}", fs_path));
+        }
+    }
+    problems
+}
+
 pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
     // Register rest module
     let rest = lua.create_table()?;
@@ -88,287 +425,763 @@ pub fn register(lua: &Lua, app_state: Arc<Mutex<AppState>>) -> LuaResult<()> {
         lua.create_function(move |_, ()| {
             Ok(RestServer {
                 state: state_clone.clone(),
+                prefix: String::new(),
             })
         })?,
     )?;
+    crate::response::register(lua, &rest)?;
     lua.globals().set("rest", rest)?;
 
     Ok(())
 }
 
+/// Builds the router for the routes in `app_state`, which hands requests to
+/// the engine behind `tx`.
+pub fn build_router(
+    app_state: &Arc<Mutex<AppState>>,
+    tx: &Sender<EngineRequest>,
+    script_dir: &Path,
+) -> Router {
+    let mut router = Router::new()
+        .route("/auth/{provider}/callback", get(handle_oauth_callback))
+        .route("/auth/{provider}/login", get(handle_oauth_login))
+        .route("/auth/{provider}/authorize", get(handle_mock_authorize))
+        .route("/auth/logout", get(handle_logout));
+
+    // Setup routes
+    {
+        let state = app_state.lock().unwrap();
+        // Setup static routes
+        for (url_path, fs_path_str) in &state.static_routes {
+            let full_fs_path = script_dir.join(fs_path_str);
+            match std::fs::canonicalize(&full_fs_path) {
+                Ok(real_path) => {
+                    if real_path.is_symlink() {
+                        eprintln!(
+                            "Warning: Skipping static path {} -> {} (Symlink detected)",
+                            url_path, fs_path_str
+                        );
+                        continue;
+                    }
+                    if let Err(e) = crate::sandbox::check_path(&real_path, false) {
+                        eprintln!("Warning: Skipping static path {}: {}", url_path, e);
+                        continue;
+                    }
+                    println!("Serving static: {} -> {:?}", url_path, real_path);
+
+                    let service = ServeDir::new(real_path);
+                    if url_path == "/" {
+                        router = router.fallback_service(service);
+                    } else {
+                        router = router.nest_service(url_path, service);
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Static path {} not found: {} ({})",
+                        fs_path_str,
+                        full_fs_path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        for route_info in &state.routes {
+            let path = route_info.path.clone();
+            let method = route_info.method.clone();
+            let callback_id = route_info.callback_id;
+            let tx_clone = tx.clone();
+            let upload_config = state.upload_config.clone();
+            let session_config = state.session_config.clone();
+            let require_login = route_info.require_login;
+            let handler_state = app_state.clone();
+
+            let handler = move |req: Request| async move {
+                let mut request = match read_request(req, &upload_config).await {
+                    Ok(r) => r,
+                    Err(resp) => return resp,
+                };
+
+                let session = Session::load(
+                    &session_config,
+                    request.cookies.get(SESSION_COOKIE).map(String::as_str),
+                )
+                .await;
+                if require_login && login_user(&handler_state, &session).is_none() {
+                    if request.method != "GET" {
+                        return (StatusCode::UNAUTHORIZED, "Login required").into_response();
+                    }
+                    let return_to = match request.query.is_empty() {
+                        true => request.path.clone(),
+                        false => format!(
+                            "{}?{}",
+                            request.path,
+                            url::form_urlencoded::Serializer::new(String::new())
+                                .extend_pairs(&request.query)
+                                .finish()
+                        ),
+                    };
+                    return login_redirect(&handler_state, &return_to).await;
+                }
+                request.session = Some(session.clone());
+
+                let (res_tx, res_rx) = oneshot::channel();
+                let req = RestRequest {
+                    callback_id,
+                    request,
+                    response_tx: res_tx,
+                };
+
+                if tx_clone
+                    .send(EngineRequest::Rest(Box::new(req)))
+                    .await
+                    .is_err()
+                {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server shutting down")
+                        .into_response();
+                }
+
+                let mut response =
+                    match res_rx.await {
+                        Ok(Ok((res, stream))) => into_axum_response(res, stream),
+                        Ok(Err(_)) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                            .into_response(),
+                        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "No response from Lua")
+                            .into_response(),
+                    };
+
+                if let Some(cookie) = session.commit(&session_config).await
+                    && let Ok(value) = HeaderValue::from_str(&cookie.to_string())
+                {
+                    response.headers_mut().append(SET_COOKIE, value);
+                }
+                response
+            };
+
+            let method_router = match method_filter(&method) {
+                Some(filter) => on(filter, handler),
+                None => any(handler),
+            };
+            router = router.route(&path, method_router);
+        }
+
+        if let Some(config) = &state.debugger {
+            if debugger_conflicts(&state, config) {
+                eprintln!(
+                    "Debugger path {} is taken by a route, not serving it",
+                    config.path
+                );
+            } else {
+                println!("Debugger at {}", config.path);
+                router = router.merge(crate::debugger::router(config));
+            }
+        }
+
+        for ws_route in &state.websocket_routes {
+            let path = ws_route.path.clone();
+            let callback_id = ws_route.callback_id;
+            let require_login = ws_route.require_login;
+            let session_config = state.session_config.clone();
+            let tx_clone = tx.clone();
+            let app_state_clone = app_state.clone();
+
+            let handler = move |req: Request| async move {
+                let (mut parts, _body) = req.into_parts();
+                let upgrade = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+                    Ok(u) => u,
+                    Err(rejection) => return rejection.into_response(),
+                };
+                let mut request = request_from_parts(&mut parts, Vec::new()).await;
+                let session = Session::load(
+                    &session_config,
+                    request.cookies.get(SESSION_COOKIE).map(String::as_str),
+                )
+                .await;
+                if require_login && login_user(&app_state_clone, &session).is_none() {
+                    return (StatusCode::UNAUTHORIZED, "Login required").into_response();
+                }
+                request.session = Some(session);
+                let path = request.path.clone();
+
+                // The middleware decides before the upgrade whether it happens
+                let (accept_tx, accept_rx) = oneshot::channel();
+                let (connection_tx, connection_rx) = oneshot::channel();
+                let req = WebSocketRequest {
+                    callback_id,
+                    request,
+                    accept_tx,
+                    connection_rx,
+                };
+                if tx_clone
+                    .send(EngineRequest::WebSocket(Box::new(req)))
+                    .await
+                    .is_err()
+                {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server shutting down")
+                        .into_response();
+                }
+                match accept_rx.await {
+                    Ok(Ok(None)) => upgrade.on_upgrade(move |socket| {
+                        crate::websocket::serve_connection(
+                            socket,
+                            app_state_clone,
+                            tx_clone,
+                            path,
+                            connection_tx,
+                        )
+                    }),
+                    Ok(Ok(Some(res))) => into_axum_response(res, None),
+                    _ => {
+                        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+                    }
+                }
+            };
+            router = router.route(&path, get(handler));
+        }
+    }
+
+    router.fallback(proxy_handler).with_state(app_state.clone())
+}
+
+/// Builds the router for the routes in `app_state`. If `running` already
+/// listens on the configured address its router is replaced in place,
+/// otherwise a new listener is bound.
 pub async fn start(
     app_state: Arc<Mutex<AppState>>,
     tx: Sender<EngineRequest>,
     abs_path: PathBuf,
+    running: Option<ServerGuard>,
 ) -> Option<ServerGuard> {
     let script_dir = abs_path.parent().unwrap_or(Path::new("."));
 
     // Check if we should start server
-    let should_run = {
-        let state = app_state.lock().unwrap();
-        !state.routes.is_empty()
-            || !state.static_routes.is_empty()
-            || !state.reverse_proxies.is_empty()
-            || state.gmail_state.is_some()
-    };
+    let should_run = serves(&app_state.lock().unwrap());
 
     if should_run {
         println!("Starting server...");
         let config = {
             let mut state = app_state.lock().unwrap();
-            state.engine_tx = Some(tx.clone());
+            state.engine_tx = Some(tx.downgrade());
             if state.config.is_none() {
                 println!("Using default configuration: HTTPS 0.0.0.0:3443");
-                state.config = Some(ServerConfig::Https(
-                    "0.0.0.0:3443".to_string(),
-                    "cert.pem".to_string(),
-                    "key.pem".to_string(),
-                ));
+                state.config = Some(default_config());
             }
+            state.session_config.secure = matches!(state.config, Some(ServerConfig::Https(..)));
             state.config.clone()
         };
 
-        let mut router = Router::new().route("/auth/google/callback", get(handle_google_callback));
+        let router = build_router(&app_state, &tx, script_dir);
 
-        // Setup routes
-        {
-            let state = app_state.lock().unwrap();
-            // Setup static routes
-            for (url_path, fs_path_str) in &state.static_routes {
-                let full_fs_path = script_dir.join(fs_path_str);
-                match std::fs::canonicalize(&full_fs_path) {
-                    Ok(real_path) => {
-                        if real_path.is_symlink() {
-                            eprintln!(
-                                "Warning: Skipping static path {} -> {} (Symlink detected)",
-                                url_path, fs_path_str
-                            );
-                            continue;
-                        }
-                        println!("Serving static: {} -> {:?}", url_path, real_path);
+        // This shouldn't be None because we set default config above if none
+        let config = config?;
+        if let Some(running) = running {
+            if running.config == config {
+                *running.router.write().unwrap() = router;
+                println!("REST server reloaded");
+                return Some(running);
+            }
+            running.shutdown().await;
+        }
 
-                        let service = ServeDir::new(real_path);
-                        if url_path == "/" {
-                            router = router.fallback_service(service);
-                        } else {
-                            router = router.nest_service(url_path, service);
+        let current = Arc::new(RwLock::new(router));
+        let app = swappable(current.clone());
+        let (graceful, graceful_rx) = oneshot::channel::<()>();
+        let handle = match &config {
+            ServerConfig::Http(addr) => {
+                println!("REST server listening on http://{}", addr);
+                let listener_res = tokio::net::TcpListener::bind(addr).await;
+                match listener_res {
+                    Ok(listener) => tokio::spawn(async move {
+                        if let Err(e) = axum::serve(
+                            listener,
+                            app.into_make_service_with_connect_info::<SocketAddr>(),
+                        )
+                        .with_graceful_shutdown(async {
+                            let _ = graceful_rx.await;
+                        })
+                        .await
+                        {
+                            eprintln!("REST server error: {}", e);
                         }
-                    }
+                    }),
                     Err(e) => {
-                        eprintln!(
-                            "Warning: Static path {} not found: {} ({})",
-                            fs_path_str,
-                            full_fs_path.display(),
-                            e
-                        );
+                        eprintln!("Failed to bind to {}: {}", addr, e);
+                        return None;
                     }
                 }
             }
-
-            for route_info in &state.routes {
-                let path = route_info.path.clone();
-                let method = route_info.method.clone();
-                let callback_id = route_info.callback_id;
-                let tx_clone = tx.clone();
-
-                let handler = move |AxQuery(params): AxQuery<HashMap<String, String>>| async move {
-                    let (res_tx, res_rx) = oneshot::channel();
-                    let req = RestRequest {
-                        callback_id,
-                        params,
-                        response_tx: res_tx,
+            ServerConfig::Https(addr, cert, key) => {
+                println!("REST server listening (TLS) on https://{}", addr);
+                let tls_config =
+                    match OpenSslConfig::from_pem_file(PathBuf::from(cert), PathBuf::from(key)) {
+                        Ok(tls_config) => tls_config,
+                        Err(e) => {
+                            eprintln!("TLS config error: {}", e);
+                            return None;
+                        }
                     };
-
-                    if tx_clone.send(EngineRequest::Rest(req)).await.is_err() {
-                        return (StatusCode::INTERNAL_SERVER_ERROR, "Server shutting down")
-                            .into_response();
-                    }
-
-                    match res_rx.await {
-                        Ok(Ok(val)) => Json::<JsonValue>(val).into_response(),
-                        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-                        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "No response from Lua")
-                            .into_response(),
+                let socket_addr: SocketAddr = match addr.parse() {
+                    Ok(socket_addr) => socket_addr,
+                    Err(e) => {
+                        eprintln!("Invalid address {}: {}", addr, e);
+                        return None;
                     }
                 };
-
-                router = match method.as_str() {
-                    "GET" => router.route(&path, get(handler)),
-                    "POST" => router.route(&path, post(handler)),
-                    "PUT" => router.route(&path, put(handler)),
-                    "DELETE" => router.route(&path, delete(handler)),
-                    _ => router.route(&path, get(handler)),
+                // Bind here so a port that is in use is reported now
+                let listener = match std::net::TcpListener::bind(socket_addr) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Failed to bind to {}: {}", addr, e);
+                        return None;
+                    }
                 };
+                let _ = listener.set_nonblocking(true);
+                let server_handle = axum_server::Handle::new();
+                let shutdown_handle = server_handle.clone();
+                tokio::spawn(async move {
+                    if graceful_rx.await.is_ok() {
+                        shutdown_handle.graceful_shutdown(None);
+                    }
+                });
+                tokio::spawn(async move {
+                    if let Err(e) = axum_server::from_tcp(listener)
+                        .acceptor(OpenSslAcceptor::new(tls_config))
+                        .handle(server_handle)
+                        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                    {
+                        eprintln!("REST server error: {}", e);
+                    }
+                })
             }
+        };
+        Some(ServerGuard {
+            handle,
+            graceful: Some(graceful),
+            config,
+            router: current,
+        })
+    } else {
+        None
+    }
+}
+
+/// Collects everything a Lua handler may need to know about an incoming request.
+async fn read_request(req: Request, upload_config: &UploadConfig) -> Result<HttpRequest, Response> {
+    let (mut parts, body) = req.into_parts();
+    let boundary = parts
+        .headers
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok());
+    if let Some(boundary) = boundary {
+        let uploads = read_multipart(body, boundary, upload_config)
+            .await
+            .map_err(|e| e.into_response())?;
+        let mut request = request_from_parts(&mut parts, Vec::new()).await;
+        request.uploads = Some(uploads);
+        return Ok(request);
+    }
+
+    let body = match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(b) => b.to_vec(),
+        Err(_) => {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response());
         }
+    };
+    Ok(request_from_parts(&mut parts, body).await)
+}
 
-        let router = router.fallback(proxy_handler).with_state(app_state.clone());
+async fn request_from_parts(parts: &mut Parts, body: Vec<u8>) -> HttpRequest {
+    let params: HashMap<String, String> = RawPathParams::from_request_parts(parts, &())
+        .await
+        .map(|raw| {
+            raw.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
 
-        match config {
-            Some(ServerConfig::Http(addr)) => {
-                println!("REST server listening on http://{}", addr);
-                let listener_res = tokio::net::TcpListener::bind(&addr).await;
-                match listener_res {
-                    Ok(listener) => {
-                        let server_handle = tokio::spawn(async move {
-                            if let Err(e) = axum::serve(listener, router.into_make_service()).await
-                            {
-                                eprintln!("REST server error: {}", e);
-                            }
-                        });
-                        Some(ServerGuard(server_handle))
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to bind to {}: {}", addr, e);
-                        None
-                    }
-                }
+    let remote_addr = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+
+    let query: HashMap<String, String> = parts
+        .uri
+        .query()
+        .map(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        headers
+            .entry(name.as_str().to_string())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    let cookies = CookieJar::from_headers(&parts.headers)
+        .iter()
+        .map(|c| (c.name().to_string(), c.value().to_string()))
+        .collect();
+
+    HttpRequest {
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
+        query,
+        params,
+        headers,
+        cookies,
+        body,
+        remote_addr,
+        uploads: None,
+        session: None,
+    }
+}
+
+fn into_axum_response(res: HttpResponse, stream: Option<BodyStream>) -> Response {
+    let mut builder = Response::builder().status(res.status);
+    for (name, value) in &res.headers {
+        builder = builder.header(name, value);
+    }
+    if res.header("content-type").is_none() && !res.body.is_empty() {
+        builder = builder.header("Content-Type", "application/octet-stream");
+    }
+    let body = match stream {
+        Some(rx) => Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv()
+                .await
+                .map(|chunk| (Ok::<_, std::convert::Infallible>(chunk), rx))
+        })),
+        None => Body::from(res.body),
+    };
+    builder.body(body).unwrap_or_else(|e| {
+        eprintln!("Invalid response from Lua handler: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to build response",
+        )
+            .into_response()
+    })
+}
+
+/// Builds the request table passed to Lua route handlers.
+///
+/// Besides the raw `body`, JSON bodies are decoded into `json` and
+/// urlencoded forms into `form`, based on the `Content-Type` header.
+pub fn request_to_lua(lua: &Lua, request: &HttpRequest) -> LuaResult<LuaTable> {
+    let req = lua.create_table()?;
+    req.set("method", request.method.as_str())?;
+    req.set("path", request.path.as_str())?;
+    req.set("query", lua.create_table_from(request.query.clone())?)?;
+    req.set("params", lua.create_table_from(request.params.clone())?)?;
+    req.set("headers", lua.create_table_from(request.headers.clone())?)?;
+    req.set("cookies", lua.create_table_from(request.cookies.clone())?)?;
+    req.set("body", lua.create_string(&request.body)?)?;
+    req.set("remote_addr", request.remote_addr.clone())?;
+    if let Some(session) = &request.session {
+        req.set("user", session.email())?;
+        req.set("session", session.clone())?;
+    }
+
+    let content_type = request
+        .headers
+        .get("content-type")
+        .map(|ct| ct.to_ascii_lowercase())
+        .unwrap_or_default();
+    if content_type.starts_with("application/json") {
+        if let Ok(json_val) = serde_json::from_slice::<JsonValue>(&request.body) {
+            req.set("json", lua.to_value(&json_val)?)?;
+        }
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        let form: HashMap<String, String> = url::form_urlencoded::parse(&request.body)
+            .into_owned()
+            .collect();
+        req.set("form", lua.create_table_from(form)?)?;
+    }
+
+    // Files are available both by field name and as a list in upload order
+    if let Some(uploads) = &request.uploads {
+        let form = lua.create_table()?;
+        for (name, value) in &uploads.fields {
+            form.set(name.as_str(), value.as_str())?;
+        }
+        req.set("form", form)?;
+        let files = lua.create_table()?;
+        for (name, file) in &uploads.files {
+            files.push(file.clone())?;
+            if !files.contains_key(name.as_str())? {
+                files.set(name.as_str(), file.clone())?;
             }
-            Some(ServerConfig::Https(addr, cert, key)) => {
-                println!("REST server listening (TLS) on https://{}", addr);
-                let config_res =
-                    OpenSslConfig::from_pem_file(PathBuf::from(cert), PathBuf::from(key));
-                match config_res {
-                    Ok(tls_config) => {
-                        let addr_parsed: Result<std::net::SocketAddr, _> = addr.parse();
-                        match addr_parsed {
-                            Ok(socket_addr) => {
-                                let server_handle = tokio::spawn(async move {
-                                    if let Err(e) =
-                                        axum_server::bind_openssl(socket_addr, tls_config)
-                                            .serve(router.into_make_service())
-                                            .await
-                                    {
-                                        eprintln!("REST server error: {}", e);
-                                    }
-                                });
-                                Some(ServerGuard(server_handle))
-                            }
-                            Err(e) => {
-                                eprintln!("Invalid address {}: {}", addr, e);
-                                None
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("TLS config error: {}", e);
-                        None
+        }
+        req.set("files", files)?;
+    }
+
+    Ok(req)
+}
+
+/// Runs a route handler behind the middlewares that apply to it.
+///
+/// Each middleware is called as `mw(req, next)`; calling `next(req)` runs the
+/// rest of the chain and returns its result, while returning without calling
+/// `next` short-circuits the request. The handler itself receives the merged
+/// query and path parameters followed by the request table.
+pub async fn call_handler(
+    lua: &Lua,
+    handler: LuaFunction,
+    middlewares: Vec<LuaFunction>,
+    req: LuaTable,
+) -> LuaResult<LuaValue> {
+    let mut next = lua.create_async_function(move |lua, req: LuaTable| {
+        let handler = handler.clone();
+        async move {
+            let params = lua.create_table()?;
+            for key in ["query", "params"] {
+                if let Some(t) = req.get::<Option<LuaTable>>(key)? {
+                    for pair in t.pairs::<LuaValue, LuaValue>() {
+                        let (k, v) = pair?;
+                        params.set(k, v)?;
                     }
                 }
             }
-            None => {
-                // This shouldn't happen because we set default config above if none
-                None
-            }
+            handler.call_async::<LuaValue>((params, req)).await
         }
-    } else {
-        None
+    })?;
+
+    for mw in middlewares.into_iter().rev() {
+        let inner = next.clone();
+        next = lua.create_async_function(move |lua, req: LuaTable| {
+            let mw = mw.clone();
+            let inner = inner.clone();
+            async move {
+                // `next()` without arguments passes the current request along
+                let current_req = req.clone();
+                let next_fn = lua.create_async_function(move |_, req: Option<LuaTable>| {
+                    let inner = inner.clone();
+                    let req = req.unwrap_or_else(|| current_req.clone());
+                    async move { inner.call_async::<LuaValue>(req).await }
+                })?;
+                mw.call_async::<LuaValue>((req, next_fn)).await
+            }
+        })?;
     }
+
+    next.call_async(req).await
 }
 
-async fn handle_google_callback(
+async fn handle_oauth_callback(
     State(app_state): State<Arc<Mutex<AppState>>>,
+    AxPath(provider_name): AxPath<String>,
     jar: CookieJar,
     AxQuery(params): AxQuery<HashMap<String, String>>,
-) -> impl IntoResponse {
+) -> Response {
+    let Some(state) = params.get("state") else {
+        return (StatusCode::BAD_REQUEST, "Missing state").into_response();
+    };
+    let browser_state = jar.get(STATE_COOKIE).map(|c| c.value());
+    let Some(pending) = oauth::take_pending(state, &provider_name, browser_state) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Unknown or expired login, please try again",
+        )
+            .into_response();
+    };
+    if let Some(error) = params.get("error") {
+        return (
+            StatusCode::FORBIDDEN,
+            format!("Authentication failed: {}", error),
+        )
+            .into_response();
+    }
     let code = match params.get("code") {
         Some(c) => c.clone(),
-        None => return "Missing code".into_response(),
-    };
-    let state_param = match params.get("state") {
-        Some(s) => s.clone(),
-        None => return "Missing state".into_response(),
+        None => return (StatusCode::BAD_REQUEST, "Missing code").into_response(),
     };
 
-    let gmail_state = {
-        let state = app_state.lock().unwrap();
-        state.gmail_state.clone()
+    let return_to = match &pending.kind {
+        PendingKind::Login { return_to } => return_to.clone(),
+        PendingKind::GoogleLink => {
+            let gmail_state = {
+                let state = app_state.lock().unwrap();
+                state.gmail_state.clone()
+            };
+            let Some(gs) = gmail_state else {
+                return "Gmail not initialized".into_response();
+            };
+            return match crate::gmail::handle_callback(gs, code).await {
+                Ok(email) => format!(
+                    "Authentication successful as {}! You can close this window.",
+                    email
+                )
+                .into_response(),
+                Err(e) => format!("Authentication failed: {}", e).into_response(),
+            };
+        }
     };
 
-    let gs = match gmail_state {
-        Some(s) => s,
-        None => return "Gmail not initialized".into_response(),
+    let Some(provider) = oauth::find_provider(&app_state, &provider_name) else {
+        return (StatusCode::NOT_FOUND, "Unknown login provider").into_response();
     };
-
-    // If state_param looks like an email, it's the old GMail linking flow
-    if state_param.contains('@') && !state_param.contains('/') {
-        match crate::gmail::handle_callback(gs, code).await {
-            Ok(email) => format!(
-                "Authentication successful as {}! You can close this window.",
-                email
+    let identity = match oauth::complete(&provider, &code, &pending).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Login with {} failed: {}", provider_name, e);
+            return (
+                StatusCode::FORBIDDEN,
+                format!("Authentication failed: {}", e),
             )
-            .into_response(),
-            Err(e) => format!("Authentication failed: {}", e).into_response(),
+                .into_response();
         }
-    } else {
-        // General login flow for proxy
-        let config = &gs.config;
-        let res = match tokio::task::spawn_blocking({
-            let client_id = config.client_id.clone();
-            let client_secret = config.client_secret.clone();
-            let redirect_uri = config.redirect_uri.clone();
-            move || {
-                ureq::post("https://oauth2.googleapis.com/token")
-                    .set("Content-Type", "application/x-www-form-urlencoded")
-                    .send_string(&format!(
-                        "client_id={}&client_secret={}&code={}&grant_type=authorization_code&redirect_uri={}",
-                        client_id,
-                        client_secret,
-                        code,
-                        urlencoding::encode(&redirect_uri)
-                    ))
-            }
-        }).await {
-            Ok(Ok(r)) => r,
-            _ => return "Failed to exchange token".into_response(),
-        };
+    };
+    // Users without a verified email are identified by provider and subject
+    let user = match oauth::verified_email(&identity) {
+        Some(email) => email.to_string(),
+        None => format!(
+            "{}:{}",
+            provider_name,
+            identity["sub"].as_str().unwrap_or_default()
+        ),
+    };
 
-        #[derive(serde::Deserialize)]
-        struct TokenResponse {
-            access_token: String,
-            _scope: Option<String>,
-        }
+    // Data stored before the login is kept
+    let config = app_state.lock().unwrap().session_config.clone();
+    let cookie = jar.get(SESSION_COOKIE).map(|c| c.value().to_string());
+    let session = Session::load(&config, cookie.as_deref()).await;
+    session.login(user, identity);
 
-        let token_res: TokenResponse = match res.into_json() {
-            Ok(t) => t,
-            Err(e) => return format!("Failed to parse token response: {}", e).into_response(),
-        };
+    // Drop the unsigned cookies older versions relied on
+    let mut jar = jar
+        .remove(Cookie::build(STATE_COOKIE).path("/auth"))
+        .remove(Cookie::build("lumen_email").path("/"))
+        .remove(Cookie::build("lumen_access_token").path("/"));
+    if let Some(cookie) = session.commit(&config).await {
+        jar = jar.add(cookie);
+    }
 
-        // Get email
-        let access_token = token_res.access_token.clone();
-        let email_res = tokio::task::spawn_blocking(move || {
-            ureq::get("https://www.googleapis.com/oauth2/v3/userinfo")
-                .set("Authorization", &format!("Bearer {}", access_token))
-                .call()
-        })
-        .await;
+    (jar, Redirect::to(local_redirect(&return_to))).into_response()
+}
 
-        let email_json: serde_json::Value = match email_res {
-            Ok(Ok(r)) => r.into_json().unwrap_or_default(),
-            _ => return "Failed to get user info".into_response(),
-        };
+async fn handle_oauth_login(
+    State(app_state): State<Arc<Mutex<AppState>>>,
+    AxPath(provider_name): AxPath<String>,
+    AxQuery(params): AxQuery<HashMap<String, String>>,
+) -> Response {
+    let return_to = params.get("return_to").map(String::as_str).unwrap_or("/");
+    let Some(provider) = oauth::find_provider(&app_state, &provider_name) else {
+        return (StatusCode::NOT_FOUND, "Unknown login provider").into_response();
+    };
+    redirect_to_provider(
+        &app_state,
+        &provider,
+        local_redirect(return_to),
+        params.get("login_hint"),
+    )
+    .await
+}
 
-        let email = match email_json.get("email").and_then(|v| v.as_str()) {
-            Some(e) => e.to_string(),
-            None => return "Failed to get email from Google".into_response(),
-        };
+/// Authorize endpoint of mock providers.
+async fn handle_mock_authorize(
+    State(app_state): State<Arc<Mutex<AppState>>>,
+    AxPath(provider_name): AxPath<String>,
+    AxQuery(params): AxQuery<HashMap<String, String>>,
+) -> Response {
+    let provider = oauth::find_provider(&app_state, &provider_name);
+    let Some(mock) = provider.as_deref().and_then(oauth::mock_issuer) else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+    match mock.authorize(&params) {
+        Ok(url) => Redirect::to(&url).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
 
-        // Set cookies
-        let jar = jar
-            .add(Cookie::build(("lumen_email", email)).path("/").build())
-            .add(
-                Cookie::build(("lumen_access_token", token_res.access_token))
-                    .path("/")
-                    .build(),
-            );
+/// Holds the login state so the callback can check it reached the same browser.
+const STATE_COOKIE: &str = "lumen_oauth_state";
 
-        let redirect_to = if state_param.starts_with('/') {
-            state_param
-        } else {
-            "/".to_string()
-        };
+async fn redirect_to_provider(
+    app_state: &Arc<Mutex<AppState>>,
+    provider: &oauth::Provider,
+    return_to: &str,
+    login_hint: Option<&String>,
+) -> Response {
+    match oauth::authorization_url(provider, return_to, login_hint.map(String::as_str)).await {
+        Ok((url, state)) => {
+            let secure = app_state.lock().unwrap().session_config.secure;
+            let cookie = Cookie::build((STATE_COOKIE, state))
+                .path("/auth")
+                .http_only(true)
+                .secure(secure)
+                .same_site(SameSite::Lax)
+                .max_age(cookie::time::Duration::seconds(
+                    oauth::PENDING_TTL.as_secs() as i64,
+                ))
+                .build();
+            (CookieJar::new().add(cookie), Redirect::to(&url)).into_response()
+        }
+        Err(e) => {
+            eprintln!("Failed to start login with {}: {}", provider.name, e);
+            (StatusCode::BAD_GATEWAY, "Login provider unavailable").into_response()
+        }
+    }
+}
+
+/// Only same-site paths are accepted as redirect targets.
+fn local_redirect(target: &str) -> &str {
+    if target.starts_with('/') && !target.starts_with("//") && !target.starts_with("/\\") {
+        target
+    } else {
+        "/"
+    }
+}
+
+async fn handle_logout(
+    State(app_state): State<Arc<Mutex<AppState>>>,
+    jar: CookieJar,
+    AxQuery(params): AxQuery<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let config = app_state.lock().unwrap().session_config.clone();
+    let cookie = jar.get(SESSION_COOKIE).map(|c| c.value().to_string());
+    let session = Session::load(&config, cookie.as_deref()).await;
+    session.destroy();
+    let mut jar = jar;
+    if let Some(cookie) = session.commit(&config).await {
+        jar = jar.add(cookie);
+    }
+    let redirect_to = params.get("redirect").map(String::as_str).unwrap_or("/");
+    (jar, Redirect::to(local_redirect(redirect_to))).into_response()
+}
 
-        (jar, Redirect::to(&redirect_to)).into_response()
+/// The user of a session, if it was logged in by the login provider. Other
+/// providers, which a script may register for its own use, are not trusted
+/// by `require_login`, the reverse proxy or the debugger.
+pub fn login_user(app_state: &Arc<Mutex<AppState>>, session: &Session) -> Option<String> {
+    let provider = oauth::login_provider(app_state);
+    if session.provider().as_deref() != Some(provider.as_str()) {
+        return None;
+    }
+    session.email()
+}
+
+/// Sends the browser through the login provider, returning to `return_to`.
+pub async fn login_redirect(app_state: &Arc<Mutex<AppState>>, return_to: &str) -> Response {
+    let name = oauth::login_provider(app_state);
+    match oauth::find_provider(app_state, &name) {
+        Some(provider) => redirect_to_provider(app_state, &provider, return_to, None).await,
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No login provider configured",
+        )
+            .into_response(),
     }
 }
 
@@ -389,38 +1202,13 @@ async fn proxy_handler(
 
     if let Some(proxy) = matched_proxy {
         if let Some(domain) = &proxy.domain {
-            let email = match jar.get("lumen_email") {
-                Some(c) => c.value().to_string(),
-                None => {
-                    // Redirect to login
-                    let gmail_state = {
-                        let state = app_state.lock().unwrap();
-                        state.gmail_state.clone()
-                    };
-                    let gs = match gmail_state {
-                        Some(s) => s,
-                        None => {
-                            return (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                "Gmail not initialized for auth",
-                            )
-                                .into_response();
-                        }
-                    };
-
-                    let mut auth_url =
-                        url::Url::parse("https://accounts.google.com/o/oauth2/v2/auth").unwrap();
-                    {
-                        let mut query = auth_url.query_pairs_mut();
-                        query.append_pair("client_id", &gs.config.client_id);
-                        query.append_pair("redirect_uri", &gs.config.redirect_uri);
-                        query.append_pair("response_type", "code");
-                        query.append_pair("scope", "openid email");
-                        query.append_pair("access_type", "online");
-                        query.append_pair("state", req.uri().path()); // Store current path to redirect back
-                    }
-                    return Redirect::to(auth_url.as_str()).into_response();
-                }
+            let config = app_state.lock().unwrap().session_config.clone();
+            let cookie = jar.get(SESSION_COOKIE).map(|c| c.value().to_string());
+            let session = Session::load(&config, cookie.as_deref()).await;
+            let email = match login_user(&app_state, &session) {
+                Some(email) => email,
+                // Store current path to redirect back
+                None => return login_redirect(&app_state, req.uri().path()).await,
             };
 
             // Check authorization
@@ -448,7 +1236,7 @@ async fn check_authorization(
         let (tx, rx) = tokio::sync::oneshot::channel();
         let engine_tx = {
             let state = app_state.lock().unwrap();
-            state.engine_tx.clone()
+            state.engine_tx.as_ref().and_then(|tx| tx.upgrade())
         };
 
         if let Some(engine_tx) = engine_tx {
@@ -471,21 +1259,26 @@ async fn check_authorization(
     // 2. Try SQLite
     let domain = domain.to_string();
     let email = email.to_string();
-    tokio::task::spawn_blocking(move || match rusqlite::Connection::open("server.db") {
-        Ok(conn) => {
-            let res: Result<i32, _> = conn.query_row(
-                "SELECT 1 FROM authorized_users WHERE domain = ? AND email = ?",
-                params![domain, email],
-                |_| Ok(1),
-            );
-            res.is_ok()
+    tokio::task::spawn_blocking(move || {
+        match rusqlite::Connection::open(crate::session::db_path()) {
+            Ok(conn) => {
+                let res: Result<i32, _> = conn.query_row(
+                    "SELECT 1 FROM authorized_users WHERE domain = ? AND email = ?",
+                    params![domain, email],
+                    |_| Ok(1),
+                );
+                res.is_ok()
+            }
+            Err(_) => false,
         }
-        Err(_) => false,
     })
     .await
     .unwrap_or(false)
 }
 
+/// Cookies that carry Lumen credentials, which proxied backends don't get.
+const PRIVATE_COOKIES: &[&str] = &[SESSION_COOKIE, "lumen_email", "lumen_access_token"];
+
 async fn forward_request(proxy: ReverseProxyInfo, req: Request) -> Response {
     let path = req.uri().path().to_string();
     let query = req
@@ -498,13 +1291,32 @@ async fn forward_request(proxy: ReverseProxyInfo, req: Request) -> Response {
     let method = req.method().to_string();
 
     let mut headers = HashMap::new();
+    let mut cookies = Vec::new();
     for (name, value) in req.headers() {
         if name == "host" {
             continue;
         }
-        if let Ok(v) = value.to_str() {
-            headers.insert(name.as_str().to_string(), v.to_string());
+        let Ok(v) = value.to_str() else {
+            continue;
+        };
+        if name == "cookie" {
+            // Lumen's own credentials stay here
+            cookies.extend(
+                v.split(';')
+                    .map(str::trim)
+                    .filter(|pair| !pair.is_empty())
+                    .filter(|pair| {
+                        let name = pair.split('=').next().unwrap_or_default().trim();
+                        !PRIVATE_COOKIES.contains(&name)
+                    })
+                    .map(str::to_string),
+            );
+            continue;
         }
+        headers.insert(name.as_str().to_string(), v.to_string());
+    }
+    if !cookies.is_empty() {
+        headers.insert("cookie".to_string(), cookies.join("; "));
     }
     if let Some(h) = req.headers().get("host").and_then(|v| v.to_str().ok()) {
         headers.insert("X-Forwarded-Host".to_string(), h.to_string());
@@ -0,0 +1,266 @@
+use crate::types::{AppState, EngineRequest};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use mlua::prelude::*;
+use serde_json::Value as JsonValue;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+
+/// Interval of keepalive pings sent to every client.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct WsClient {
+    pub id: u64,
+    pub sender: UnboundedSender<Message>,
+}
+
+#[derive(Clone)]
+pub struct WsConnection {
+    pub id: u64,
+    pub path: String,
+    sender: UnboundedSender<Message>,
+    receiver: Arc<tokio::sync::Mutex<Receiver<Message>>>,
+}
+
+/// Closes its connection when dropped, so that the connection ends with its
+/// handler, even one that is cancelled.
+pub struct HandlerGuard(UnboundedSender<Message>);
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(Message::Close(Some(CloseFrame {
+            code: 1000,
+            reason: "".into(),
+        })));
+    }
+}
+
+impl WsConnection {
+    pub fn guard(&self) -> HandlerGuard {
+        HandlerGuard(self.sender.clone())
+    }
+}
+
+/// Converts a Lua value into a WebSocket message. Tables are sent as JSON
+/// text, strings as text unless they are not valid UTF-8 or `binary` is set.
+fn to_message(lua: &Lua, data: LuaValue, binary: bool) -> LuaResult<Message> {
+    match data {
+        LuaValue::String(s) => {
+            let bytes = s.as_bytes().to_vec();
+            if binary {
+                return Ok(Message::Binary(bytes.into()));
+            }
+            match String::from_utf8(bytes) {
+                Ok(text) => Ok(Message::Text(text.into())),
+                Err(e) => Ok(Message::Binary(e.into_bytes().into())),
+            }
+        }
+        LuaValue::Table(_) => {
+            let json_val: JsonValue = lua.from_value(data)?;
+            let text = serde_json::to_string(&json_val)
+                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            Ok(Message::Text(text.into()))
+        }
+        other => {
+            let s = lua
+                .coerce_string(other)?
+                .ok_or_else(|| LuaError::RuntimeError("cannot send this value".into()))?;
+            Ok(Message::Text(s.to_str()?.to_string().into()))
+        }
+    }
+}
+
+impl LuaUserData for WsConnection {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_, this| Ok(this.id));
+        fields.add_field_method_get("path", |_, this| Ok(this.path.clone()));
+        fields.add_field_method_get("closed", |_, this| Ok(this.sender.is_closed()));
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // Returns the next message and its type ("text" or "binary"), or nil
+        // once the connection is closed. With a timeout, returns nil, "timeout".
+        methods.add_async_method("recv", |lua, this, timeout: Option<f64>| async move {
+            let mut receiver = this.receiver.lock().await;
+            let msg = match timeout {
+                Some(secs) => {
+                    match tokio::time::timeout(Duration::from_secs_f64(secs), receiver.recv()).await
+                    {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            return Ok((
+                                LuaValue::Nil,
+                                LuaValue::String(lua.create_string("timeout")?),
+                            ));
+                        }
+                    }
+                }
+                None => receiver.recv().await,
+            };
+            match msg {
+                Some(Message::Text(text)) => Ok((
+                    LuaValue::String(lua.create_string(text.as_str())?),
+                    LuaValue::String(lua.create_string("text")?),
+                )),
+                Some(Message::Binary(bytes)) => Ok((
+                    LuaValue::String(lua.create_string(&bytes)?),
+                    LuaValue::String(lua.create_string("binary")?),
+                )),
+                _ => Ok((
+                    LuaValue::Nil,
+                    LuaValue::String(lua.create_string("closed")?),
+                )),
+            }
+        });
+
+        methods.add_method(
+            "send",
+            |lua, this, (data, opts): (LuaValue, Option<LuaTable>)| {
+                let binary = match opts {
+                    Some(o) => o.get::<Option<bool>>("binary")?.unwrap_or(false),
+                    None => false,
+                };
+                let msg = to_message(lua, data, binary)?;
+                Ok(this.sender.send(msg).is_ok())
+            },
+        );
+
+        methods.add_method("ping", |_, this, payload: Option<LuaString>| {
+            let payload = payload.map(|p| p.as_bytes().to_vec()).unwrap_or_default();
+            Ok(this.sender.send(Message::Ping(payload.into())).is_ok())
+        });
+
+        methods.add_method(
+            "close",
+            |_, this, (code, reason): (Option<u16>, Option<String>)| {
+                let frame = CloseFrame {
+                    code: code.unwrap_or(1000),
+                    reason: reason.unwrap_or_default().into(),
+                };
+                Ok(this.sender.send(Message::Close(Some(frame))).is_ok())
+            },
+        );
+    }
+}
+
+/// Sends a message to every client connected to a websocket route.
+/// Returns the number of clients the message was queued for.
+pub fn broadcast(
+    lua: &Lua,
+    app_state: &Arc<Mutex<AppState>>,
+    path: &str,
+    data: LuaValue,
+) -> LuaResult<usize> {
+    let msg = to_message(lua, data, false)?;
+    let mut state = app_state.lock().unwrap();
+    let mut sent = 0;
+    if let Some(clients) = state.websocket_clients.get_mut(path) {
+        clients.retain(|client| !client.sender.is_closed());
+        for client in clients.iter() {
+            if client.sender.send(msg.clone()).is_ok() {
+                sent += 1;
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Drives an upgraded socket: hands the connection to the Lua handler waiting
+/// for it, forwards incoming messages, writes queued outgoing messages and
+/// keeps the connection alive with pings.
+pub async fn serve_connection(
+    socket: WebSocket,
+    app_state: Arc<Mutex<AppState>>,
+    tx: Sender<EngineRequest>,
+    path: String,
+    connection_tx: oneshot::Sender<WsConnection>,
+) {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+    let (in_tx, in_rx) = mpsc::channel::<Message>(256);
+
+    {
+        let mut state = app_state.lock().unwrap();
+        state
+            .websocket_clients
+            .entry(path.clone())
+            .or_default()
+            .push(WsClient {
+                id,
+                sender: out_tx.clone(),
+            });
+    }
+
+    let close_tx = out_tx.clone();
+    let connection = WsConnection {
+        id,
+        path: path.clone(),
+        sender: out_tx,
+        receiver: Arc::new(tokio::sync::Mutex::new(in_rx)),
+    };
+    if connection_tx.send(connection).is_ok() {
+        let (mut sink, mut stream) = socket.split();
+
+        let mut writer = tokio::spawn(async move {
+            let mut ping = tokio::time::interval(PING_INTERVAL);
+            ping.tick().await;
+            loop {
+                tokio::select! {
+                    msg = out_rx.recv() => {
+                        let Some(msg) = msg else { break };
+                        let is_close = matches!(msg, Message::Close(_));
+                        if sink.send(msg).await.is_err() || is_close {
+                            break;
+                        }
+                    }
+                    _ = ping.tick() => {
+                        if sink.send(Message::Ping(Vec::new().into())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = sink.close().await;
+        });
+
+        let mut overflowed = false;
+        loop {
+            tokio::select! {
+                msg = stream.next() => {
+                    match msg {
+                        Some(Ok(_)) if overflowed => {}
+                        Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                            // A client far ahead of the handler is closed
+                            // rather than losing messages
+                            if in_tx.try_send(msg).is_err() {
+                                overflowed = true;
+                                eprintln!("Websocket client {} on {} sent too many unread messages, closing", id, path);
+                                let _ = close_tx.send(Message::Close(Some(CloseFrame {
+                                    code: 1008,
+                                    reason: "Too many unread messages".into(),
+                                })));
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    }
+                }
+                // The engine loop ended (reload or exit)
+                _ = tx.closed() => break,
+                // The connection was closed on this side
+                _ = &mut writer => break,
+            }
+        }
+        writer.abort();
+    }
+
+    let mut state = app_state.lock().unwrap();
+    if let Some(clients) = state.websocket_clients.get_mut(&path) {
+        clients.retain(|client| client.id != id);
+    }
+}
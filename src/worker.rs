@@ -0,0 +1,152 @@
+use crate::gmail::GmailState;
+use crate::types::{AppState, EngineRequest};
+use crate::{cron, engine};
+use mlua::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of worker threads requested with `LUMEN_WORKERS`, 0 if unset.
+pub fn worker_count() -> usize {
+    crate::util::env_var("LUMEN_WORKERS")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+struct Worker {
+    tx: mpsc::Sender<EngineRequest>,
+    /// Requests the worker is running, maintained by its engine.
+    load: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    fn busy(&self) -> usize {
+        let queued = self.tx.max_capacity() - self.tx.capacity();
+        self.load.load(Ordering::Relaxed) + queued
+    }
+}
+
+/// Worker threads that share the rest, cron and telegram requests of one
+/// version of the script.
+pub struct Pool {
+    workers: Vec<Worker>,
+}
+
+impl Pool {
+    /// Hands the request to the least busy worker. The request is given back
+    /// if every worker is gone or its queue is full.
+    pub fn dispatch(&self, mut req: EngineRequest) -> Option<EngineRequest> {
+        let mut order: Vec<&Worker> = self.workers.iter().collect();
+        order.sort_by_key(|w| w.busy());
+        for worker in order {
+            match worker.tx.try_send(req) {
+                Ok(()) => return None,
+                Err(mpsc::error::TrySendError::Full(r) | mpsc::error::TrySendError::Closed(r)) => {
+                    req = r
+                }
+            }
+        }
+        Some(req)
+    }
+}
+
+/// Handle of a started worker, kept by the main loop.
+pub struct WorkerHandle {
+    /// Result of loading the script.
+    pub ready: oneshot::Receiver<Result<(), String>>,
+    /// Fires once the worker has drained and stopped.
+    pub done: oneshot::Receiver<()>,
+    /// Stops the worker's engine, which is retired if this is dropped.
+    pub stop: oneshot::Sender<engine::Stop>,
+}
+
+/// Starts `count` threads that each load `content` into their own Lua state.
+/// `LUMEN_WORKER` is set to the worker number (1 to `count`) in every state
+/// so scripts can skip one-off work, such as migrations, on the workers.
+pub fn spawn(
+    count: usize,
+    name: &str,
+    content: &str,
+    gmail_state: Option<Arc<GmailState>>,
+    exit_tx: mpsc::UnboundedSender<i32>,
+    cron_tx: mpsc::UnboundedSender<cron::Command>,
+) -> (Pool, Vec<WorkerHandle>) {
+    let mut workers = Vec::with_capacity(count);
+    let mut handles = Vec::with_capacity(count);
+
+    for id in 1..=count {
+        let (tx, req_rx) = mpsc::channel::<EngineRequest>(100);
+        let (ready_tx, ready) = oneshot::channel();
+        let (done_tx, done) = oneshot::channel();
+        let (stop, stop_rx) = oneshot::channel();
+        let load = Arc::new(AtomicUsize::new(0));
+
+        let name = name.to_string();
+        let content = content.to_string();
+        let gmail_state = gmail_state.clone();
+        let exit_tx = exit_tx.clone();
+        let cron_tx = cron_tx.clone();
+        let engine_tx = tx.downgrade();
+        let engine_load = load.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("lumen-worker-{}", id))
+            .spawn(move || {
+                let rt = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(rt) => rt,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e.to_string()));
+                        return;
+                    }
+                };
+                rt.block_on(async move {
+                    let lua = Lua::new();
+                    let app_state = Arc::new(Mutex::new(AppState::new(gmail_state, exit_tx)));
+                    let loaded = load_script(&lua, &app_state, id, &name, &content).await;
+                    let handlers = match loaded {
+                        Ok(handlers) => handlers,
+                        Err(e) => {
+                            let _ = ready_tx.send(Err(e.to_string()));
+                            return;
+                        }
+                    };
+                    {
+                        // Jobs added from now on run on this worker
+                        let mut state = app_state.lock().unwrap();
+                        state.engine_tx = Some(engine_tx);
+                        state.cron_tx = Some(cron_tx);
+                    }
+                    let _ = ready_tx.send(Ok(()));
+                    engine::run(lua, handlers, req_rx, stop_rx, None, engine_load).await;
+                });
+                // Don't wait for blocking tasks of handlers that were cancelled
+                rt.shutdown_background();
+                let _ = done_tx.send(());
+            });
+        if let Err(e) = spawned {
+            eprintln!("Failed to start worker {}: {}", id, e);
+            continue;
+        }
+
+        workers.push(Worker { tx, load });
+        handles.push(WorkerHandle { ready, done, stop });
+    }
+
+    (Pool { workers }, handles)
+}
+
+async fn load_script(
+    lua: &Lua,
+    app_state: &Arc<Mutex<AppState>>,
+    id: usize,
+    name: &str,
+    content: &str,
+) -> LuaResult<engine::Handlers> {
+    crate::register_modules(lua, app_state.clone())?;
+    lua.globals().set("LUMEN_WORKER", id)?;
+    crate::sandbox::limited(lua.load(content).set_name(name).call_async::<()>(())).await?;
+    let state = app_state.lock().unwrap();
+    engine::Handlers::load(lua, &state)
+}